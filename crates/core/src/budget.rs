@@ -0,0 +1,56 @@
+//! Global cap on connection attempts across all hosts
+//! (`max_total_connections`).
+//!
+//! Every connection opened through `run_probe` takes one unit; once none are
+//! left, further attempts fail and the runner stops starting hosts, counting
+//! each one it skips.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+static REMAINING: OnceLock<AtomicU64> = OnceLock::new();
+static SKIPPED: AtomicU64 = AtomicU64::new(0);
+static RETRY_SKIPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Set the budget; `0` means unlimited (no-op).
+pub fn init(max_total_connections: u64) {
+    if max_total_connections > 0 {
+        let _ = REMAINING.set(AtomicU64::new(max_total_connections));
+    }
+}
+
+/// Take one connection attempt from the budget; `false` once it is used up.
+pub fn take() -> bool {
+    REMAINING.get().is_none_or(|r| {
+        r.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok()
+    })
+}
+
+/// Whether the budget is set and used up.
+pub fn exhausted() -> bool {
+    REMAINING
+        .get()
+        .is_some_and(|r| r.load(Ordering::Relaxed) == 0)
+}
+
+/// Count a host not probed because the budget was used up.
+pub fn record_skipped() {
+    SKIPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Hosts skipped so far because the budget was used up.
+pub fn skipped() -> u64 {
+    SKIPPED.load(Ordering::Relaxed)
+}
+
+/// Count a retry-round re-run not started because the budget was used up
+/// (the host was probed before and keeps that outcome).
+pub fn record_retry_skipped() {
+    RETRY_SKIPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Retry-round re-runs skipped so far because the budget was used up.
+pub fn retry_skipped() -> u64 {
+    RETRY_SKIPPED.load(Ordering::Relaxed)
+}
@@ -0,0 +1,139 @@
+//! Infrastructure-centric view of a run: per-host outcomes grouped by the
+//! resolved peer IP and its subnet, written once at exit to `<out_dir>/by_ip.json`.
+//!
+//! Shows how many hostnames share a server and whether they behave the same
+//! (handshake, ALPN, HTTP status). ASN enrichment is left to post-processing
+//! on the `ip`/`subnet` keys; no ASN database ships with the tool.
+
+use std::collections::BTreeMap;
+use std::io::{BufWriter, Write};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+use anyhow::Result;
+use serde::Serialize;
+
+const FILE_NAME: &str = "by_ip.json";
+
+/// What one host attempt looked like on a given IP.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HostOutcome {
+    pub host: String,
+    pub handshake_ok: bool,
+    pub alpn: Option<String>,
+    pub status: Option<u16>,
+}
+
+impl HostOutcome {
+    /// The behaviour compared across hosts sharing an IP.
+    fn signature(&self) -> (bool, Option<&str>, Option<u16>) {
+        (self.handshake_ok, self.alpn.as_deref(), self.status)
+    }
+}
+
+#[derive(Serialize)]
+struct IpGroup<'a> {
+    ip: IpAddr,
+    subnet: String,
+    host_count: usize,
+    /// All hosts on this IP had the same handshake/ALPN/status outcome
+    identical: bool,
+    hosts: &'a [HostOutcome],
+}
+
+#[derive(Serialize)]
+struct SubnetGroup {
+    ips: usize,
+    hosts: usize,
+}
+
+#[derive(Serialize)]
+struct ByIpFile<'a> {
+    ips: Vec<IpGroup<'a>>,
+    subnets: BTreeMap<String, SubnetGroup>,
+}
+
+struct Aggregator {
+    path: PathBuf,
+    groups: Mutex<BTreeMap<IpAddr, Vec<HostOutcome>>>,
+}
+
+static GLOBAL: OnceLock<Aggregator> = OnceLock::new();
+
+/// Subnet key: /24 for IPv4, /48 for IPv6 (typical announcement granularity).
+fn subnet(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => {
+            let o = v4.octets();
+            format!("{}.{}.{}.0/24", o[0], o[1], o[2])
+        }
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            format!("{:x}:{:x}:{:x}::/48", s[0], s[1], s[2])
+        }
+    }
+}
+
+/// Enable aggregation; results are kept in memory until [`shutdown`].
+pub fn init(out_dir: &str, enabled: bool) {
+    if !enabled {
+        return;
+    }
+    let _ = GLOBAL.set(Aggregator {
+        path: PathBuf::from(out_dir).join(FILE_NAME),
+        groups: Mutex::new(BTreeMap::new()),
+    });
+}
+
+/// Add one host outcome for `ip` (no-op when disabled).
+pub fn record(ip: IpAddr, outcome: HostOutcome) {
+    if let Some(agg) = GLOBAL.get() {
+        agg.groups
+            .lock()
+            .unwrap()
+            .entry(ip)
+            .or_default()
+            .push(outcome);
+    }
+}
+
+/// Path of `by_ip.json`, if enabled.
+pub fn path() -> Option<PathBuf> {
+    GLOBAL.get().map(|agg| agg.path.clone())
+}
+
+/// Write `by_ip.json`, if enabled.
+pub fn shutdown() -> Result<()> {
+    let Some(agg) = GLOBAL.get() else {
+        return Ok(());
+    };
+    let groups = agg.groups.lock().unwrap();
+
+    let mut subnets: BTreeMap<String, SubnetGroup> = BTreeMap::new();
+    let ips = groups
+        .iter()
+        .map(|(&ip, hosts)| {
+            let subnet = subnet(ip);
+            let sg = subnets
+                .entry(subnet.clone())
+                .or_insert(SubnetGroup { ips: 0, hosts: 0 });
+            sg.ips += 1;
+            sg.hosts += hosts.len();
+            IpGroup {
+                ip,
+                subnet,
+                host_count: hosts.len(),
+                identical: hosts
+                    .windows(2)
+                    .all(|w| w[0].signature() == w[1].signature()),
+                hosts,
+            }
+        })
+        .collect();
+
+    let mut w = BufWriter::new(std::fs::File::create(&agg.path)?);
+    serde_json::to_writer_pretty(&mut w, &ByIpFile { ips, subnets })?;
+    w.flush()?;
+    Ok(())
+}
@@ -1,11 +1,19 @@
-use crate::types::IpVersion;
-use anyhow::{Context, Result};
+use crate::types::{
+    DelayDistribution, IpVersion, PrioritySignal, ProbeKind, QlogFraming, QlogMode, QlogProfile,
+    QlogTimeFormat, ResolverFallback, ResolverMode, TimeRotation, UserAgentSelection,
+};
+use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::BTreeMap,
     fs,
     io::{self, BufRead},
+    net::IpAddr,
     path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
 };
+use tquic::CongestionControlAlgorithm;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct RootConfig {
@@ -24,10 +32,22 @@ pub struct RootConfig {
     /// Probe attempt configurations (tried in order until one succeeds).
     #[serde(default)]
     pub connection_config: Vec<ConnectionConfig>,
+
+    /// The two arms of `probe = "compare"`
+    #[serde(default)]
+    pub compare: CompareConfig,
+
+    /// Exact-duplicate attempts dropped at load time (see [`dedup_attempts`])
+    #[serde(skip)]
+    pub duplicate_attempts: usize,
 }
 
 // ---------------- Scheduler ----------------
-#[derive(Debug, Clone, Deserialize)]
+/// Global pacing. Every probe takes this same struct: `requests_per_second` /
+/// `burst` pace connections across all hosts, while `inter_attempt_delay_ms`
+/// (shaped by `delay_distribution`) is the only per-domain pacing knob, the
+/// pause between one host's `[[connection_config]]` attempts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SchedulerConfig {
     /// Number of worker threads (0 = auto = CPU count)
     #[serde(default = "default_concurrency")]
@@ -44,6 +64,55 @@ pub struct SchedulerConfig {
     /// Delay between attempts to the same domain (milliseconds)
     #[serde(default = "default_inter_attempt_delay_ms")]
     pub inter_attempt_delay_ms: u64,
+
+    /// Distribution of the inter-attempt delay; `inter_attempt_delay_ms` is its mean
+    #[serde(default)]
+    pub delay_distribution: DelayDistribution,
+
+    /// Upper bound on the total time spent on one host (ms, 0 = none),
+    /// checked before each resolution, connection and inter-attempt delay
+    #[serde(default)]
+    pub host_deadline_ms: u64,
+
+    /// After the whole list, re-run hosts whose last failure was transient
+    /// (no response, DNS, local error, host deadline) up to this many rounds,
+    /// waiting `global_retry_backoff_ms` before the first and doubling after
+    #[serde(default)]
+    pub global_retry_rounds: u32,
+    #[serde(default = "default_global_retry_backoff_ms")]
+    pub global_retry_backoff_ms: u64,
+
+    /// Retry a connection config whose addresses all failed transiently (no
+    /// response, local error) up to this many times before moving on,
+    /// waiting `retry_backoff_ms` before the first retry and doubling after
+    #[serde(default)]
+    pub max_retries: u32,
+    #[serde(default = "default_retry_backoff_ms")]
+    pub retry_backoff_ms: u64,
+
+    /// Pause of the worker after each completed host (ms, 0 = none), on top
+    /// of the rate limit and inter-attempt delays
+    #[serde(default)]
+    pub post_host_sleep_ms: u64,
+
+    /// Cap on connection attempts across all hosts and retry rounds (0 =
+    /// unlimited); once reached, remaining hosts are skipped
+    #[serde(default)]
+    pub max_total_connections: u64,
+
+    /// Longest wait for a rate-limit token before an attempt (ms, 0 = no
+    /// limit); the host deadline also bounds it
+    #[serde(default)]
+    pub throttle_max_wait_ms: u64,
+}
+impl SchedulerConfig {
+    /// `throttle_max_wait_ms` as a duration (`Duration::MAX` when unset).
+    pub fn throttle_max_wait(&self) -> Duration {
+        match self.throttle_max_wait_ms {
+            0 => Duration::MAX,
+            ms => Duration::from_millis(ms),
+        }
+    }
 }
 impl Default for SchedulerConfig {
     fn default() -> Self {
@@ -52,6 +121,15 @@ impl Default for SchedulerConfig {
             requests_per_second: default_requests_per_second(),
             burst: default_burst(),
             inter_attempt_delay_ms: default_inter_attempt_delay_ms(),
+            delay_distribution: DelayDistribution::Fixed,
+            host_deadline_ms: 0,
+            global_retry_rounds: 0,
+            global_retry_backoff_ms: default_global_retry_backoff_ms(),
+            max_retries: 0,
+            retry_backoff_ms: default_retry_backoff_ms(),
+            post_host_sleep_ms: 0,
+            max_total_connections: 0,
+            throttle_max_wait_ms: 0,
         }
     }
 }
@@ -84,6 +162,10 @@ impl Default for IOConfig {
 // ---------------- General ----------------
 #[derive(Debug, Clone, Deserialize)]
 pub struct GeneralConfig {
+    /// Probe run for every host
+    #[serde(default)]
+    pub probe: ProbeKind,
+
     /// Log level, support OFF/ERROR/WARN/INFO/DEBUG/TRACE.
     #[serde(default = "default_log_level")]
     pub log_level: log::LevelFilter,
@@ -96,10 +178,41 @@ pub struct GeneralConfig {
     #[serde(default = "default_save_recorder_files")]
     pub save_recorder_files: bool,
 
+    /// Also write failed hosts' records to `recorder_files/errors.jsonl`
+    #[serde(default)]
+    pub save_error_records: bool,
+
     /// Enable and save qlog files
     #[serde(default = "default_save_qlog_files")]
     pub save_qlog_files: bool,
 
+    /// qlog event times: relative to the session start (default) or absolute epoch ms
+    #[serde(default)]
+    pub qlog_time_format: QlogTimeFormat,
+    /// Record framing of the aggregated qlog (default: "json-seq")
+    #[serde(default)]
+    pub qlog_framing: QlogFraming,
+    /// One aggregated qlog, or one file per connection (default: "aggregate")
+    #[serde(default)]
+    pub qlog_mode: QlogMode,
+    /// Events and fields kept in connection traces (default: "qvis")
+    #[serde(default)]
+    pub qlog_profile: QlogProfile,
+
+    /// Also start a new log and qlog file every UTC hour/day (default: size
+    /// only); rotated files then carry the period start in their name
+    #[serde(default)]
+    pub time_rotation: TimeRotation,
+
+    /// Gzip rotated log, qlog and keylog files (`base.N.gz`) in the background
+    #[serde(default)]
+    pub compress_rotated: bool,
+
+    /// Keep at most this many rotated log, qlog and keylog files each,
+    /// deleting the oldest (default: keep all)
+    #[serde(default)]
+    pub max_rotated_files: Option<usize>,
+
     /// Enable and save keylog files
     #[serde(default = "default_save_keylog_files")]
     pub save_keylog_files: bool,
@@ -107,17 +220,136 @@ pub struct GeneralConfig {
     /// Enable and save session files
     #[serde(default = "default_save_session_files")]
     pub save_session_files: bool,
+
+    /// Group host outcomes by resolved IP/subnet into `<out_dir>/by_ip.json`
+    /// (kept in memory until the end of the run)
+    #[serde(default)]
+    pub save_by_ip: bool,
+
+    /// Append `host -> output files` lines to `<out_dir>/index.jsonl` as hosts complete
+    #[serde(default)]
+    pub save_index: bool,
+
+    /// Append `host,status,handshake_ms,error_category` rows to
+    /// `<out_dir>/triage.csv` as hosts complete
+    #[serde(default)]
+    pub save_triage_csv: bool,
+
+    /// Write end-of-run aggregates (e.g. `server` header histogram) to
+    /// `<out_dir>/summary.json`; optionally reduce server values to the product
+    #[serde(default)]
+    pub save_summary: bool,
+    #[serde(default)]
+    pub summary_strip_server_versions: bool,
+
+    /// List every output file (with size and record count) in
+    /// `<out_dir>/manifest.json` at exit
+    #[serde(default)]
+    pub save_manifest: bool,
+
+    /// `--resume` without `progress.done`: find completed hosts by scanning
+    /// the memory-mapped recorder files for keys instead of parsing every line
+    #[serde(default)]
+    pub fast_resume: bool,
+
+    /// Report workers whose host made no progress (no connection opened or
+    /// closed) this long (s, 0 = no watchdog) and
+    /// optionally abort the scan (after flushing all sinks) when one is found
+    #[serde(default)]
+    pub worker_stall_secs: u64,
+    #[serde(default)]
+    pub abort_on_stall: bool,
+
+    /// Count a host as failed unless some attempt got a 2xx/3xx HTTP response
+    /// (default: only transport/handshake errors are failures)
+    #[serde(default)]
+    pub http_status_counts_as_failure: bool,
+
+    /// Census mode: no per-host output at all (recorder, error records, index,
+    /// by-IP, qlog, keylog, sessions), only the host outcome counts in
+    /// `summary.json`, which it turns on
+    #[serde(default)]
+    pub counts_only: bool,
+}
+
+impl GeneralConfig {
+    /// Apply `counts_only`: switch off every per-host sink, keep the summary.
+    fn apply_counts_only(&mut self) {
+        if !self.counts_only {
+            return;
+        }
+        self.save_recorder_files = false;
+        self.save_error_records = false;
+        self.save_index = false;
+        self.save_triage_csv = false;
+        self.save_by_ip = false;
+        self.save_qlog_files = false;
+        self.save_keylog_files = false;
+        self.save_session_files = false;
+        self.save_summary = true;
+    }
 }
 
 impl Default for GeneralConfig {
     fn default() -> Self {
         Self {
+            probe: ProbeKind::H3,
             log_level: default_log_level(),
             save_log_files: default_save_log_files(),
             save_recorder_files: default_save_recorder_files(),
+            save_error_records: false,
             save_qlog_files: default_save_qlog_files(),
+            qlog_time_format: QlogTimeFormat::Relative,
+            qlog_framing: QlogFraming::JsonSeq,
+            qlog_mode: QlogMode::Aggregate,
+            qlog_profile: QlogProfile::Qvis,
+            time_rotation: TimeRotation::None,
+            compress_rotated: false,
+            max_rotated_files: None,
             save_keylog_files: default_save_keylog_files(),
             save_session_files: default_save_session_files(),
+            save_by_ip: false,
+            save_index: false,
+            save_triage_csv: false,
+            save_summary: false,
+            worker_stall_secs: 0,
+            abort_on_stall: false,
+            summary_strip_server_versions: false,
+            save_manifest: false,
+            fast_resume: false,
+            http_status_counts_as_failure: false,
+            counts_only: false,
+        }
+    }
+}
+
+// ---------------- Compare ----------------
+/// `probe = "compare"`: every host is probed with arm `a`, then arm `b`, each
+/// a full `[[connection_config]]` table; hosts whose outcomes differ go to
+/// `<out_dir>/diffs.jsonl`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompareConfig {
+    #[serde(default)]
+    pub a: Option<ConnectionConfig>,
+    #[serde(default)]
+    pub b: Option<ConnectionConfig>,
+
+    /// Handshake times differ only beyond this many ms
+    #[serde(default = "default_compare_handshake_ms_threshold")]
+    pub handshake_ms_threshold: u64,
+}
+impl CompareConfig {
+    /// The configured arms, `a` first.
+    pub fn arms(&self) -> Vec<ConnectionConfig> {
+        self.a.iter().chain(&self.b).cloned().collect()
+    }
+}
+impl Default for CompareConfig {
+    fn default() -> Self {
+        Self {
+            a: None,
+            b: None,
+            handshake_ms_threshold: default_compare_handshake_ms_threshold(),
         }
     }
 }
@@ -132,19 +364,137 @@ pub struct ConnectionConfig {
     pub path: String,
     #[serde(default = "default_user_agent")]
     pub user_agent: String,
+    // Optional User-Agents to use instead of `user_agent`, one picked per
+    // connection (round-robin across hosts, or at random)
+    #[serde(default)]
+    pub user_agent_pool: Vec<String>,
+    #[serde(default)]
+    pub user_agent_selection: UserAgentSelection,
+    // Request method (an HTTP token, e.g. "HEAD") and an optional body sent
+    // with each request (e.g. for "POST"); no body = headers carry FIN
+    #[serde(default = "default_method")]
+    pub method: String,
+    #[serde(default)]
+    pub request_body: Option<String>,
+
+    // Throughput: parallel request streams on one connection (1 = single GET).
+    // Bounded by the peer's stream limit; streams beyond it are not opened.
+    #[serde(default = "default_parallel_streams")]
+    pub parallel_streams: u64,
+    // At most this many of them in flight at once; the rest are opened as
+    // earlier ones finish (0 = all at once)
+    #[serde(default)]
+    pub max_concurrent_streams: u64,
+    // Paths fetched by the parallel streams, assigned round-robin (empty = `path`)
+    #[serde(default)]
+    pub stream_paths: Vec<String>,
+    // Issue one warm-up GET first and measure on the already-warm connection
+    #[serde(default)]
+    pub warmup_request: bool,
+    // Keep up to this many bytes of the first response body, hex-encoded in
+    // the stream's result (0 = discard bodies)
+    #[serde(default)]
+    pub capture_body_bytes: usize,
+    // Extensible Priorities (RFC 9218) for request streams: urgency 0-7 (unset =
+    // no signal), incremental flag, and whether to send a header and/or frame
+    #[serde(default)]
+    pub priority_urgency: Option<u8>,
+    #[serde(default)]
+    pub priority_incremental: bool,
+    #[serde(default)]
+    pub priority_signal: PrioritySignal,
+    // QPACK decoder limits advertised in our SETTINGS (unset = tquic default)
+    #[serde(default)]
+    pub qpack_max_table_capacity: Option<u64>,
+    #[serde(default)]
+    pub qpack_blocked_streams: Option<u64>,
+
+    /// Soak probe: request interval and total duration on one connection (ms)
+    #[serde(default = "default_soak_interval_ms")]
+    pub soak_interval_ms: u64,
+    #[serde(default = "default_soak_duration_ms")]
+    pub soak_duration_ms: u64,
 
     // TLS / verification
     #[serde(default = "default_verify_peer")]
     pub verify_peer: bool,
+    // Offer 0-RTT early data when a stored session ticket is available
+    #[serde(default)]
+    pub enable_early_data: bool,
 
     // ALPN to advertise (e.g., ["h3"])
     #[serde(default = "default_alpn")]
     pub alpn: Vec<String>,
+    // Per-family overrides of `alpn`, picked by the address being probed
+    #[serde(default)]
+    pub alpn_v4: Option<Vec<String>>,
+    #[serde(default)]
+    pub alpn_v6: Option<Vec<String>>,
+    // On an ALPN mismatch, skip this attempt's remaining addresses: the host
+    // answered with QUIC, so another family would only repeat the rejection
+    #[serde(default)]
+    pub alpn_mismatch_is_final: bool,
+    // After a connection got no response at all, try this attempt's next
+    // address (e.g. the other family); a peer that answered but failed the
+    // handshake is never retried on another address
+    #[serde(default)]
+    pub timeout_is_retryable: bool,
+
+    // Happy Eyeballs (RFC 8305) for `ip_version = "auto"`: connect to the
+    // first IPv6 address, and also to the first IPv4 address if no handshake
+    // completed within this many ms; the first handshake wins (0 = one
+    // address after the other)
+    #[serde(default)]
+    pub happy_eyeballs_delay_ms: u64,
+    // Connect race (when Happy Eyeballs does not apply): connect to up to
+    // this many of the host's addresses at once, in order, and keep the
+    // first handshake (0 or 1 = one address after the other)
+    #[serde(default)]
+    pub connect_race_width: usize,
 
     // Preferred IP version for this connection config
     #[serde(default)]
     pub ip_version: IpVersion,
 
+    /// Resolver to use (default: system)
+    #[serde(default)]
+    pub resolver_mode: ResolverMode,
+
+    /// Fixed addresses per host name, used instead of DNS (SNI and
+    /// `:authority` stay the host name)
+    #[serde(default)]
+    pub resolve_override: BTreeMap<String, IpAddr>,
+
+    /// Resolver to fall back to when resolution fails (default: none)
+    #[serde(default)]
+    pub resolver_fallback: ResolverFallback,
+
+    /// Cache DNS answers per host for this long (s, 0 = resolve every attempt),
+    /// then keep serving them for up to `dns_stale_ttl_secs` more while a
+    /// background refresh runs (0 = strict, never serve stale)
+    #[serde(default)]
+    pub dns_cache_ttl_secs: u64,
+    #[serde(default)]
+    pub dns_stale_ttl_secs: u64,
+    /// Remember failed lookups (NXDOMAIN, no addresses; not temporary
+    /// resolver failures) for this long (s, 0 = off)
+    #[serde(default)]
+    pub dns_negative_ttl_secs: u64,
+
+    /// Never probe private, loopback, link-local or reserved addresses (a host
+    /// with nothing else is skipped); `allow_loopback` exempts 127.0.0.0/8 and
+    /// ::1 for self-tests against a local server
+    #[serde(default)]
+    pub skip_private_addresses: bool,
+    #[serde(default)]
+    pub allow_loopback: bool,
+
+    /// Addresses tried per IP family, and overall per attempt (0 = no overall cap)
+    #[serde(default = "default_max_addrs_per_family")]
+    pub max_addrs_per_family: usize,
+    #[serde(default)]
+    pub max_addrs_total: usize,
+
     // Timeouts (ms)
     #[serde(default = "default_max_idle_timeout_ms")]
     pub max_idle_timeout_ms: u64,
@@ -171,22 +521,122 @@ pub struct ConnectionConfig {
     #[serde(default = "default_max_receive_buffer_size")]
     pub max_receive_buffer_size: usize,
 
+    /// Bounds on the event-loop poll timeout (ms, 0 = unbounded). A floor
+    /// saves CPU by coarsening timers; a ceiling forces periodic wake-ups.
+    #[serde(default)]
+    pub min_poll_interval_ms: u64,
+    #[serde(default)]
+    pub max_poll_interval_ms: u64,
+
+    /// Keep the connection open this long after the probe is done before
+    /// closing it, so trailing ACKs/frames are logged (ms, 0 = close at once)
+    #[serde(default)]
+    pub close_linger_ms: u64,
+
+    /// Keep reading this long after the last response finished (ms, before
+    /// `close_linger_ms`), recording late frames such as trailers or GOAWAY
+    #[serde(default)]
+    pub post_finish_ms: u64,
+
+    /// TESTING AID, off by default: delay every outgoing datagram by
+    /// `impair_delay_ms`, and hold every `impair_reorder_every`-th one another
+    /// `impair_reorder_ms` so later datagrams overtake it (0 = off)
+    #[serde(default)]
+    pub impair_delay_ms: u64,
+    #[serde(default)]
+    pub impair_reorder_every: u64,
+    #[serde(default)]
+    pub impair_reorder_ms: u64,
+    /// TESTING AID: drop outgoing datagrams with this probability (0.0-1.0),
+    /// drawn from an RNG seeded per connection with `send_loss_seed`
+    #[serde(default)]
+    pub send_loss_rate: f64,
+    #[serde(default)]
+    pub send_loss_seed: u64,
+
     // tquic multipath flags
     #[serde(default = "default_enable_multipath")]
     pub enable_multipath: bool,
     /// One of: "minrtt", "roundrobin", "redundant". Defaults to "minrtt".
     #[serde(default = "default_multipath_algorithm")]
     pub multipath_algorithm: String,
+
+    /// Congestion control: one of "cubic", "bbr", "bbr3", "copa". Defaults
+    /// to "bbr" (tquic's default).
+    #[serde(default = "default_cc_algorithm")]
+    pub cc_algorithm: String,
+}
+impl ConnectionConfig {
+    /// ALPN list to offer to `peer`: the family override if set, else `alpn`.
+    pub fn alpn_for(&self, peer: &std::net::SocketAddr) -> &[String] {
+        let family = if peer.is_ipv4() {
+            &self.alpn_v4
+        } else {
+            &self.alpn_v6
+        };
+        family.as_deref().unwrap_or(&self.alpn)
+    }
+
+    /// User-Agent for a new connection: `user_agent`, or the next (or a
+    /// random) `user_agent_pool` entry.
+    pub fn pick_user_agent(&self) -> &str {
+        let pool = &self.user_agent_pool;
+        if pool.is_empty() {
+            return &self.user_agent;
+        }
+        let i = match self.user_agent_selection {
+            UserAgentSelection::RoundRobin => UA_NEXT.fetch_add(1, Ordering::Relaxed) % pool.len(),
+            UserAgentSelection::Random => rand::random_range(0..pool.len()),
+        };
+        &pool[i]
+    }
 }
+
+/// Next `user_agent_pool` index for round-robin selection, shared by all hosts.
+static UA_NEXT: AtomicUsize = AtomicUsize::new(0);
+
 impl Default for ConnectionConfig {
     fn default() -> Self {
         Self {
             port: default_port(),
             path: default_path(),
             user_agent: default_user_agent(),
+            user_agent_pool: Vec::new(),
+            user_agent_selection: UserAgentSelection::RoundRobin,
+            method: default_method(),
+            request_body: None,
+            parallel_streams: default_parallel_streams(),
+            max_concurrent_streams: 0,
+            stream_paths: Vec::new(),
+            warmup_request: false,
+            capture_body_bytes: 0,
+            priority_urgency: None,
+            priority_incremental: false,
+            priority_signal: PrioritySignal::Header,
+            qpack_max_table_capacity: None,
+            qpack_blocked_streams: None,
+            soak_interval_ms: default_soak_interval_ms(),
+            soak_duration_ms: default_soak_duration_ms(),
             verify_peer: default_verify_peer(),
+            enable_early_data: false,
             ip_version: IpVersion::Auto,
+            resolver_mode: ResolverMode::System,
+            resolve_override: BTreeMap::new(),
+            resolver_fallback: ResolverFallback::None,
+            dns_cache_ttl_secs: 0,
+            dns_stale_ttl_secs: 0,
+            dns_negative_ttl_secs: 0,
+            skip_private_addresses: false,
+            allow_loopback: false,
+            max_addrs_per_family: default_max_addrs_per_family(),
+            max_addrs_total: 0,
             alpn: default_alpn(),
+            alpn_v4: None,
+            alpn_v6: None,
+            alpn_mismatch_is_final: false,
+            timeout_is_retryable: false,
+            happy_eyeballs_delay_ms: 0,
+            connect_race_width: 0,
             max_idle_timeout_ms: default_max_idle_timeout_ms(),
             initial_max_data: default_initial_max_data(),
             initial_max_stream_data_bidi_local: default_initial_max_stream_data_bidi_local(),
@@ -198,8 +648,18 @@ impl Default for ConnectionConfig {
             active_connection_id_limit: default_active_connection_id_limit(),
             send_udp_payload_size: default_send_udp_payload_size(),
             max_receive_buffer_size: default_max_receive_buffer_size(),
+            min_poll_interval_ms: 0,
+            max_poll_interval_ms: 0,
+            close_linger_ms: 0,
+            post_finish_ms: 0,
+            impair_delay_ms: 0,
+            impair_reorder_every: 0,
+            impair_reorder_ms: 0,
+            send_loss_rate: 0.0,
+            send_loss_seed: 0,
             enable_multipath: default_enable_multipath(),
             multipath_algorithm: default_multipath_algorithm(),
+            cc_algorithm: default_cc_algorithm(),
         }
     }
 }
@@ -217,6 +677,12 @@ fn default_burst() -> u32 {
 fn default_inter_attempt_delay_ms() -> u64 {
     3000
 }
+fn default_global_retry_backoff_ms() -> u64 {
+    30_000
+}
+fn default_retry_backoff_ms() -> u64 {
+    1000
+}
 
 // ---- IO defaults ----
 fn default_in_dir() -> String {
@@ -250,15 +716,30 @@ fn default_save_session_files() -> bool {
 }
 
 // ---- Attempt defaults ----
+fn default_soak_interval_ms() -> u64 {
+    1000
+}
+fn default_soak_duration_ms() -> u64 {
+    60_000
+}
+fn default_max_addrs_per_family() -> usize {
+    1
+}
 fn default_port() -> u16 {
     443
 }
 fn default_path() -> String {
     "/".into()
 }
+fn default_method() -> String {
+    "GET".into()
+}
 fn default_user_agent() -> String {
     "QUIC Lab (research; no-harm-intended; opt-out: [INSERT CONTACT INFO])".into()
 }
+fn default_parallel_streams() -> u64 {
+    1
+}
 fn default_verify_peer() -> bool {
     true
 }
@@ -304,20 +785,122 @@ fn default_enable_multipath() -> bool {
 fn default_multipath_algorithm() -> String {
     "minrtt".into()
 }
+fn default_compare_handshake_ms_threshold() -> u64 {
+    100
+}
+fn default_cc_algorithm() -> String {
+    "bbr".into()
+}
 
 // ---- public API ----
 pub fn read_config<P: AsRef<Path>>(p: P) -> Result<RootConfig> {
-    let s = fs::read_to_string(&p)
-        .with_context(|| format!("reading config file {}", p.as_ref().display()))?;
-    let mut root: RootConfig = toml::from_str(&s)
-        .with_context(|| format!("parsing TOML config {}", p.as_ref().display()))?;
+    read_configs(&[p])
+}
+
+/// Read several config files and deep-merge them in order (base first, then overlays).
+///
+/// Tables are merged key by key, so an overlay only needs the keys it changes.
+/// Every other value, arrays included, is replaced: a `[[connection_config]]`
+/// list in a later file replaces the earlier list as a whole rather than being
+/// appended to it.
+pub fn read_configs<P: AsRef<Path>>(paths: &[P]) -> Result<RootConfig> {
+    let mut merged = toml::Value::Table(toml::Table::new());
+    for p in paths {
+        let s = fs::read_to_string(p)
+            .with_context(|| format!("reading config file {}", p.as_ref().display()))?;
+        let layer: toml::Value = toml::from_str::<toml::Table>(&s)
+            .map(toml::Value::Table)
+            .with_context(|| format!("parsing TOML config {}", p.as_ref().display()))?;
+        merge_toml(&mut merged, layer);
+    }
+    let mut root: RootConfig = merged.try_into().context("parsing merged TOML config")?;
     if root.connection_config.is_empty() {
         // ensure at least one default attempt
         root.connection_config.push(ConnectionConfig::default());
     }
+    for (i, att) in root.connection_config.iter().enumerate() {
+        validate_attempt(&format!("connection_config[{i}]"), att)?;
+    }
+    if matches!(root.general.probe, ProbeKind::Compare) {
+        let (Some(a), Some(b)) = (&root.compare.a, &root.compare.b) else {
+            bail!("probe = \"compare\" needs both [compare.a] and [compare.b]");
+        };
+        validate_attempt("compare.a", a)?;
+        validate_attempt("compare.b", b)?;
+    }
+    root.duplicate_attempts = dedup_attempts(&mut root.connection_config)?;
+    root.general.apply_counts_only();
     Ok(root)
 }
 
+/// Reject attempt settings that would only fail once probing started.
+fn validate_attempt(what: &str, att: &ConnectionConfig) -> Result<()> {
+    if !is_http_token(&att.method) {
+        bail!("{what}: method {:?} is not an HTTP token", att.method);
+    }
+    if let Some(ua) = att
+        .user_agent_pool
+        .iter()
+        .find(|ua| ua.trim().is_empty() || ua.contains(['\r', '\n', '\0']))
+    {
+        bail!("{what}: user_agent_pool entry {ua:?} is not a usable header value");
+    }
+    if att
+        .cc_algorithm
+        .parse::<CongestionControlAlgorithm>()
+        .is_err()
+    {
+        bail!(
+            "{what}: unknown cc_algorithm {:?} (expected cubic, bbr, bbr3 or copa)",
+            att.cc_algorithm
+        );
+    }
+    if let ResolverMode::DoH { url } = &att.resolver_mode {
+        crate::doh::DohUrl::parse(url).with_context(|| what.to_string())?;
+    }
+    Ok(())
+}
+
+/// Whether `s` is a non-empty HTTP token (RFC 9110, Section 5.6.2), as a
+/// request method must be.
+fn is_http_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Remove attempts identical to an earlier one (compared in serialized form),
+/// keeping the first occurrence; returns how many were removed. A copy-pasted
+/// `[[connection_config]]` would otherwise repeat the same probe per host.
+pub fn dedup_attempts(attempts: &mut Vec<ConnectionConfig>) -> Result<usize> {
+    let before = attempts.len();
+    let mut seen = Vec::with_capacity(before);
+    for a in std::mem::take(attempts) {
+        let v = serde_json::to_value(&a).context("serializing connection_config")?;
+        if !seen.contains(&v) {
+            seen.push(v);
+            attempts.push(a);
+        }
+    }
+    Ok(before - attempts.len())
+}
+
+fn merge_toml(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (k, v) in overlay {
+                match base.get_mut(&k) {
+                    Some(existing) => merge_toml(existing, v),
+                    None => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
 /// Stream domains lazily from a file. Lines may contain comments starting with '#'.
 pub fn read_domains_iter<P: AsRef<Path>>(p: P) -> Result<impl Iterator<Item = String>> {
     let file = fs::File::open(&p)
@@ -333,3 +916,88 @@ pub fn read_domains_iter<P: AsRef<Path>>(p: P) -> Result<impl Iterator<Item = St
         }
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn toml_value(s: &str) -> toml::Value {
+        toml::Value::Table(toml::from_str(s).unwrap())
+    }
+
+    /// Write `layers` to files of their own and read them as base + overlays.
+    fn read_layers(name: &str, layers: &[&str]) -> RootConfig {
+        let dir =
+            std::env::temp_dir().join(format!("quic-lab-config-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let paths: Vec<PathBuf> = layers
+            .iter()
+            .enumerate()
+            .map(|(i, layer)| {
+                let p = dir.join(format!("{i}.toml"));
+                fs::write(&p, layer).unwrap();
+                p
+            })
+            .collect();
+        let root = read_configs(&paths).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        root
+    }
+
+    #[test]
+    fn overlay_merges_nested_tables_key_by_key() {
+        let mut base = toml_value("[a]\nx = 1\ny = 2\n[a.b]\nz = 3\n");
+        merge_toml(&mut base, toml_value("[a]\ny = 5\n[a.b]\nw = 4\n"));
+        assert_eq!(base, toml_value("[a]\nx = 1\ny = 5\n[a.b]\nz = 3\nw = 4\n"));
+    }
+
+    #[test]
+    fn overlay_replaces_arrays() {
+        let mut base = toml_value("list = [1, 2, 3]\n");
+        merge_toml(&mut base, toml_value("list = [4]\n"));
+        assert_eq!(base, toml_value("list = [4]\n"));
+
+        // `[[connection_config]]` is an array too: replaced as a whole
+        let root = read_layers(
+            "attempts",
+            &[
+                "[[connection_config]]\nport = 443\n\n[[connection_config]]\nport = 8443\n",
+                "[[connection_config]]\nport = 4433\n",
+            ],
+        );
+        let ports: Vec<u16> = root.connection_config.iter().map(|a| a.port).collect();
+        assert_eq!(ports, [4433]);
+    }
+
+    #[test]
+    fn identical_attempts_collapse() {
+        let alt = ConnectionConfig {
+            port: 8443,
+            ..ConnectionConfig::default()
+        };
+        let mut attempts = vec![
+            ConnectionConfig::default(),
+            alt.clone(),
+            ConnectionConfig::default(),
+            alt,
+        ];
+
+        assert_eq!(dedup_attempts(&mut attempts).unwrap(), 2);
+        let ports: Vec<u16> = attempts.iter().map(|a| a.port).collect();
+        assert_eq!(ports, [ConnectionConfig::default().port, 8443]);
+    }
+
+    #[test]
+    fn overlay_overrides_scalars_and_keeps_the_rest() {
+        let root = read_layers(
+            "scalars",
+            &[
+                "[scheduler]\nconcurrency = 8\nrequests_per_second = 50\n",
+                "[scheduler]\nconcurrency = 2\n",
+            ],
+        );
+        assert_eq!(root.scheduler.concurrency, 2);
+        assert_eq!(root.scheduler.requests_per_second, 50);
+    }
+}
@@ -0,0 +1,146 @@
+//! Append-only `<out_dir>/diffs.jsonl` for `probe = "compare"`: one line per
+//! host whose two arms disagree on handshake, HTTP status, ALPN, failure
+//! category or (beyond `handshake_ms_threshold`) handshake time. Hosts where
+//! the arms agree get no line.
+//!
+//! A host re-run by a retry round is compared (and may be written) again.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Result as IoResult, Write};
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+use crate::types::{ArmOutcome, DiffRecord};
+
+const FILE_NAME: &str = "diffs.jsonl";
+const FLUSH_EVERY: u32 = 100; // flush every N diffs
+
+struct Inner {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    since_flush: u32,
+    handshake_ms_threshold: u64,
+}
+
+static GLOBAL: OnceLock<Mutex<Inner>> = OnceLock::new();
+
+/// Open (append) the file, if enabled.
+pub fn init(out_dir: &str, enabled: bool, handshake_ms_threshold: u64) -> anyhow::Result<()> {
+    if !enabled {
+        return Ok(());
+    }
+    let path = PathBuf::from(out_dir).join(FILE_NAME);
+    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    let _ = GLOBAL.set(Mutex::new(Inner {
+        path,
+        writer: BufWriter::new(file),
+        since_flush: 0,
+        handshake_ms_threshold,
+    }));
+    Ok(())
+}
+
+/// Fields on which `a` and `b` disagree, in [`DiffRecord::differs`] order.
+fn differences(a: &ArmOutcome, b: &ArmOutcome, handshake_ms_threshold: u64) -> Vec<&'static str> {
+    let handshake_ms = match (a.handshake_ms, b.handshake_ms) {
+        (Some(x), Some(y)) => x.abs_diff(y) > handshake_ms_threshold,
+        _ => false,
+    };
+    [
+        ("handshake_ok", a.handshake_ok != b.handshake_ok),
+        ("status", a.status != b.status),
+        ("alpn", a.alpn != b.alpn),
+        ("category", a.category != b.category),
+        ("handshake_ms", handshake_ms),
+    ]
+    .into_iter()
+    .filter_map(|(name, differs)| differs.then_some(name))
+    .collect()
+}
+
+/// Compare a host's two arms and append a line if they differ; returns
+/// whether they did (false when disabled).
+pub fn record(host: &str, a: ArmOutcome, b: ArmOutcome) -> anyhow::Result<bool> {
+    let Some(inner) = GLOBAL.get() else {
+        return Ok(false);
+    };
+    let mut g = inner.lock().unwrap();
+    let differs = differences(&a, &b, g.handshake_ms_threshold);
+    if differs.is_empty() {
+        return Ok(false);
+    }
+    let record = DiffRecord {
+        host: host.to_string(),
+        differs,
+        a,
+        b,
+    };
+    let mut buf = serde_json::to_vec(&record)?;
+    buf.push(b'\n');
+
+    g.writer.write_all(&buf)?;
+    g.since_flush += 1;
+    if g.since_flush >= FLUSH_EVERY {
+        g.writer.flush()?;
+        g.since_flush = 0;
+    }
+    Ok(true)
+}
+
+/// Path of the file, if enabled.
+pub fn path() -> Option<PathBuf> {
+    GLOBAL.get().map(|inner| inner.lock().unwrap().path.clone())
+}
+
+/// Flush the tail; the writer lives in a never-dropped `OnceLock`.
+pub fn shutdown() -> IoResult<()> {
+    if let Some(inner) = GLOBAL.get() {
+        let mut g = inner.lock().unwrap();
+        g.writer.flush()?;
+        g.since_flush = 0;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::FailureCategory;
+
+    fn ok(ms: u64) -> ArmOutcome {
+        ArmOutcome {
+            handshake_ok: true,
+            handshake_ms: Some(ms),
+            status: Some(200),
+            alpn: Some("h3".into()),
+            ..ArmOutcome::default()
+        }
+    }
+
+    #[test]
+    fn equal_arms_do_not_differ() {
+        assert!(differences(&ok(40), &ok(40), 100).is_empty());
+        // Family and address are context, not a difference
+        let mut v6 = ok(40);
+        v6.fam = "IPv6".into();
+        assert!(differences(&ok(40), &v6, 100).is_empty());
+    }
+
+    #[test]
+    fn handshake_time_differs_beyond_threshold_only() {
+        assert!(differences(&ok(40), &ok(140), 100).is_empty());
+        assert_eq!(differences(&ok(40), &ok(141), 100), ["handshake_ms"]);
+    }
+
+    #[test]
+    fn failed_arm_differs_in_outcome() {
+        let failed = ArmOutcome {
+            category: Some(FailureCategory::NoResponse),
+            ..ArmOutcome::default()
+        };
+        assert_eq!(
+            differences(&ok(40), &failed, 100),
+            ["handshake_ok", "status", "alpn", "category"]
+        );
+    }
+}
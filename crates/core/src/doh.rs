@@ -0,0 +1,426 @@
+//! DNS-over-HTTPS (RFC 8484) A/AAAA lookups for `resolver_mode = { doh = ... }`.
+//!
+//! Queries go out over HTTP/3 on the same tquic stack the probes use, as GET
+//! requests carrying the base64url-encoded DNS message, one stream per record
+//! type. The DoH connection itself is not recorded (no records, qlog, keylog
+//! or session files): it is opened untraced. The server's own name is resolved with the system
+//! resolver; an IP literal (`https://1.1.1.1/dns-query`) avoids that lookup.
+
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, error};
+use tquic::h3::connection::Http3Connection;
+use tquic::h3::{Header, Http3Config, Http3Event, NameValue};
+use tquic::Connection;
+
+use crate::config::{ConnectionConfig, GeneralConfig, IOConfig};
+use crate::recorder::Recorder;
+use crate::transport::quic::{open_connection, AppProtocol};
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// Largest DNS message (and so response body) accepted.
+const MAX_MESSAGE: usize = 65535;
+
+/// The name does not exist (NXDOMAIN), as opposed to a failed exchange.
+#[derive(Debug)]
+pub struct NxDomain(pub String);
+
+impl std::fmt::Display for NxDomain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: NXDOMAIN", self.0)
+    }
+}
+
+impl std::error::Error for NxDomain {}
+
+/// A DoH endpoint, `https://host[:port][/path]`; the path defaults to
+/// `/dns-query`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DohUrl {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl DohUrl {
+    pub fn parse(url: &str) -> Result<Self> {
+        let rest = url
+            .strip_prefix("https://")
+            .ok_or_else(|| anyhow!("DoH URL {url:?} must start with https://"))?;
+        let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(v6) => {
+                let (host, port) = v6
+                    .split_once(']')
+                    .ok_or_else(|| anyhow!("DoH URL {url:?}: unclosed '['"))?;
+                (host, port.strip_prefix(':'))
+            }
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        if host.is_empty() {
+            bail!("DoH URL {url:?} has no host");
+        }
+        let port = port
+            .map(|p| p.parse())
+            .transpose()
+            .with_context(|| format!("DoH URL {url:?}: invalid port"))?
+            .unwrap_or(443);
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: if path.is_empty() { "/dns-query" } else { path }.to_string(),
+        })
+    }
+
+    /// `:authority` value: the host, plus the port unless it is 443.
+    fn authority(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        match self.port {
+            443 => host,
+            port => format!("{host}:{port}"),
+        }
+    }
+
+    /// Request path carrying `query` as the `dns` parameter.
+    fn query_path(&self, query: &[u8]) -> String {
+        let sep = if self.path.contains('?') { '&' } else { '?' };
+        format!("{}{sep}dns={}", self.path, base64url(query))
+    }
+}
+
+/// Unpadded base64url (RFC 4648, Section 5), as RFC 8484 requires for GET.
+fn base64url(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    out
+}
+
+/// DNS query for `host`/`qtype` with ID 0 (RFC 8484, Section 4.1) and
+/// recursion desired.
+fn build_query(host: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut q = vec![0, 0, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            bail!("invalid DNS name {host:?}");
+        }
+        q.push(label.len() as u8);
+        q.extend_from_slice(label.as_bytes());
+    }
+    q.push(0);
+    q.extend_from_slice(&qtype.to_be_bytes());
+    q.extend_from_slice(&1u16.to_be_bytes()); // IN
+    Ok(q)
+}
+
+/// Offset just past the (possibly compressed) name at `pos`.
+fn skip_name(msg: &[u8], mut pos: usize) -> Result<usize> {
+    loop {
+        match *msg.get(pos).ok_or_else(|| anyhow!("truncated DNS name"))? {
+            0 => return Ok(pos + 1),
+            // A compression pointer ends the name
+            len if len & 0xc0 == 0xc0 => return Ok(pos + 2),
+            len => pos += 1 + usize::from(len),
+        }
+    }
+}
+
+/// Addresses of type `qtype` in the answer section of `msg`; CNAMEs and
+/// other records are skipped.
+fn parse_response(host: &str, msg: &[u8], qtype: u16) -> Result<Vec<IpAddr>> {
+    let truncated = || anyhow!("truncated DNS response");
+    let hdr = msg.get(..12).ok_or_else(truncated)?;
+    match hdr[3] & 0x0f {
+        0 => {}
+        RCODE_NXDOMAIN => return Err(NxDomain(host.to_string()).into()),
+        rcode => bail!("DNS error for {host}: rcode {rcode}"),
+    }
+    let questions = u16::from_be_bytes([hdr[4], hdr[5]]);
+    let answers = u16::from_be_bytes([hdr[6], hdr[7]]);
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(msg, pos)? + 4;
+    }
+    let mut out = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(msg, pos)?;
+        let rr = msg.get(pos..pos + 10).ok_or_else(truncated)?;
+        let ty = u16::from_be_bytes([rr[0], rr[1]]);
+        let len = usize::from(u16::from_be_bytes([rr[8], rr[9]]));
+        pos += 10;
+        let data = msg.get(pos..pos + len).ok_or_else(truncated)?;
+        pos += len;
+        if ty != qtype {
+            continue;
+        }
+        out.extend(match ty {
+            TYPE_A => <[u8; 4]>::try_from(data).ok().map(IpAddr::from),
+            _ => <[u8; 16]>::try_from(data).ok().map(IpAddr::from),
+        });
+    }
+    Ok(out)
+}
+
+#[derive(Debug, Default)]
+struct DohStream {
+    qtype: u16,
+    sid: Option<u64>,
+    status: Option<u16>,
+    body: Vec<u8>,
+    done: bool,
+}
+
+/// HTTP/3 app sending one GET per record type and closing once all are
+/// answered.
+struct DohApp {
+    url: DohUrl,
+    host: String,
+    h3: Option<Http3Connection>,
+    shared: Arc<Mutex<Vec<DohStream>>>,
+}
+
+impl DohApp {
+    fn send_queries(&mut self, conn: &mut Connection) -> Result<()> {
+        let h3 = Http3Config::new()
+            .and_then(|cfg| Http3Connection::new_with_quic_conn(conn, &cfg))
+            .map_err(|e| anyhow!("http3 init: {e:?}"))?;
+        let h3 = self.h3.insert(h3);
+        let authority = self.url.authority();
+        for stream in self.shared.lock().unwrap().iter_mut() {
+            let path = self.url.query_path(&build_query(&self.host, stream.qtype)?);
+            let headers = [
+                Header::new(b":method", b"GET"),
+                Header::new(b":scheme", b"https"),
+                Header::new(b":authority", authority.as_bytes()),
+                Header::new(b":path", path.as_bytes()),
+                Header::new(b"accept", b"application/dns-message"),
+            ];
+            let sid = h3
+                .stream_new(conn)
+                .map_err(|e| anyhow!("stream_new: {e:?}"))?;
+            h3.send_headers(conn, sid, &headers, true)
+                .map_err(|e| anyhow!("send_headers: {e:?}"))?;
+            stream.sid = Some(sid);
+        }
+        Ok(())
+    }
+}
+
+impl AppProtocol for DohApp {
+    fn on_connected(&mut self, conn: &mut Connection) {
+        if let Err(e) = self.send_queries(conn) {
+            error!(
+                "[{}] DoH query to {} failed: {e:#}",
+                self.host, self.url.host
+            );
+            let _ = conn.close(true, 0x1, b"doh");
+        }
+    }
+
+    fn on_stream_readable(&mut self, conn: &mut Connection, _stream_id: u64) {
+        let Some(h3) = self.h3.as_mut() else {
+            return;
+        };
+        let mut streams = self.shared.lock().unwrap();
+        while let Ok((sid, event)) = h3.poll(conn) {
+            let Some(stream) = streams.iter_mut().find(|s| s.sid == Some(sid)) else {
+                continue;
+            };
+            match event {
+                Http3Event::Headers { headers, fin } => {
+                    stream.status = headers
+                        .iter()
+                        .find(|h| h.name() == b":status")
+                        .and_then(|h| std::str::from_utf8(h.value()).ok())
+                        .and_then(|s| s.parse().ok());
+                    stream.done |= fin;
+                }
+                Http3Event::Data => {
+                    let mut buf = [0u8; 4096];
+                    while let Ok(n @ 1..) = h3.recv_body(conn, sid, &mut buf) {
+                        let room = MAX_MESSAGE.saturating_sub(stream.body.len());
+                        stream.body.extend_from_slice(&buf[..n.min(room)]);
+                    }
+                }
+                Http3Event::Finished | Http3Event::Reset(_) => stream.done = true,
+                _ => {}
+            }
+        }
+        if streams.iter().all(|s| s.done) {
+            let _ = conn.close(true, 0x00, b"ok");
+        }
+    }
+}
+
+/// Resolve `host` to its IPv4 and IPv6 socket addresses (on `port`) through
+/// the DoH server at `url`. The server certificate is checked if
+/// `verify_peer` is set.
+pub fn lookup(
+    url: &str,
+    host: &str,
+    port: u16,
+    verify_peer: bool,
+) -> Result<(Vec<SocketAddr>, Vec<SocketAddr>)> {
+    let url = DohUrl::parse(url)?;
+    let server = match url.host.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, url.port),
+        Err(_) => (url.host.as_str(), url.port)
+            .to_socket_addrs()
+            .with_context(|| format!("resolving DoH server {}", url.host))?
+            .next()
+            .ok_or_else(|| anyhow!("no address for DoH server {}", url.host))?,
+    };
+
+    let cfg = ConnectionConfig {
+        port: url.port,
+        verify_peer,
+        alpn: vec!["h3".to_string()],
+        ..ConnectionConfig::default()
+    };
+    let general = GeneralConfig {
+        save_recorder_files: false,
+        save_error_records: false,
+        save_qlog_files: false,
+        save_keylog_files: false,
+        save_session_files: false,
+        ..GeneralConfig::default()
+    };
+    let recorder = Recorder::new(std::env::temp_dir(), false, false)?;
+
+    let shared = Arc::new(Mutex::new(
+        [TYPE_A, TYPE_AAAA]
+            .map(|qtype| DohStream {
+                qtype,
+                ..DohStream::default()
+            })
+            .into(),
+    ));
+    let app = DohApp {
+        url: url.clone(),
+        host: host.to_string(),
+        h3: None,
+        shared: shared.clone(),
+    };
+    let server_name = url.host.clone();
+    open_connection(
+        &server_name,
+        &server,
+        &IOConfig::default(),
+        &general,
+        &cfg,
+        &recorder,
+        false,
+        Box::new(app),
+    )
+    .map_err(|e| anyhow!("DoH connection to {server}: {e}"))?;
+
+    let (mut v4, mut v6) = (Vec::new(), Vec::new());
+    for stream in shared.lock().unwrap().iter() {
+        if stream.status != Some(200) {
+            bail!(
+                "DoH {} for {host} (type {}): HTTP status {:?}",
+                url.host,
+                stream.qtype,
+                stream.status
+            );
+        }
+        for ip in parse_response(host, &stream.body, stream.qtype)? {
+            let list = if ip.is_ipv4() { &mut v4 } else { &mut v6 };
+            let addr = SocketAddr::new(ip, port);
+            if !list.contains(&addr) {
+                list.push(addr);
+            }
+        }
+    }
+    debug!(
+        "[{host}] DoH {}: {} A, {} AAAA",
+        url.host,
+        v4.len(),
+        v6.len()
+    );
+    Ok((v4, v6))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_urls() {
+        let u = DohUrl::parse("https://1.1.1.1/dns-query").unwrap();
+        assert_eq!(
+            (u.host.as_str(), u.port, u.path.as_str()),
+            ("1.1.1.1", 443, "/dns-query")
+        );
+        let u = DohUrl::parse("https://[2606:4700::1111]:8443").unwrap();
+        assert_eq!(
+            (u.host.as_str(), u.port, u.path.as_str()),
+            ("2606:4700::1111", 8443, "/dns-query")
+        );
+        assert_eq!(u.authority(), "[2606:4700::1111]:8443");
+        assert!(DohUrl::parse("http://1.1.1.1/dns-query").is_err());
+        assert!(DohUrl::parse("https://:443/").is_err());
+    }
+
+    #[test]
+    fn base64url_without_padding() {
+        assert_eq!(base64url(b""), "");
+        assert_eq!(base64url(b"f"), "Zg");
+        assert_eq!(base64url(b"fo"), "Zm8");
+        assert_eq!(base64url(b"foo"), "Zm9v");
+        assert_eq!(base64url(&[0xfb, 0xff]), "-_8");
+    }
+
+    #[test]
+    fn query_matches_rfc8484_example() {
+        // RFC 8484, Section 4.1.1: www.example.com, type A
+        let q = build_query("www.example.com", TYPE_A).unwrap();
+        assert_eq!(
+            base64url(&q),
+            "AAABAAABAAAAAAAAA3d3dwdleGFtcGxlA2NvbQAAAQAB"
+        );
+        assert!(build_query("a..b", TYPE_A).is_err());
+    }
+
+    #[test]
+    fn parses_answers_with_cname_and_compression() {
+        let mut msg = build_query("www.example.com", TYPE_A).unwrap();
+        msg[2] |= 0x80; // QR
+        msg[7] = 2; // two answers
+                    // CNAME www.example.com -> example.com (pointer into the question)
+        msg.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 16]);
+        // A for the pointer target
+        msg.extend_from_slice(&[0xc0, 16, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+        let ips = parse_response("www.example.com", &msg, TYPE_A).unwrap();
+        assert_eq!(ips, vec![IpAddr::from([192, 0, 2, 1])]);
+    }
+
+    #[test]
+    fn nxdomain_is_typed() {
+        let mut msg = build_query("nope.example", TYPE_AAAA).unwrap();
+        msg[3] = RCODE_NXDOMAIN;
+        let e = parse_response("nope.example", &msg, TYPE_AAAA).unwrap_err();
+        assert!(e.is::<NxDomain>());
+    }
+}
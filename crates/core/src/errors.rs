@@ -0,0 +1,188 @@
+//! Mapping of raw QUIC/TLS connection errors to findings worth recording.
+
+use tquic::error::ConnectionError;
+use tquic::h3::Http3Error;
+use tquic::Connection;
+
+use crate::types::CloseReason;
+
+/// CRYPTO_ERROR codes are 0x0100 + TLS alert (RFC 9001, Section 4.8).
+const CRYPTO_ERROR_BASE: u64 = 0x100;
+
+/// TLS `protocol_version` alert (RFC 8446, Section 6.2).
+const TLS_ALERT_PROTOCOL_VERSION: u64 = 70;
+
+/// TLS `no_application_protocol` alert (RFC 7301, Section 3.2).
+const TLS_ALERT_NO_APPLICATION_PROTOCOL: u64 = 120;
+
+/// Only TLS version a QUIC handshake can complete with (RFC 9001, Section 4.2).
+pub const QUIC_TLS_VERSION: &str = "1.3";
+
+fn is_tls_alert(err: &ConnectionError, alert: u64) -> bool {
+    !err.is_app && err.error_code == CRYPTO_ERROR_BASE + alert
+}
+
+/// Side that closed the connection with the given TLS alert, if any.
+fn alert_side(conn: &Connection, alert: u64) -> Option<&'static str> {
+    if conn.peer_error().is_some_and(|e| is_tls_alert(e, alert)) {
+        Some("peer")
+    } else if conn.local_error().is_some_and(|e| is_tls_alert(e, alert)) {
+        Some("local")
+    } else {
+        None
+    }
+}
+
+/// Detect a handshake that failed only because no ALPN could be agreed on.
+///
+/// Returns which side raised the alert: `"peer"` when the server rejected every
+/// offered protocol, `"local"` when we rejected the server's selection. Either
+/// way the host speaks QUIC, just not the application protocol we asked for.
+pub fn alpn_mismatch(conn: &Connection) -> Option<&'static str> {
+    alert_side(conn, TLS_ALERT_NO_APPLICATION_PROTOCOL)
+}
+
+/// Negotiated TLS version, as far as it can be observed.
+///
+/// tquic pins BoringSSL to TLS 1.3 on both ends of the range and does not expose
+/// the negotiated version, so a completed handshake implies 1.3.
+pub fn tls_version(conn: &Connection) -> Option<&'static str> {
+    conn.is_established().then_some(QUIC_TLS_VERSION)
+}
+
+/// Detect a handshake aborted over the TLS version (`protocol_version` alert).
+///
+/// Should never happen with a conformant server; when it does, something (the
+/// server stack or a middlebox) tried to negotiate below TLS 1.3. Returns which
+/// side raised the alert.
+pub fn tls_version_anomaly(conn: &Connection) -> Option<&'static str> {
+    alert_side(conn, TLS_ALERT_PROTOCOL_VERSION)
+}
+
+/// Name of a QPACK error code (RFC 9204, Section 6), if `code` is one.
+fn qpack_error_name(code: u64) -> Option<&'static str> {
+    match code {
+        0x200 => Some("QPACK_DECOMPRESSION_FAILED"),
+        0x201 => Some("QPACK_ENCODER_STREAM_ERROR"),
+        0x202 => Some("QPACK_DECODER_STREAM_ERROR"),
+        _ => None,
+    }
+}
+
+/// Detect a connection closed with a QPACK error code.
+///
+/// Returns the side and code, e.g. `"peer QPACK_DECODER_STREAM_ERROR"`, so
+/// header-compression interop failures are not lumped in with other H3 errors.
+pub fn qpack_close(conn: &Connection) -> Option<String> {
+    let side = |err: Option<&ConnectionError>, side: &str| {
+        err.filter(|e| e.is_app)
+            .and_then(|e| qpack_error_name(e.error_code))
+            .map(|name| format!("{side} {name}"))
+    };
+    side(conn.peer_error(), "peer").or_else(|| side(conn.local_error(), "local"))
+}
+
+/// Close reason tquic uses when a server sends PRIORITY_UPDATE, which only
+/// clients may send (RFC 9218, Section 7).
+const PRIORITY_UPDATE_FROM_SERVER: &[u8] = b"client received PRIORITY_UPDATE";
+
+/// Detect that we closed the connection because the server sent a
+/// PRIORITY_UPDATE frame (H3_FRAME_UNEXPECTED).
+pub fn peer_priority_update(conn: &Connection) -> bool {
+    conn.local_error().is_some_and(|e| {
+        e.is_app
+            && e.error_code == Http3Error::FrameUnexpected.to_wire()
+            && e.reason == PRIORITY_UPDATE_FROM_SERVER
+    })
+}
+
+/// Describe a QPACK failure reported by our own H3 layer (e.g. from `poll`).
+pub fn qpack_h3_error(err: &Http3Error) -> Option<String> {
+    qpack_error_name(err.to_wire()).map(|name| format!("local {name}"))
+}
+
+/// QUIC transport NO_ERROR (RFC 9000, Section 20.1).
+const TRANSPORT_NO_ERROR: u64 = 0x0;
+
+/// Why a closed connection ended, from its final state.
+pub fn close_reason(conn: &Connection) -> CloseReason {
+    classify_close(
+        conn.peer_error(),
+        conn.local_error(),
+        conn.is_reset(),
+        conn.is_handshake_timeout(),
+        conn.is_idle_timeout(),
+    )
+}
+
+/// An explicit CONNECTION_CLOSE wins over the timers: tquic may still time
+/// out a connection that is already closing.
+fn classify_close(
+    peer: Option<&ConnectionError>,
+    local: Option<&ConnectionError>,
+    reset: bool,
+    handshake_timeout: bool,
+    idle_timeout: bool,
+) -> CloseReason {
+    if peer.is_some() {
+        CloseReason::PeerClose
+    } else if let Some(e) = local {
+        if e.is_app || e.error_code == TRANSPORT_NO_ERROR {
+            CloseReason::LocalClose
+        } else {
+            CloseReason::Error
+        }
+    } else if reset {
+        CloseReason::Reset
+    } else if handshake_timeout {
+        CloseReason::HandshakeTimeout
+    } else if idle_timeout {
+        CloseReason::IdleTimeout
+    } else {
+        CloseReason::Error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn err(is_app: bool, error_code: u64) -> ConnectionError {
+        ConnectionError {
+            is_app,
+            error_code,
+            frame: None,
+            reason: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn idle_timeout_without_close_frame() {
+        let reason = classify_close(None, None, false, false, true);
+        assert_eq!(reason, CloseReason::IdleTimeout);
+    }
+
+    #[test]
+    fn idle_timeout_after_explicit_close_keeps_close() {
+        let peer = err(false, TRANSPORT_NO_ERROR);
+        let reason = classify_close(Some(&peer), None, false, false, true);
+        assert_eq!(reason, CloseReason::PeerClose);
+
+        let local = err(true, 0x100);
+        let reason = classify_close(None, Some(&local), false, false, true);
+        assert_eq!(reason, CloseReason::LocalClose);
+    }
+
+    #[test]
+    fn handshake_timeout_beats_idle_timeout() {
+        let reason = classify_close(None, None, false, true, true);
+        assert_eq!(reason, CloseReason::HandshakeTimeout);
+    }
+
+    #[test]
+    fn local_transport_error_is_error() {
+        let local = err(false, 0xa);
+        let reason = classify_close(None, Some(&local), false, false, false);
+        assert_eq!(reason, CloseReason::Error);
+    }
+}
@@ -0,0 +1,89 @@
+//! Append-only `<out_dir>/index.jsonl`: one line per completed host naming the
+//! recorder, qlog and keylog files its artefacts went to, so a host can be
+//! found without scanning the whole output tree.
+//!
+//! File names are the rotated names (`base.N`); the newest file of each kind
+//! is still called `base` until it is rotated.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Result as IoResult, Write};
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+use serde::Serialize;
+
+use crate::recorder::Recorder;
+use crate::{keylog, qlog};
+
+const FILE_NAME: &str = "index.jsonl";
+const FLUSH_EVERY: u32 = 100; // flush every N hosts
+
+#[derive(Serialize)]
+struct IndexEntry<'a> {
+    host: &'a str,
+    recorder_file: Option<String>,
+    qlog_file: Option<String>,
+    keylog_file: Option<String>,
+}
+
+struct Inner {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    since_flush: u32,
+}
+
+static GLOBAL: OnceLock<Mutex<Inner>> = OnceLock::new();
+
+/// Open (append) the index, if enabled.
+pub fn init(out_dir: &str, enabled: bool) -> anyhow::Result<()> {
+    if !enabled {
+        return Ok(());
+    }
+    let path = PathBuf::from(out_dir).join(FILE_NAME);
+    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    let _ = GLOBAL.set(Mutex::new(Inner {
+        path,
+        writer: BufWriter::new(file),
+        since_flush: 0,
+    }));
+    Ok(())
+}
+
+/// Append the current output locations for a host that just completed.
+pub fn record(host: &str, recorder: &Recorder) -> anyhow::Result<()> {
+    let Some(inner) = GLOBAL.get() else {
+        return Ok(());
+    };
+    let entry = IndexEntry {
+        host,
+        recorder_file: recorder.current_file(),
+        qlog_file: qlog::current_file(),
+        keylog_file: keylog::current_file(),
+    };
+    let mut buf = serde_json::to_vec(&entry)?;
+    buf.push(b'\n');
+
+    let mut g = inner.lock().unwrap();
+    g.writer.write_all(&buf)?;
+    g.since_flush += 1;
+    if g.since_flush >= FLUSH_EVERY {
+        g.writer.flush()?;
+        g.since_flush = 0;
+    }
+    Ok(())
+}
+
+/// Path of the index, if enabled.
+pub fn path() -> Option<PathBuf> {
+    GLOBAL.get().map(|inner| inner.lock().unwrap().path.clone())
+}
+
+/// Flush the index tail; the writer lives in a never-dropped `OnceLock`.
+pub fn shutdown() -> IoResult<()> {
+    if let Some(inner) = GLOBAL.get() {
+        let mut g = inner.lock().unwrap();
+        g.writer.flush()?;
+        g.since_flush = 0;
+    }
+    Ok(())
+}
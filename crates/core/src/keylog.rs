@@ -2,7 +2,8 @@ use std::io::{Result as IoResult, Write};
 use std::path::PathBuf;
 use std::sync::{Mutex, OnceLock};
 
-use crate::rotate::{NewFileHook, RotatingWriter};
+use crate::manifest::SinkFiles;
+use crate::rotate::{NewFileHook, RotatingWriter, RotationPolicy};
 
 const BASE_NAME: &str = "quic-lab.keylog";
 const MAX_KEYLOG_BYTES: u64 = 256 * 1024 * 1024;
@@ -14,6 +15,8 @@ impl NewFileHook for NoHook {}
 struct Inner {
     writer: RotatingWriter<NoHook>,
     since_flush: u32,
+    /// Key log lines written this run
+    lines: u64,
 }
 
 pub struct KeylogSink {
@@ -22,20 +25,33 @@ pub struct KeylogSink {
 
 static GLOBAL: OnceLock<KeylogSink> = OnceLock::new();
 
-/// Initialise global, rotated keylog sink: `<out_dir>/keylog_files/quic-lab.keylog[.N]`
-pub fn init(out_dir: &str, enabled: bool) -> anyhow::Result<()> {
+/// Initialise global, rotated keylog sink: `<out_dir>/keylog_files/quic-lab.keylog[.N[.gz]]`
+pub fn init(
+    out_dir: &str,
+    enabled: bool,
+    compress_rotated: bool,
+    max_files: Option<usize>,
+) -> anyhow::Result<()> {
     if !enabled {
         return Ok(());
     }
 
     let dir = PathBuf::from(out_dir).join("keylog_files");
     std::fs::create_dir_all(&dir)?;
-    let writer = RotatingWriter::new(&dir, BASE_NAME, MAX_KEYLOG_BYTES, Some(NoHook))?;
+    let writer = RotatingWriter::with_policy(
+        &dir,
+        BASE_NAME,
+        RotationPolicy::Size(MAX_KEYLOG_BYTES),
+        compress_rotated,
+        Some(NoHook),
+    )?
+    .with_max_files(max_files);
 
     let sink = KeylogSink {
         inner: Mutex::new(Inner {
             writer,
             since_flush: 0,
+            lines: 0,
         }),
     };
 
@@ -50,6 +66,7 @@ fn append_line(line: &[u8]) -> IoResult<()> {
     if let Some(sink) = GLOBAL.get() {
         let mut g = sink.inner.lock().unwrap();
         g.writer.write_all(line)?;
+        g.lines += 1;
         g.since_flush += 1;
         if g.since_flush >= FLUSH_EVERY {
             g.writer.flush()?;
@@ -59,6 +76,36 @@ fn append_line(line: &[u8]) -> IoResult<()> {
     Ok(())
 }
 
+/// Flush the global keylog sink. It lives in a `OnceLock` and is never dropped,
+/// so this is the only point the `since_flush < FLUSH_EVERY` tail is written.
+pub fn shutdown() -> IoResult<()> {
+    if let Some(sink) = GLOBAL.get() {
+        let mut g = sink.inner.lock().unwrap();
+        g.writer.flush()?;
+        g.since_flush = 0;
+    }
+    Ok(())
+}
+
+/// Keylog files and lines written, for the manifest (None when disabled).
+pub fn sink_files() -> Option<SinkFiles> {
+    GLOBAL.get().map(|sink| {
+        let g = sink.inner.lock().unwrap();
+        SinkFiles {
+            files: g.writer.files(),
+            records: Some(g.lines),
+        }
+    })
+}
+
+/// Final name of the active keylog file, if enabled (see
+/// [`RotatingWriter::final_name`]).
+pub fn current_file() -> Option<String> {
+    GLOBAL
+        .get()
+        .map(|sink| sink.inner.lock().unwrap().writer.final_name())
+}
+
 pub fn is_enabled() -> bool {
     GLOBAL.get().is_some()
 }
@@ -1,14 +1,27 @@
 use std::hash::{DefaultHasher, Hash, Hasher};
 
+use crate::recorder::Recorder;
+
+pub mod budget;
+pub mod by_ip;
 pub mod config;
+pub mod diffs;
+pub mod doh;
+pub mod errors;
+pub mod index;
 pub mod keylog;
 pub mod logging;
+pub mod manifest;
+pub mod progress;
 pub mod qlog;
 pub mod recorder;
 pub mod resolver;
+pub mod retry;
 pub mod rotate;
+pub mod summary;
 pub mod throttle;
 pub mod transport;
+pub mod triage;
 pub mod types;
 
 fn shard2(base: &std::path::Path, host: &str) -> std::path::PathBuf {
@@ -18,3 +31,42 @@ fn shard2(base: &std::path::Path, host: &str) -> std::path::PathBuf {
     base.join(format!("{:02x}", (x >> 56) & 0xff))
         .join(format!("{:02x}", (x >> 48) & 0xff))
 }
+
+/// Flush every global sink before exit, in order: keylog, qlog, recorder, the
+/// host index, triage CSV and compare diffs, the by-IP aggregate, the run summary, the manifest of all of
+/// these and finally the log writer (so failures of the others can still be
+/// logged).
+/// The sinks live in process-wide statics that are never dropped, so without
+/// this their buffered tails are lost.
+pub fn shutdown_all(recorder: &Recorder) {
+    if let Err(e) = keylog::shutdown() {
+        log::error!("keylog shutdown flush failed: {e}");
+    }
+    if let Err(e) = qlog::shutdown() {
+        log::error!("qlog shutdown flush failed: {e}");
+    }
+    if let Err(e) = recorder.flush() {
+        log::error!("recorder shutdown flush failed: {e}");
+    }
+    if let Err(e) = index::shutdown() {
+        log::error!("index shutdown flush failed: {e}");
+    }
+    if let Err(e) = triage::shutdown() {
+        log::error!("triage.csv shutdown flush failed: {e}");
+    }
+    if let Err(e) = diffs::shutdown() {
+        log::error!("diffs.jsonl shutdown flush failed: {e}");
+    }
+    if let Err(e) = by_ip::shutdown() {
+        log::error!("by_ip.json write failed: {e}");
+    }
+    if let Err(e) = summary::shutdown() {
+        log::error!("summary.json write failed: {e}");
+    }
+    // Rotated files still being gzipped, before the manifest lists them
+    rotate::wait_compressions();
+    if let Err(e) = manifest::write(recorder) {
+        log::error!("manifest.json write failed: {e}");
+    }
+    logging::shutdown();
+}
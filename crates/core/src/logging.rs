@@ -5,7 +5,9 @@ use tracing_appender::non_blocking::{self, WorkerGuard};
 use tracing_log::LogTracer;
 use tracing_subscriber::{fmt, EnvFilter};
 
-use crate::rotate::{NewFileHook, RotatingWriter};
+use crate::manifest::SinkFiles;
+use crate::rotate::{rotated_files, NewFileHook, RotatingWriter, RotationPolicy};
+use crate::types::TimeRotation;
 
 const MAX_LOG_BYTES: u64 = 128 * 1024 * 1024;
 const BASE_NAME: &str = "quic-lab.log";
@@ -23,7 +25,12 @@ impl std::io::Write for ThreadSafeWriter {
     }
 }
 
-static LOG_GUARD: OnceLock<WorkerGuard> = OnceLock::new();
+// Kept in an Option so `shutdown` can drop it: dropping the guard flushes the
+// non-blocking worker.
+static LOG_GUARD: Mutex<Option<WorkerGuard>> = Mutex::new(None);
+
+// The writer moves into the background worker; remember where it writes.
+static LOG_DIR: OnceLock<PathBuf> = OnceLock::new();
 
 fn map_level(l: log::LevelFilter) -> tracing_subscriber::filter::LevelFilter {
     use log::LevelFilter as L;
@@ -38,21 +45,34 @@ fn map_level(l: log::LevelFilter) -> tracing_subscriber::filter::LevelFilter {
     }
 }
 
-/// Initialise logging to `<out_dir>/log_files/quic-lab.log` with rotation.
-pub fn init_file_logger(out_dir: &str, level: log::LevelFilter) -> anyhow::Result<PathBuf> {
+/// Initialise logging to `<out_dir>/log_files/quic-lab.log` with rotation
+/// (by size, and by time if `time_rotation` is set), optionally gzipping
+/// rotated files and keeping at most `max_files` of them.
+pub fn init_file_logger(
+    out_dir: &str,
+    level: log::LevelFilter,
+    time_rotation: TimeRotation,
+    compress_rotated: bool,
+    max_files: Option<usize>,
+) -> anyhow::Result<PathBuf> {
     let dir = std::path::PathBuf::from(out_dir).join("log_files");
     std::fs::create_dir_all(&dir)?;
 
-    let writer = ThreadSafeWriter(Mutex::new(RotatingWriter::new(
-        &dir,
-        BASE_NAME,
-        MAX_LOG_BYTES,
-        Some(NoHook),
-    )?));
+    let writer = ThreadSafeWriter(Mutex::new(
+        RotatingWriter::with_policy(
+            &dir,
+            BASE_NAME,
+            RotationPolicy::size_or(MAX_LOG_BYTES, time_rotation),
+            compress_rotated,
+            Some(NoHook),
+        )?
+        .with_max_files(max_files),
+    ));
 
     // Non-blocking channel + background worker (default capacity, lossy).
     let (nb, guard) = non_blocking::NonBlockingBuilder::default().finish(writer);
-    let _ = LOG_GUARD.set(guard);
+    LOG_GUARD.lock().unwrap().get_or_insert(guard);
+    let _ = LOG_DIR.set(dir.clone());
 
     let _ = LogTracer::init();
 
@@ -82,3 +102,17 @@ pub fn init_file_logger(out_dir: &str, level: log::LevelFilter) -> anyhow::Resul
 
     Ok(dir.join(BASE_NAME))
 }
+
+/// Log files, for the manifest (None when file logging is off).
+pub fn sink_files() -> Option<SinkFiles> {
+    LOG_DIR.get().map(|dir| SinkFiles {
+        files: rotated_files(dir, BASE_NAME),
+        records: None,
+    })
+}
+
+/// Flush queued log lines and stop the background writer. Logging after this
+/// point is dropped, so call it last.
+pub fn shutdown() {
+    drop(LOG_GUARD.lock().unwrap().take());
+}
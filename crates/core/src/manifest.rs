@@ -0,0 +1,118 @@
+//! `<out_dir>/manifest.json`: every output file of the run, written last at
+//! exit so ingestion can iterate it instead of globbing the output tree.
+//!
+//! Each sink is asked for its files (rotations included) after the others
+//! were flushed; sizes are read from disk then. Record counts cover what this
+//! run wrote and are only given where a sink counts them. The log file is
+//! still open at that point, so its size may miss the last lines.
+
+use std::collections::BTreeMap;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::recorder::Recorder;
+use crate::{by_ip, diffs, index, keylog, logging, qlog, summary, triage};
+
+const FILE_NAME: &str = "manifest.json";
+
+/// Files of one output kind and, where counted, the records written this run.
+pub struct SinkFiles {
+    pub files: Vec<PathBuf>,
+    pub records: Option<u64>,
+}
+
+impl SinkFiles {
+    /// A single file that is written as a whole (no rotation, no count).
+    pub fn single(path: PathBuf) -> Self {
+        Self {
+            files: vec![path],
+            records: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct FileEntry {
+    kind: &'static str,
+    /// Relative to `out_dir`
+    path: String,
+    bytes: u64,
+}
+
+#[derive(Serialize)]
+struct ManifestFile {
+    files: Vec<FileEntry>,
+    /// Records written this run, per kind (where counted)
+    records: BTreeMap<&'static str, u64>,
+}
+
+static OUT_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Enable the manifest; it is written by [`write`].
+pub fn init(out_dir: &str, enabled: bool) {
+    if enabled {
+        let _ = OUT_DIR.set(PathBuf::from(out_dir));
+    }
+}
+
+fn relative(out_dir: &Path, path: &Path) -> String {
+    path.strip_prefix(out_dir)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Write `manifest.json`, if enabled. Call after the other sinks are flushed.
+pub fn write(recorder: &Recorder) -> Result<()> {
+    let Some(out_dir) = OUT_DIR.get() else {
+        return Ok(());
+    };
+
+    let [(rec_kind, rec), (err_kind, errs), (done_kind, done)] = recorder.sink_files();
+    let sinks = [
+        (rec_kind, rec),
+        (err_kind, errs),
+        (done_kind, done),
+        ("qlog", qlog::sink_files()),
+        ("keylog", keylog::sink_files()),
+        ("log", logging::sink_files()),
+        ("index", index::path().map(SinkFiles::single)),
+        ("triage", triage::path().map(SinkFiles::single)),
+        ("diffs", diffs::path().map(SinkFiles::single)),
+        ("by_ip", by_ip::path().map(SinkFiles::single)),
+        ("summary", summary::path().map(SinkFiles::single)),
+    ];
+
+    let mut manifest = ManifestFile {
+        files: Vec::new(),
+        records: BTreeMap::new(),
+    };
+    for (kind, sink) in sinks {
+        let Some(sink) = sink else {
+            continue;
+        };
+        for path in &sink.files {
+            // Skip files that were never created (e.g. nothing to aggregate)
+            let Ok(meta) = std::fs::metadata(path) else {
+                continue;
+            };
+            manifest.files.push(FileEntry {
+                kind,
+                path: relative(out_dir, path),
+                bytes: meta.len(),
+            });
+        }
+        if let Some(n) = sink.records {
+            manifest.records.insert(kind, n);
+        }
+    }
+
+    let mut w = BufWriter::new(std::fs::File::create(out_dir.join(FILE_NAME))?);
+    serde_json::to_writer_pretty(&mut w, &manifest)?;
+    w.flush()?;
+    Ok(())
+}
@@ -0,0 +1,51 @@
+//! Last progress on every host being probed, for the runner's stalled-worker
+//! watchdog (`worker_stall_secs`).
+//!
+//! The runner registers a host when a worker starts on it; the transport marks
+//! progress each time one of its connections opens or closes, so a host that
+//! works through many addresses and retries is not mistaken for a hang.
+//! Marks for hosts that were not registered (DoH servers) are ignored.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+static LAST: OnceLock<Mutex<HashMap<String, Instant>>> = OnceLock::new();
+
+/// Enable tracking (no-op otherwise).
+pub fn init(enabled: bool) {
+    if enabled {
+        let _ = LAST.set(Mutex::new(HashMap::new()));
+    }
+}
+
+/// A worker started on `host`.
+pub fn begin(host: &str) {
+    if let Some(last) = LAST.get() {
+        last.lock()
+            .unwrap()
+            .insert(host.to_string(), Instant::now());
+    }
+}
+
+/// Work on `host` moved on (a connection opened or closed).
+pub fn mark(host: &str) {
+    let Some(last) = LAST.get() else {
+        return;
+    };
+    if let Some(t) = last.lock().unwrap().get_mut(host) {
+        *t = Instant::now();
+    }
+}
+
+/// The worker is done with `host`.
+pub fn end(host: &str) {
+    if let Some(last) = LAST.get() {
+        last.lock().unwrap().remove(host);
+    }
+}
+
+/// When `host` last made progress, if it is being probed.
+pub fn last(host: &str) -> Option<Instant> {
+    LAST.get()?.lock().unwrap().get(host).copied()
+}
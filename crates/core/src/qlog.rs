@@ -1,6 +1,7 @@
 use std::collections::HashMap;
+use std::fs::File;
 use std::io::{BufWriter, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Mutex, OnceLock};
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -8,7 +9,11 @@ use anyhow::Result;
 use serde::Serialize;
 use serde_json::{json, Map, Value};
 
-use crate::rotate::{NewFileHook, RotatingWriter};
+use crate::config::GeneralConfig;
+use crate::manifest::SinkFiles;
+use crate::rotate::{NewFileHook, RotatingWriter, RotationPolicy};
+use crate::shard2;
+use crate::types::{QlogFraming, QlogMode, QlogProfile, QlogTimeFormat};
 
 const BASE_NAME: &str = "quic-lab.sqlog";
 const MAX_SQLOG_BYTES: u64 = 256 * 1024 * 1024;
@@ -16,10 +21,6 @@ const RS: u8 = 0x1E;
 const LF: u8 = b'\n';
 const FLUSH_EVERY: u32 = 2000; // flush every N records
 
-/// When true, keep only fields/events that qvis + some custom stats.
-/// When false, write the full events as received.
-pub const MINIMIZE_QLOG: bool = true;
-
 #[derive(Clone)]
 struct QlogHeaderHook {
     title: String,
@@ -27,17 +28,31 @@ struct QlogHeaderHook {
     vp_name: String,
     vp_type: String,
     reference_time_ms: f64,
+    framing: QlogFraming,
 }
 
 impl QlogHeaderHook {
-    fn with_epoch(epoch: SystemTime) -> Self {
-        let ms = epoch.duration_since(UNIX_EPOCH).unwrap().as_secs_f64() * 1000.0;
+    fn with_epoch(epoch: SystemTime, time_format: QlogTimeFormat, framing: QlogFraming) -> Self {
+        // Absolute mode: a zero reference makes every relative time an epoch time
+        let ms = match time_format {
+            QlogTimeFormat::Relative => epoch_ms(epoch),
+            QlogTimeFormat::Absolute => 0.0,
+        };
         Self {
             title: "quic-lab session".into(),
             description: "Aggregated multi-connection log".into(),
             vp_name: "quic-lab".into(),
             vp_type: "client".into(),
             reference_time_ms: ms,
+            framing,
+        }
+    }
+
+    /// Header of a single connection's file (`qlog_mode = "per_connection"`).
+    fn for_connection(group_id: &str, time_format: QlogTimeFormat, framing: QlogFraming) -> Self {
+        Self {
+            description: format!("Connection {group_id}"),
+            ..Self::with_epoch(SystemTime::now(), time_format, framing)
         }
     }
 }
@@ -48,7 +63,17 @@ impl NewFileHook for QlogHeaderHook {
         _path: &std::path::Path,
         file: &mut std::fs::File,
     ) -> std::io::Result<()> {
-        // Single JSON-SEQ header at the start of each .sqlog
+        self.write_header(file)?;
+        file.flush()
+    }
+}
+
+impl QlogHeaderHook {
+    /// Single JSON-SEQ header at the start of each .sqlog; NDJSON has none
+    fn write_header<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        if self.framing == QlogFraming::Ndjson {
+            return Ok(());
+        }
         let header = json!({
           "qlog_version": "0.4",
           "qlog_format":  "JSON-SEQ",
@@ -62,14 +87,17 @@ impl NewFileHook for QlogHeaderHook {
             "vantage_point": { "name": self.vp_name, "type": self.vp_type }
           }
         });
-        file.write_all(&[RS])?;
-        serde_json::to_writer(&mut *file, &header)?;
-        file.write_all(&[LF])?;
-        file.flush()?;
-        Ok(())
+        out.write_all(&[RS])?;
+        serde_json::to_writer(&mut *out, &header)?;
+        out.write_all(&[LF])
     }
 }
 
+#[inline]
+fn epoch_ms(t: SystemTime) -> f64 {
+    t.duration_since(UNIX_EPOCH).unwrap().as_secs_f64() * 1000.0
+}
+
 #[inline]
 fn ms_since(then: SystemTime) -> f64 {
     let now = SystemTime::now();
@@ -79,44 +107,141 @@ fn ms_since(then: SystemTime) -> f64 {
     }
 }
 
+/// Where the mux writes: rotating files, or any writer (tests, embedding).
+enum MuxOutput {
+    Files(Box<RotatingWriter<QlogHeaderHook>>),
+    Writer(Box<dyn Write + Send>),
+}
+
+impl MuxOutput {
+    fn files(&self) -> Option<&RotatingWriter<QlogHeaderHook>> {
+        match self {
+            Self::Files(w) => Some(w),
+            Self::Writer(_) => None,
+        }
+    }
+}
+
+impl Write for MuxOutput {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Files(w) => w.write(data),
+            Self::Writer(w) => w.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Files(w) => w.flush(),
+            Self::Writer(w) => w.flush(),
+        }
+    }
+}
+
 struct Inner {
-    bufw: BufWriter<RotatingWriter<QlogHeaderHook>>,
+    bufw: BufWriter<MuxOutput>,
     epoch: SystemTime,
     since_flush: u32,
+    /// Events written this run
+    events: u64,
     // last emitted time per group_id to keep traces strictly monotonic
     last_t: HashMap<String, f64>,
 }
 
 pub struct QlogMux {
     inner: Mutex<Inner>,
+    time_format: QlogTimeFormat,
+    framing: QlogFraming,
+    /// Minimization applied to connection traces
+    profile: QlogProfile,
+    /// Root of the per-connection files, when `qlog_mode = "per_connection"`
+    conn_dir: Option<PathBuf>,
 }
 
 static GLOBAL: OnceLock<QlogMux> = OnceLock::new();
 
 impl QlogMux {
-    fn new(out_dir: &str) -> std::io::Result<Self> {
+    fn new(out_dir: &str, general: &GeneralConfig) -> std::io::Result<Self> {
+        let (time_format, framing) = (general.qlog_time_format, general.qlog_framing);
         let dir = PathBuf::from(out_dir).join("qlog_files");
         std::fs::create_dir_all(&dir)?;
         let epoch = SystemTime::now();
-        let hook = QlogHeaderHook::with_epoch(epoch);
-        let writer = RotatingWriter::new(&dir, BASE_NAME, MAX_SQLOG_BYTES, Some(hook))?;
-        Ok(Self {
+        let hook = QlogHeaderHook::with_epoch(epoch, time_format, framing);
+        let policy = RotationPolicy::size_or(MAX_SQLOG_BYTES, general.time_rotation);
+        let writer = RotatingWriter::with_policy(
+            &dir,
+            BASE_NAME,
+            policy,
+            general.compress_rotated,
+            Some(hook),
+        )?
+        .with_max_files(general.max_rotated_files);
+        let mut mux = Self::with_output(
+            MuxOutput::Files(Box::new(writer)),
+            epoch,
+            time_format,
+            framing,
+        )
+        .with_profile(general.qlog_profile);
+        if general.qlog_mode == QlogMode::PerConnection {
+            mux.conn_dir = Some(dir);
+        }
+        Ok(mux)
+    }
+
+    /// Mux writing to `out` instead of files under `out_dir`: the JSON-SEQ
+    /// header once, then events as with [`init`], never rotated.
+    pub fn with_writer<W: Write + Send + 'static>(
+        mut out: W,
+        time_format: QlogTimeFormat,
+        framing: QlogFraming,
+    ) -> std::io::Result<Self> {
+        let epoch = SystemTime::now();
+        QlogHeaderHook::with_epoch(epoch, time_format, framing).write_header(&mut out)?;
+        let out = MuxOutput::Writer(Box::new(out));
+        Ok(Self::with_output(out, epoch, time_format, framing))
+    }
+
+    fn with_output(
+        out: MuxOutput,
+        epoch: SystemTime,
+        time_format: QlogTimeFormat,
+        framing: QlogFraming,
+    ) -> Self {
+        Self {
             inner: Mutex::new(Inner {
-                bufw: BufWriter::with_capacity(256 * 1024, writer),
+                bufw: BufWriter::with_capacity(256 * 1024, out),
                 epoch,
                 since_flush: 0,
+                events: 0,
                 last_t: HashMap::new(),
             }),
-        })
+            time_format,
+            framing,
+            profile: QlogProfile::default(),
+            conn_dir: None,
+        }
+    }
+
+    /// Minimize connection traces per `profile` (default: qvis).
+    pub fn with_profile(mut self, profile: QlogProfile) -> Self {
+        self.profile = profile;
+        self
     }
 
+    /// Append one RS…LF frame, without the RS in NDJSON mode.
     fn append_record(&self, record: &[u8]) -> std::io::Result<()> {
         // Drop any per-connection JSON-SEQ headers; keep only events
         if is_header_frame(record) {
             return Ok(());
         }
+        let record = match self.framing {
+            QlogFraming::JsonSeq => record,
+            QlogFraming::Ndjson => record.strip_prefix(&[RS]).unwrap_or(record),
+        };
         let mut g = self.inner.lock().unwrap();
         g.bufw.write_all(record)?;
+        g.events += 1;
         g.since_flush += 1;
         if g.since_flush >= FLUSH_EVERY {
             g.bufw.flush()?;
@@ -134,7 +259,10 @@ impl QlogMux {
         let mut g = self.inner.lock().unwrap();
 
         // make time strictly monotonic per group_id
-        let mut t_ms = ms_since(g.epoch);
+        let mut t_ms = match self.time_format {
+            QlogTimeFormat::Relative => ms_since(g.epoch),
+            QlogTimeFormat::Absolute => epoch_ms(SystemTime::now()),
+        };
         if let Some(prev) = g.last_t.get(group_id) {
             if t_ms <= *prev {
                 t_ms = prev + 1e-6;
@@ -143,9 +271,12 @@ impl QlogMux {
         g.last_t.insert(group_id.to_string(), t_ms);
 
         let ev = json!({ "time": t_ms, "name": name, "group_id": group_id, "data": data });
-        g.bufw.write_all(&[RS])?;
+        if self.framing == QlogFraming::JsonSeq {
+            g.bufw.write_all(&[RS])?;
+        }
         serde_json::to_writer(&mut g.bufw, &ev)?;
         g.bufw.write_all(&[LF])?;
+        g.events += 1;
         g.since_flush += 1;
         if g.since_flush >= FLUSH_EVERY {
             g.bufw.flush()?;
@@ -154,6 +285,18 @@ impl QlogMux {
         Ok(())
     }
 
+    /// Flush all buffered events to disk.
+    ///
+    /// The mux lives in a `OnceLock` for the whole process and is never dropped,
+    /// so up to `FLUSH_EVERY` events sit in the `BufWriter` until this is called.
+    /// Call it once all connections are closed (end of run or on interrupt).
+    pub fn shutdown(&self) -> std::io::Result<()> {
+        let mut g = self.inner.lock().unwrap();
+        g.bufw.flush()?;
+        g.since_flush = 0;
+        Ok(())
+    }
+
     pub fn info(&self, group_id: &str, message: &str) {
         let _ = self.append_event(group_id, "loglevel:info", &json!({ "message": message }));
     }
@@ -172,14 +315,59 @@ pub fn is_enabled() -> bool {
     GLOBAL.get().is_some()
 }
 
-pub fn init(out_dir: &str, enabled: bool) -> Result<()> {
-    if !enabled {
+/// Final name of the active qlog file, if enabled (see
+/// [`RotatingWriter::final_name`]).
+pub fn current_file() -> Option<String> {
+    GLOBAL.get().and_then(|q| {
+        q.inner
+            .lock()
+            .unwrap()
+            .bufw
+            .get_ref()
+            .files()
+            .map(|w| w.final_name())
+    })
+}
+
+/// qlog files and events written, for the manifest (None when disabled).
+pub fn sink_files() -> Option<SinkFiles> {
+    GLOBAL.get().map(|q| {
+        let g = q.inner.lock().unwrap();
+        SinkFiles {
+            files: g
+                .bufw
+                .get_ref()
+                .files()
+                .map(|w| w.files())
+                .unwrap_or_default(),
+            records: Some(g.events),
+        }
+    })
+}
+
+/// Drain the global mux, if enabled. See [`QlogMux::shutdown`].
+pub fn shutdown() -> std::io::Result<()> {
+    match GLOBAL.get() {
+        Some(q) => q.shutdown(),
+        None => Ok(()),
+    }
+}
+
+/// Open the global mux under `<out_dir>/qlog_files`, if `save_qlog_files`.
+pub fn init(out_dir: &str, general: &GeneralConfig) -> Result<()> {
+    if !general.save_qlog_files {
         return Ok(());
     }
-    let _ = GLOBAL.set(QlogMux::new(out_dir)?);
+    let _ = GLOBAL.set(QlogMux::new(out_dir, general)?);
     Ok(())
 }
 
+/// Install `mux` (e.g. from [`QlogMux::with_writer`]) as the global mux that
+/// connections log to, when embedding; no-op if one is already set.
+pub fn init_with_mux(mux: QlogMux) {
+    let _ = GLOBAL.set(mux);
+}
+
 // Detects JSON-SEQ header frames: look for known header keys between RS…LF.
 fn is_header_frame(frame: &[u8]) -> bool {
     if frame.first() != Some(&RS) {
@@ -206,10 +394,10 @@ fn vobj(v: &mut Value) -> Option<&mut Map<String, Value>> {
     v.as_object_mut()
 }
 
-/// Reduce event payload to what qvis + custom stats.
+/// Reduce event payload per `profile` (see [`QlogProfile`]).
 /// Returns `false` to drop the event entirely.
-fn qvis_minimize_in_place(ev: &mut Value) -> bool {
-    if !MINIMIZE_QLOG {
+fn qvis_minimize_in_place(ev: &mut Value, profile: QlogProfile) -> bool {
+    if profile == QlogProfile::Full {
         return true;
     }
 
@@ -220,6 +408,15 @@ fn qvis_minimize_in_place(ev: &mut Value) -> bool {
         .map(str::to_owned)
         .unwrap_or_default();
 
+    let is_packet_event = name.starts_with("quic:packet_") || name == "recovery:packet_lost";
+    if profile == QlogProfile::PacketsOnly
+        && !is_packet_event
+        && !name.starts_with("meta:")
+        && !name.starts_with("loglevel:")
+    {
+        return false;
+    }
+
     // Always keep meta:* (e.g., meta:connection for labels) and loglevel:*
     if name.starts_with("meta:") || name.starts_with("loglevel:") {
         // Still prune heavy subfields if any
@@ -303,12 +500,17 @@ fn qvis_minimize_in_place(ev: &mut Value) -> bool {
                     }
                 }
 
-                // frames: keep only frame_type (and optionally stream_id if present)
+                // frames: keep only frame_type (and optionally stream_id if present);
+                // ACKs also keep acked_ranges and ack_delay for loss/reordering analysis
                 if let Some(frames) = data.get_mut("frames").and_then(|f| f.as_array_mut()) {
                     for f in frames.iter_mut() {
                         if let Some(fo) = f.as_object_mut() {
                             let ft = fo.get("frame_type").cloned();
                             let sid = fo.get("stream_id").cloned(); // cheap; ok to keep if present
+                            let ack = match ft.as_ref().and_then(Value::as_str) {
+                                Some("ack") => (fo.remove("acked_ranges"), fo.remove("ack_delay")),
+                                _ => (None, None),
+                            };
                             fo.clear();
                             if let Some(v) = ft {
                                 fo.insert("frame_type".into(), v);
@@ -316,6 +518,12 @@ fn qvis_minimize_in_place(ev: &mut Value) -> bool {
                             if let Some(v) = sid {
                                 fo.insert("stream_id".into(), v);
                             }
+                            if let Some(v) = ack.0 {
+                                fo.insert("acked_ranges".into(), v);
+                            }
+                            if let Some(v) = ack.1 {
+                                fo.insert("ack_delay".into(), v);
+                            }
                         }
                     }
                 }
@@ -355,69 +563,142 @@ fn qvis_minimize_in_place(ev: &mut Value) -> bool {
     true
 }
 
+/// Where a `PerConnSqlog` sends its frames.
+enum FrameSink {
+    /// The global mux (normal operation)
+    Mux(&'static QlogMux),
+    /// The connection's own file (`qlog_mode = "per_connection"`), framed
+    /// per `qlog_framing` after its own header
+    File(BufWriter<File>, QlogFraming),
+    /// Any writer, receiving RS…LF frames as-is (e.g. an in-memory buffer)
+    Writer(Box<dyn Write + Send + Sync>),
+}
+
 /// Per-connection writer: splits RS…LF and forwards to the mux.
 /// Adds a fixed `group_id` if missing and keeps times monotonic per connection.
-/// Optionally strips payload to a minimal subset when `QVIS_MINIMAL` is true.
+/// Strips payloads per the configured [`QlogProfile`].
 pub struct PerConnSqlog {
     buf: Vec<u8>,
     gid: String,
+    profile: QlogProfile,
     last_t: Option<f64>,
+    // Added to tquic's per-connection times (relative to when qlog was attached)
+    t_offset: f64,
+    sink: FrameSink,
 }
 
 impl PerConnSqlog {
+    /// Create right before handing the writer to tquic, so that "now" matches
+    /// the connection's own time origin.
+    ///
+    /// With `qlog_mode = "per_connection"` the trace bypasses the mux and goes
+    /// to `qlog_files/<shard>/<group_id>.sqlog` (the mux if that file cannot
+    /// be created).
     pub fn new(group_id: &str) -> Option<Self> {
-        if is_enabled() {
-            Some(Self {
-                buf: Vec::with_capacity(8 * 1024),
-                gid: group_id.to_string(),
-                last_t: None,
-            })
-        } else {
-            None
+        let mux = qlog()?;
+        let sink = match &mux.conn_dir {
+            None => FrameSink::Mux(mux),
+            Some(dir) => match open_conn_file(dir, group_id, mux.time_format, mux.framing) {
+                Ok(file) => FrameSink::File(BufWriter::new(file), mux.framing),
+                Err(e) => {
+                    log::error!("creating qlog file for {group_id} failed: {e}");
+                    FrameSink::Mux(mux)
+                }
+            },
+        };
+        Some(Self::with_sink(
+            group_id,
+            mux.time_format,
+            mux.profile,
+            sink,
+        ))
+    }
+
+    /// Same processing, but frames go to `out` instead of the global mux
+    /// (header frames dropped, events always JSON-SEQ framed).
+    pub fn with_writer<W: Write + Send + Sync + 'static>(
+        group_id: &str,
+        time_format: QlogTimeFormat,
+        profile: QlogProfile,
+        out: W,
+    ) -> Self {
+        Self::with_sink(
+            group_id,
+            time_format,
+            profile,
+            FrameSink::Writer(Box::new(out)),
+        )
+    }
+
+    fn with_sink(
+        group_id: &str,
+        time_format: QlogTimeFormat,
+        profile: QlogProfile,
+        sink: FrameSink,
+    ) -> Self {
+        Self {
+            buf: Vec::with_capacity(8 * 1024),
+            gid: group_id.to_string(),
+            profile,
+            last_t: None,
+            t_offset: match time_format {
+                QlogTimeFormat::Relative => 0.0,
+                QlogTimeFormat::Absolute => epoch_ms(SystemTime::now()),
+            },
+            sink,
         }
     }
 
+    fn emit(&mut self, rec: &[u8]) {
+        let _ = match &mut self.sink {
+            FrameSink::Mux(mux) => mux.append_record(rec),
+            FrameSink::File(..) | FrameSink::Writer(_) if is_header_frame(rec) => Ok(()),
+            FrameSink::File(w, QlogFraming::Ndjson) => {
+                w.write_all(rec.strip_prefix(&[RS]).unwrap_or(rec))
+            }
+            FrameSink::File(w, QlogFraming::JsonSeq) => w.write_all(rec),
+            FrameSink::Writer(w) => w.write_all(rec),
+        };
+    }
+
     // Forward one complete RS … JSON … LF frame, injecting group_id and fixing time if needed.
     fn forward_frame(&mut self, rec: Vec<u8>) {
-        if let Some(mux) = qlog() {
-            if rec.len() >= 3 && rec[0] == RS && rec[rec.len() - 1] == LF {
-                let payload = &rec[1..rec.len() - 1];
-                if let Ok(mut v) = serde_json::from_slice::<Value>(payload) {
-                    // ensure group_id
-                    if v.get("group_id").is_none() {
-                        if let Some(obj) = v.as_object_mut() {
-                            obj.insert("group_id".to_string(), Value::String(self.gid.clone()));
-                        }
-                    }
-                    // enforce monotonic time per connection
-                    if let Some(t) = v.get("time").and_then(|x| x.as_f64()) {
-                        let t_adj = match self.last_t {
-                            Some(prev) if t <= prev => prev + 1e-6,
-                            _ => t,
-                        };
-                        if let Some(obj) = v.as_object_mut() {
-                            if (t_adj - t).abs() > f64::EPSILON {
-                                obj.insert("time".into(), Value::from(t_adj));
-                            }
-                        }
-                        self.last_t = Some(t_adj);
-                    }
-
-                    // Optionally reduce to what qvis/custom stats need.
-                    if !qvis_minimize_in_place(&mut v) {
-                        return; // drop this event entirely
+        if rec.len() >= 3 && rec[0] == RS && rec[rec.len() - 1] == LF {
+            let payload = &rec[1..rec.len() - 1];
+            if let Ok(mut v) = serde_json::from_slice::<Value>(payload) {
+                // ensure group_id
+                if let Some(obj) = v.as_object_mut() {
+                    obj.entry("group_id")
+                        .or_insert_with(|| Value::String(self.gid.clone()));
+                }
+                // enforce monotonic time per connection
+                if let Some(t_raw) = v.get("time").and_then(|x| x.as_f64()) {
+                    let t = t_raw + self.t_offset;
+                    let t_adj = match self.last_t {
+                        Some(prev) if t <= prev => prev + 1e-6,
+                        _ => t,
+                    };
+                    let moved = (t_adj - t_raw).abs() > f64::EPSILON;
+                    if let Some(obj) = v.as_object_mut().filter(|_| moved) {
+                        obj.insert("time".into(), Value::from(t_adj));
                     }
+                    self.last_t = Some(t_adj);
+                }
 
-                    let mut out = Vec::with_capacity(payload.len().min(4096) + 256);
-                    out.push(RS);
-                    let _ = serde_json::to_writer(&mut out, &v);
-                    out.push(LF);
-                    let _ = mux.append_record(&out);
-                    return;
+                // Reduce per profile (by default to what qvis/custom stats need).
+                if !qvis_minimize_in_place(&mut v, self.profile) {
+                    return; // drop this event entirely
                 }
+
+                let mut out = Vec::with_capacity(payload.len().min(4096) + 256);
+                out.push(RS);
+                let _ = serde_json::to_writer(&mut out, &v);
+                out.push(LF);
+                self.emit(&out);
+                return;
             }
-            let _ = mux.append_record(&rec); // fallback (unparsed or malformed)
         }
+        self.emit(&rec); // fallback (unparsed or malformed)
     }
 }
 
@@ -468,12 +749,258 @@ impl Write for PerConnSqlog {
         }
         // Drop any leftovers that are not a full frame
         self.buf.clear();
-        Ok(())
+        match &mut self.sink {
+            FrameSink::File(w, _) => w.flush(),
+            _ => Ok(()),
+        }
     }
 }
 
+/// Create `<dir>/<shard>/<group_id>.sqlog` with its own qlog header.
+fn open_conn_file(
+    dir: &Path,
+    group_id: &str,
+    time_format: QlogTimeFormat,
+    framing: QlogFraming,
+) -> std::io::Result<File> {
+    let sdir = shard2(dir, group_id);
+    std::fs::create_dir_all(&sdir)?;
+    let path = sdir.join(format!("{group_id}.sqlog"));
+    let mut file = File::create(&path)?;
+    QlogHeaderHook::for_connection(group_id, time_format, framing).on_new_file(&path, &mut file)?;
+    Ok(file)
+}
+
 impl Drop for PerConnSqlog {
     fn drop(&mut self) {
         let _ = self.flush();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// Writer whose bytes can still be read after it was boxed away.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn frame(v: Value) -> Vec<u8> {
+        let mut out = vec![RS];
+        out.extend(serde_json::to_vec(&v).unwrap());
+        out.push(LF);
+        out
+    }
+
+    /// A tquic-style per-connection trace: header, then events with
+    /// out-of-order times, heavy packet fields and droppable events.
+    fn trace() -> Vec<u8> {
+        let events = [
+            json!({"qlog_format": "JSON-SEQ", "qlog_version": "0.4", "trace": {}}),
+            json!({"time": 1.0, "name": "quic:parameters_set", "data": {"owner": "local"}}),
+            json!({"time": 2.0, "name": "quic:packet_sent", "data": {
+                "header": {"packet_type": "initial", "packet_number": 0, "scid": "ab", "dcil": 8},
+                "raw": {"length": 1200, "payload_length": 1100, "data": "00ff"},
+                "frames": [{"frame_type": "crypto", "offset": 0, "length": 300}],
+            }}),
+            json!({"time": 1.5, "name": "quic:stream_data_moved", "data": {"stream_id": 0}}),
+            json!({"time": 1.5, "name": "recovery:metrics_updated", "data": {"cwnd": 1}}),
+            json!({"time": 1.5, "name": "quic:packet_received", "data": {"raw": {"length": 50}}}),
+            json!({"time": 3.0, "name": "quic:connection_closed", "data": {"raw": "x"}}),
+        ];
+        events.into_iter().flat_map(frame).collect()
+    }
+
+    /// Run `input` through a `PerConnSqlog`, written in `chunk`-byte pieces.
+    fn run(input: &[u8], chunk: usize) -> Vec<u8> {
+        run_profile(input, chunk, QlogProfile::Qvis)
+    }
+
+    fn run_profile(input: &[u8], chunk: usize, profile: QlogProfile) -> Vec<u8> {
+        let out = SharedBuf::default();
+        let mut w =
+            PerConnSqlog::with_writer("conn-1", QlogTimeFormat::Relative, profile, out.clone());
+        for part in input.chunks(chunk) {
+            w.write_all(part).unwrap();
+        }
+        drop(w);
+        out.0.lock().unwrap().clone()
+    }
+
+    fn parse_frames(out: &[u8]) -> Vec<Value> {
+        assert_eq!(out.first(), Some(&RS), "output must start with a frame");
+        out.split(|&b| b == RS)
+            .skip(1)
+            .map(|f| {
+                assert_eq!(f.last(), Some(&LF), "frame must end with LF");
+                serde_json::from_slice(&f[..f.len() - 1]).expect("frame is valid JSON")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_frame_is_valid_json_with_group_id_and_monotonic_time() {
+        for chunk in [1, 7, 64, usize::MAX] {
+            let frames = parse_frames(&run(&trace(), chunk));
+            assert_eq!(frames.len(), 4, "chunk size {chunk}");
+
+            let mut last = f64::NEG_INFINITY;
+            for ev in &frames {
+                assert_eq!(ev["group_id"], "conn-1");
+                let t = ev["time"].as_f64().expect("time is a number");
+                assert!(t > last, "time {t} not after {last}");
+                last = t;
+            }
+        }
+    }
+
+    #[test]
+    fn header_and_dropped_events_are_absent() {
+        let frames = parse_frames(&run(&trace(), usize::MAX));
+        let names: Vec<&str> = frames.iter().filter_map(|e| e["name"].as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "quic:parameters_set",
+                "quic:packet_sent",
+                "quic:packet_received",
+                "quic:connection_closed"
+            ]
+        );
+        assert!(frames.iter().all(|e| e.get("qlog_format").is_none()));
+    }
+
+    #[test]
+    fn packet_events_are_minimized() {
+        let frames = parse_frames(&run(&trace(), usize::MAX));
+        let sent = &frames[1]["data"];
+        assert_eq!(
+            sent["header"],
+            json!({"packet_type": "initial", "packet_number": 0, "dcil": 8})
+        );
+        assert_eq!(sent["raw"], json!({"length": 1200, "payload_length": 1100}));
+        assert_eq!(sent["frames"], json!([{"frame_type": "crypto"}]));
+        assert!(frames[3]["data"].get("raw").is_none());
+    }
+
+    #[test]
+    fn ack_ranges_survive_minimization() {
+        let input = frame(
+            json!({"time": 1.0, "name": "quic:packet_received", "data": {
+                "header": {"packet_type": "1RTT", "packet_number": 7},
+                "raw": {"length": 40, "payload_length": 20},
+                "frames": [
+                    {"frame_type": "ack", "ack_delay": 0.25, "acked_ranges": [[0, 3], [5, 9]],
+                     "ect1": 0, "raw": {"length": 8}},
+                    {"frame_type": "padding", "length": 4},
+                ],
+            }}),
+        );
+        let frames = parse_frames(&run(&input, usize::MAX));
+        assert_eq!(
+            frames[0]["data"]["frames"],
+            json!([
+                {"frame_type": "ack", "acked_ranges": [[0, 3], [5, 9]], "ack_delay": 0.25},
+                {"frame_type": "padding"},
+            ])
+        );
+    }
+
+    #[test]
+    fn full_profile_keeps_raw_payloads_and_every_event() {
+        let frames = parse_frames(&run_profile(&trace(), usize::MAX, QlogProfile::Full));
+        assert_eq!(frames.len(), 6);
+        let sent = &frames[1]["data"];
+        assert_eq!(sent["raw"]["data"], "00ff");
+        assert_eq!(sent["header"]["scid"], "ab");
+        assert_eq!(sent["frames"][0]["length"], 300);
+        assert_eq!(frames[5]["data"]["raw"], "x");
+    }
+
+    #[test]
+    fn packets_only_profile_keeps_packet_events() {
+        let frames = parse_frames(&run_profile(&trace(), usize::MAX, QlogProfile::PacketsOnly));
+        let names: Vec<&str> = frames.iter().filter_map(|e| e["name"].as_str()).collect();
+        assert_eq!(names, ["quic:packet_sent", "quic:packet_received"]);
+        assert_eq!(
+            frames[0]["data"]["raw"],
+            json!({"length": 1200, "payload_length": 1100})
+        );
+    }
+
+    #[test]
+    fn mux_writes_header_and_events_to_any_writer() {
+        let out = SharedBuf::default();
+        let mux = QlogMux::with_writer(out.clone(), QlogTimeFormat::Relative, QlogFraming::JsonSeq)
+            .unwrap();
+        mux.info("conn-1", "hello");
+        mux.info("conn-1", "again");
+        mux.shutdown().unwrap();
+
+        let frames = parse_frames(&out.0.lock().unwrap());
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0]["qlog_format"], "JSON-SEQ");
+        assert_eq!(frames[1]["name"], "loglevel:info");
+        assert_eq!(frames[2]["data"]["message"], "again");
+        assert!(frames[2]["time"].as_f64() > frames[1]["time"].as_f64());
+    }
+
+    #[test]
+    fn ndjson_mux_has_no_header_or_record_separators() {
+        let out = SharedBuf::default();
+        let mux = QlogMux::with_writer(out.clone(), QlogTimeFormat::Relative, QlogFraming::Ndjson)
+            .unwrap();
+        mux.info("conn-1", "hello");
+        mux.shutdown().unwrap();
+
+        let bytes = out.0.lock().unwrap().clone();
+        assert!(!bytes.contains(&RS));
+        let ev: Value = serde_json::from_slice(bytes.strip_suffix(&[LF]).unwrap()).unwrap();
+        assert_eq!(ev["group_id"], "conn-1");
+    }
+
+    #[test]
+    fn per_connection_file_has_own_header_and_sharded_path() {
+        let dir = std::env::temp_dir().join(format!("quic-lab-qlog-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let file = open_conn_file(
+            &dir,
+            "conn-1",
+            QlogTimeFormat::Relative,
+            QlogFraming::JsonSeq,
+        )
+        .unwrap();
+        let sink = FrameSink::File(BufWriter::new(file), QlogFraming::JsonSeq);
+        let mut w =
+            PerConnSqlog::with_sink("conn-1", QlogTimeFormat::Relative, QlogProfile::Qvis, sink);
+        w.write_all(&trace()).unwrap();
+        drop(w);
+
+        let path = shard2(&dir, "conn-1").join("conn-1.sqlog");
+        let frames = parse_frames(&std::fs::read(&path).unwrap());
+        assert_eq!(frames.len(), 5);
+        assert_eq!(frames[0]["description"], "Connection conn-1");
+        assert_eq!(frames[4]["name"], "quic:connection_closed");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn incomplete_trailing_frame_is_dropped() {
+        let mut input = trace();
+        input.extend_from_slice(&[RS, b'{', b'"']);
+        let frames = parse_frames(&run(&input, 5));
+        assert_eq!(frames.len(), 4);
+    }
+}
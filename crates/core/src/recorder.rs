@@ -1,16 +1,20 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use serde::Serialize;
 use serde_json::json;
-use std::fs::create_dir_all;
-use std::io::Write;
+use std::collections::HashSet;
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
-use crate::rotate::{NewFileHook, RotatingWriter};
+use crate::manifest::SinkFiles;
+use crate::rotate::{rotated_files, NewFileHook, RotatingWriter};
 
 const BASE_NAME: &str = "quic-lab-recorder.jsonl";
+const ERRORS_BASE_NAME: &str = "errors.jsonl";
 const MAX_RECORDER_BYTES: u64 = 128 * 1024 * 1024;
 const FLUSH_EVERY: u32 = 2000; // flush every N records
+const DONE_FILE_NAME: &str = "progress.done";
 
 struct NoHook;
 impl NewFileHook for NoHook {}
@@ -20,36 +24,235 @@ struct Inner {
     dir: PathBuf,
     base: String,
     since_flush: u32,
+    /// Records written this run
+    records: u64,
+}
+
+impl Inner {
+    fn open(dir: &Path, base: &str) -> Result<Arc<Mutex<Self>>> {
+        let writer = RotatingWriter::new(dir, base, MAX_RECORDER_BYTES, Some(NoHook))?;
+        Ok(Arc::new(Mutex::new(Inner {
+            writer,
+            dir: dir.to_path_buf(),
+            base: base.to_string(),
+            since_flush: 0,
+            records: 0,
+        })))
+    }
+}
+
+/// `<out_dir>/progress.done`: completed hosts, one per line, for `--resume`.
+///
+/// A host is only appended once its records are flushed, so a crash can lose
+/// done-marks (those hosts are probed again) but never records of a host
+/// marked done. A host in flight at the crash has no mark.
+struct DoneLog {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    /// Completed hosts waiting for the next flush of the records
+    pending: Vec<String>,
+}
+
+impl DoneLog {
+    /// Open the file, continuing it when resuming and starting over otherwise.
+    fn open(out_dir: &Path, resume: bool) -> Result<Self> {
+        let path = out_dir.join(DONE_FILE_NAME);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(resume)
+            .write(true)
+            .truncate(!resume)
+            .open(&path)
+            .with_context(|| format!("opening {}", path.display()))?;
+        // Drop a line cut off by a crash rather than completing it
+        if resume {
+            let mut text = Vec::new();
+            file.read_to_end(&mut text)?;
+            let keep = text.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+            if keep < text.len() {
+                file.set_len(keep as u64)?;
+            }
+        }
+        Ok(Self {
+            path,
+            writer: BufWriter::new(file),
+            pending: Vec::new(),
+        })
+    }
+
+    /// Append the pending hosts; call only once their records are on disk.
+    fn commit(&mut self) -> std::io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        for host in self.pending.drain(..) {
+            self.writer.write_all(host.as_bytes())?;
+            self.writer.write_all(b"\n")?;
+        }
+        self.writer.flush()
+    }
+}
+
+/// Hosts completed by an earlier run into `out_dir` (its `progress.done`).
+/// A last line without its newline (cut off by a crash) does not count.
+///
+/// Without `progress.done` (an out_dir written before it existed), every key
+/// in the recorder files counts instead, see [`recorded_keys`]; a host in
+/// flight when that run died may then be counted as done.
+pub fn completed_hosts<P: AsRef<Path>>(out_dir: P, fast: bool) -> Result<HashSet<String>> {
+    let path = out_dir.as_ref().join(DONE_FILE_NAME);
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let mut keys = recorded_keys(out_dir, fast)?;
+            keys.remove("run");
+            return Ok(keys);
+        }
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+    Ok(text
+        .split_inclusive('\n')
+        .filter_map(|line| line.strip_suffix('\n'))
+        .map(str::trim)
+        .filter(|host| !host.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Every `key` in the main recorder files under `out_dir` (rotations
+/// included), skipping a last line cut off by a crash.
+///
+/// `fast` memory-maps each file and takes the key straight from the
+/// `{"key":"..."` prefix every record starts with, parsing only lines where
+/// that is ambiguous (escapes, other layouts); otherwise each line is parsed.
+pub fn recorded_keys<P: AsRef<Path>>(out_dir: P, fast: bool) -> Result<HashSet<String>> {
+    let dir = out_dir.as_ref().join("recorder_files");
+    let mut keys = HashSet::new();
+    for path in rotated_files(&dir, BASE_NAME) {
+        let read = if fast {
+            scan_keys(&path, &mut keys)
+        } else {
+            parse_keys(&path, &mut keys)
+        };
+        read.with_context(|| format!("reading {}", path.display()))?;
+    }
+    Ok(keys)
+}
+
+#[derive(serde::Deserialize)]
+struct KeyOnly {
+    key: String,
+}
+
+/// Key of one complete record line, by full parsing (None if not a record).
+fn parse_key(line: &[u8]) -> Option<String> {
+    serde_json::from_slice::<KeyOnly>(line).ok().map(|r| r.key)
+}
+
+/// Key of one complete record line from its prefix, falling back to
+/// [`parse_key`] when the key is escaped or the line is laid out differently.
+fn scan_key(line: &[u8]) -> Option<String> {
+    const PREFIX: &[u8] = br#"{"key":""#;
+    let key = line.strip_prefix(PREFIX).and_then(|rest| {
+        let end = rest.iter().position(|&b| b == b'"' || b == b'\\')?;
+        (rest[end] == b'"').then(|| std::str::from_utf8(&rest[..end]).ok())?
+    });
+    key.map(str::to_string).or_else(|| parse_key(line))
+}
+
+fn scan_keys(path: &Path, keys: &mut HashSet<String>) -> Result<()> {
+    let file = File::open(path)?;
+    if file.metadata()?.len() == 0 {
+        return Ok(()); // mapping an empty file fails
+    }
+    // Safety: the file is only read; an earlier run's output is not written
+    // to while resuming.
+    let map = unsafe { memmap2::Mmap::map(&file)? };
+    // The part after the last '\n' is a record cut off by a crash
+    let complete = map
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(&map[..0], |i| &map[..i]);
+    keys.extend(complete.split(|&b| b == b'\n').filter_map(scan_key));
+    Ok(())
+}
+
+fn parse_keys(path: &Path, keys: &mut HashSet<String>) -> Result<()> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+        if line.pop() == Some(b'\n') {
+            keys.extend(parse_key(&line));
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct Recorder {
     // None = disabled (save_recorder_files = false)
     inner: Option<Arc<Mutex<Inner>>>,
+    // Failed-host records only (save_error_records = true)
+    errors: Option<Arc<Mutex<Inner>>>,
+    // Completed hosts (`with_progress`), committed with the records
+    done: Option<Arc<Mutex<DoneLog>>>,
 }
 
 impl Recorder {
-    pub fn new<P: AsRef<Path>>(root: P, save_recorder_files: bool) -> Result<Self> {
-        if !save_recorder_files {
-            return Ok(Self { inner: None });
-        }
-
+    pub fn new<P: AsRef<Path>>(
+        root: P,
+        save_recorder_files: bool,
+        save_error_records: bool,
+    ) -> Result<Self> {
         let dir = root.as_ref().join("recorder_files");
-        create_dir_all(&dir)?;
-
-        let base = BASE_NAME.to_string();
-        let writer = RotatingWriter::new(&dir, &base, MAX_RECORDER_BYTES, Some(NoHook))?;
+        if save_recorder_files || save_error_records {
+            create_dir_all(&dir)?;
+        }
 
         Ok(Self {
-            inner: Some(Arc::new(Mutex::new(Inner {
-                writer,
-                dir,
-                base,
-                since_flush: 0,
-            }))),
+            inner: save_recorder_files
+                .then(|| Inner::open(&dir, BASE_NAME))
+                .transpose()?,
+            errors: save_error_records
+                .then(|| Inner::open(&dir, ERRORS_BASE_NAME))
+                .transpose()?,
+            done: None,
         })
     }
 
+    /// Also keep `<out_dir>/progress.done` (see [`Recorder::mark_done`]),
+    /// continuing an earlier run's file when `resume` is set.
+    pub fn with_progress<P: AsRef<Path>>(mut self, out_dir: P, resume: bool) -> Result<Self> {
+        self.done = Some(Arc::new(Mutex::new(DoneLog::open(
+            out_dir.as_ref(),
+            resume,
+        )?)));
+        Ok(self)
+    }
+
+    /// Mark `host` completed once every record written for it so far is on
+    /// disk: at the next flush of the recorder, or at once when it is off.
+    pub fn mark_done(&self, host: &str) -> Result<()> {
+        let Some(done) = &self.done else {
+            return Ok(());
+        };
+        let mut done = done.lock().unwrap();
+        done.pending.push(host.to_string());
+        if self.inner.is_none() {
+            done.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Whether the main sink is on; callers may skip building records if not.
+    pub fn is_enabled(&self) -> bool {
+        self.inner.is_some()
+    }
+
     /// Append one JSON record for the given key.
     ///
     /// Format (one record per line):
@@ -57,33 +260,160 @@ impl Recorder {
     ///
     /// Returns the current active file path (or empty when disabled).
     pub fn write_for_key<T: Serialize>(&self, key: &str, value: &T) -> Result<PathBuf> {
-        let Some(inner) = &self.inner else {
-            // recorder disabled via config
-            return Ok(PathBuf::new());
-        };
-
-        let mut g = inner.lock().unwrap();
+        write_record(self.inner.as_ref(), self.done.as_ref(), key, value)
+    }
 
-        // Build a single JSON object and serialize it into a contiguous buffer.
-        let record = json!({
-            "key": key,
-            "value": value,
-        });
+    /// Append a failed host's record to `errors.jsonl` as well, if enabled.
+    /// Same format as [`Recorder::write_for_key`], which must still be called.
+    pub fn write_error_for_key<T: Serialize>(&self, key: &str, value: &T) -> Result<PathBuf> {
+        write_record(self.errors.as_ref(), None, key, value)
+    }
 
-        let mut buf = serde_json::to_vec(&record)?;
-        buf.push(b'\n');
+    /// Final name of the file records are currently written to (see
+    /// [`RotatingWriter::final_name`]); None when disabled.
+    pub fn current_file(&self) -> Option<String> {
+        self.inner
+            .as_ref()
+            .map(|inner| inner.lock().unwrap().writer.final_name())
+    }
 
-        // One write for the entire record; rotation can only happen
-        // before this call (so the whole record goes into the new file).
-        g.writer.write_all(&buf)?;
+    /// Files and record counts of the main and `errors.jsonl` sinks and
+    /// `progress.done`, for the manifest (None when disabled).
+    pub fn sink_files(&self) -> [(&'static str, Option<SinkFiles>); 3] {
+        let files = |sink: &Option<Arc<Mutex<Inner>>>| {
+            sink.as_ref().map(|inner| {
+                let g = inner.lock().unwrap();
+                SinkFiles {
+                    files: g.writer.files(),
+                    records: Some(g.records),
+                }
+            })
+        };
+        let progress = self
+            .done
+            .as_ref()
+            .map(|done| SinkFiles::single(done.lock().unwrap().path.clone()));
+        [
+            ("recorder", files(&self.inner)),
+            ("errors", files(&self.errors)),
+            ("progress", progress),
+        ]
+    }
 
-        g.since_flush += 1;
-        if g.since_flush >= FLUSH_EVERY {
+    /// Flush any records written since the last periodic flush, then the
+    /// hosts they complete.
+    pub fn flush(&self) -> Result<()> {
+        for inner in [&self.inner, &self.errors].into_iter().flatten() {
+            let mut g = inner.lock().unwrap();
             g.writer.flush()?;
             g.since_flush = 0;
         }
+        if let Some(done) = &self.done {
+            done.lock().unwrap().commit()?;
+        }
+        Ok(())
+    }
+}
+
+/// Append one `{"key", "value"}` line to `sink`; a no-op returning an empty
+/// path when the sink is disabled. `done` hosts are committed after each
+/// periodic flush.
+fn write_record<T: Serialize>(
+    sink: Option<&Arc<Mutex<Inner>>>,
+    done: Option<&Arc<Mutex<DoneLog>>>,
+    key: &str,
+    value: &T,
+) -> Result<PathBuf> {
+    let Some(inner) = sink else {
+        // disabled via config
+        return Ok(PathBuf::new());
+    };
+
+    let mut g = inner.lock().unwrap();
+
+    // Build a single JSON object and serialize it into a contiguous buffer.
+    let record = json!({
+        "key": key,
+        "value": value,
+    });
+
+    let mut buf = serde_json::to_vec(&record)?;
+    buf.push(b'\n');
+
+    // One write for the entire record; rotation can only happen
+    // before this call (so the whole record goes into the new file).
+    g.writer.write_all(&buf)?;
+    g.records += 1;
+
+    g.since_flush += 1;
+    if g.since_flush >= FLUSH_EVERY {
+        g.writer.flush()?;
+        g.since_flush = 0;
+        if let Some(done) = done {
+            done.lock().unwrap().commit()?;
+        }
+    }
+
+    // Active file is always "<dir>/<base>"; rotated files are "<base>.1", ".2", ...
+    Ok(g.dir.join(&g.base))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resume_ignores_cut_off_line_and_keeps_done_hosts() {
+        let dir = std::env::temp_dir().join(format!("quic-lab-done-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(DONE_FILE_NAME), "a.example\nb.exa").unwrap();
+        assert_eq!(
+            completed_hosts(&dir, false).unwrap(),
+            HashSet::from(["a.example".to_string()])
+        );
+
+        let recorder = Recorder::new(&dir, false, false)
+            .unwrap()
+            .with_progress(&dir, true)
+            .unwrap();
+        recorder.mark_done("c.example").unwrap();
+        let done = completed_hosts(&dir, false).unwrap();
+        assert!(done.contains("a.example") && done.contains("c.example"));
+        assert_eq!(done.len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fast_key_scan_matches_parsing() {
+        let dir = std::env::temp_dir().join(format!("quic-lab-keys-{}", std::process::id()));
+        let recorder = Recorder::new(&dir, true, false).unwrap();
+        for key in [
+            "run",
+            "a.example",
+            "quote\"d",
+            "back\\slash",
+            "ünïcode",
+            "a.example",
+        ] {
+            recorder
+                .write_for_key(key, &json!({"key": "inner"}))
+                .unwrap();
+        }
+        recorder.flush().unwrap();
+        drop(recorder);
+        // Records of another layout, and one cut off by a crash
+        let path = dir.join("recorder_files").join(BASE_NAME);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"value\":1,\"key\":\"late.example\"}\n{\"key\":\"cut.exa")
+            .unwrap();
+
+        let fast = recorded_keys(&dir, true).unwrap();
+        assert_eq!(fast, recorded_keys(&dir, false).unwrap());
+        assert_eq!(fast.len(), 6);
+        assert!(fast.contains("quote\"d") && fast.contains("late.example"));
+        assert!(!fast.iter().any(|k| k.starts_with("cut")));
 
-        // Active file is always "<dir>/<base>"; rotated files are "<base>.1", ".2", ...
-        Ok(g.dir.join(&g.base))
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -1,72 +1,431 @@
 use anyhow::{anyhow, Result};
-use std::net::{SocketAddr, ToSocketAddrs};
+use log::debug;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
-use crate::types::IpVersion;
+use crate::config::ConnectionConfig;
+use crate::doh;
+use crate::types::{IpVersion, ResolverFallback, ResolverMode};
 
-/// Resolve a single address honoring an explicit family
-pub fn resolve_peer(host: &str, port: u16, family: IpVersion) -> Result<SocketAddr> {
-    let addrs = (host, port).to_socket_addrs()?;
-    let pick = match family {
-        IpVersion::Auto => addrs.into_iter().next(),
-        IpVersion::Ipv4 => addrs.into_iter().find(|a| a.is_ipv4()),
-        IpVersion::Ipv6 => addrs.into_iter().find(|a| a.is_ipv6()),
-    };
-    pick.ok_or_else(|| anyhow!("no matching address for {host}:{port} ({:?})", family))
+/// Labels for the resolver that produced an answer.
+pub const RESOLVER_SYSTEM: &str = "system";
+pub const RESOLVER_SYSTEM_FALLBACK: &str = "system-fallback";
+pub const RESOLVER_DOH: &str = "doh";
+pub const RESOLVER_OVERRIDE: &str = "override";
+
+/// Addresses to try for one attempt, after per-family/overall caps.
+#[derive(Debug, Clone)]
+pub struct Resolved {
+    pub targets: Vec<(IpVersion, SocketAddr)>,
+    /// Matching addresses returned by the resolver before capping
+    pub available: usize,
+    /// Resolver that produced the answer
+    pub resolver: &'static str,
+    /// Answer came from a cache entry past `dns_cache_ttl_secs` (being refreshed)
+    pub stale: bool,
+    /// Private/reserved addresses were dropped (`skip_private_addresses`)
+    pub skipped_bogon: bool,
 }
 
-/// Resolve one IPv4 and/or one IPv6 when Auto is requested
-pub fn resolve_peers_for_both(
-    host: &str,
+/// Every address of the host was private/reserved and `skip_private_addresses`
+/// is set; the host is not probed.
+#[derive(Debug)]
+pub struct BogonSkipped {
+    pub host: String,
+    pub skipped: usize,
+}
+
+impl std::fmt::Display for BogonSkipped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: all {} resolved addresses are private/reserved, skipped",
+            self.host, self.skipped
+        )
+    }
+}
+
+impl std::error::Error for BogonSkipped {}
+
+type Addrs = (Vec<SocketAddr>, Vec<SocketAddr>);
+
+/// One name to resolve and where; also the cache key. Both families come
+/// from one lookup, so the family is applied later (`select`), not part of
+/// the key.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Query {
+    host: String,
     port: u16,
-) -> Result<(Option<SocketAddr>, Option<SocketAddr>)> {
-    let mut v4: Option<SocketAddr> = None;
-    let mut v6: Option<SocketAddr> = None;
+    mode: ResolverMode,
+    /// Check the DoH server's certificate
+    verify_peer: bool,
+}
+
+struct CacheEntry {
+    addrs: Addrs,
+    at: Instant,
+    /// A background refresh is in flight; don't start another
+    refreshing: bool,
+}
+
+/// Process-wide answers.
+fn cache() -> &'static Mutex<HashMap<Query, CacheEntry>> {
+    static CACHE: OnceLock<Mutex<HashMap<Query, CacheEntry>>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Process-wide failed lookups: error text and when.
+fn negative_cache() -> &'static Mutex<HashMap<Query, (String, Instant)>> {
+    static CACHE: OnceLock<Mutex<HashMap<Query, (String, Instant)>>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Whether a failed lookup through `mode` may succeed on retry. The system
+/// resolver reports every failure as the same error kind, so this goes by the
+/// `gai_strerror` text (EAI_AGAIN); anything else (NXDOMAIN, no data) is
+/// final. DoH failures are final only for NXDOMAIN: any other one (even the
+/// DoH server's own name not resolving) is about the resolver, not the host.
+fn is_transient(mode: &ResolverMode, e: &anyhow::Error) -> bool {
+    match mode {
+        ResolverMode::DoH { .. } => !e.is::<doh::NxDomain>(),
+        ResolverMode::System => e.downcast_ref::<std::io::Error>().is_none_or(|e| {
+            let msg = e.to_string().to_ascii_lowercase();
+            msg.contains("temporary failure") || msg.contains("try again")
+        }),
+    }
+}
+
+/// `lookup`, remembering final failures for `negative_ttl` (0 = never) so a
+/// dead host is not looked up again for every attempt.
+fn lookup_negative(q: &Query, negative_ttl: Duration) -> Result<Addrs> {
+    if negative_ttl.is_zero() {
+        return lookup(q);
+    }
+    if let Some((msg, _)) = negative_cache()
+        .lock()
+        .unwrap()
+        .get(q)
+        .filter(|(_, at)| at.elapsed() < negative_ttl)
+    {
+        return Err(anyhow!("{msg} (cached)"));
+    }
+    lookup(q).inspect_err(|e| {
+        if !is_transient(&q.mode, e) {
+            negative_cache()
+                .lock()
+                .unwrap()
+                .insert(q.clone(), (format!("{e:#}"), Instant::now()));
+        }
+    })
+}
+
+/// Resolve all distinct IPv4 and IPv6 addresses, in resolver order
+fn lookup(q: &Query) -> Result<Addrs> {
+    let url = match &q.mode {
+        ResolverMode::System => return lookup_system(&q.host, q.port),
+        ResolverMode::DoH { url } => url,
+    };
+    doh::lookup(url, &q.host, q.port, q.verify_peer)
+}
+
+fn lookup_system(host: &str, port: u16) -> Result<Addrs> {
+    let mut v4: Vec<SocketAddr> = Vec::new();
+    let mut v6: Vec<SocketAddr> = Vec::new();
 
     for addr in (host, port).to_socket_addrs()? {
-        if addr.is_ipv4() && v4.is_none() {
-            v4 = Some(addr);
+        let list = if addr.is_ipv4() { &mut v4 } else { &mut v6 };
+        if !list.contains(&addr) {
+            list.push(addr);
         }
-        if addr.is_ipv6() && v6.is_none() {
-            v6 = Some(addr);
+    }
+    Ok((v4, v6))
+}
+
+/// Re-resolve in the background and replace the entry; on failure the stale
+/// entry stays until it ages out.
+fn refresh(q: Query) {
+    std::thread::spawn(move || {
+        let res = lookup(&q);
+        let mut cache = cache().lock().unwrap();
+        match res {
+            Ok(addrs) => {
+                cache.insert(
+                    q,
+                    CacheEntry {
+                        addrs,
+                        at: Instant::now(),
+                        refreshing: false,
+                    },
+                );
+            }
+            Err(e) => {
+                debug!("[{}] background DNS refresh failed: {e:#}", q.host);
+                if let Some(entry) = cache.get_mut(&q) {
+                    entry.refreshing = false;
+                }
+            }
         }
-        if v4.is_some() && v6.is_some() {
-            break;
+    });
+}
+
+/// `lookup` through the cache. Fresh entries (younger than `ttl`) are served
+/// as is; entries up to `stale` past that are served immediately while a
+/// refresh runs in the background. Returns whether the answer was stale.
+fn cached_lookup(
+    q: &Query,
+    ttl: Duration,
+    stale: Duration,
+    negative_ttl: Duration,
+) -> Result<(Addrs, bool)> {
+    if ttl.is_zero() {
+        return Ok((lookup_negative(q, negative_ttl)?, false));
+    }
+
+    {
+        let mut cache = cache().lock().unwrap();
+        if let Some(entry) = cache.get_mut(q) {
+            let age = entry.at.elapsed();
+            if age < ttl {
+                return Ok((entry.addrs.clone(), false));
+            }
+            if age < ttl + stale {
+                if !entry.refreshing {
+                    entry.refreshing = true;
+                    refresh(q.clone());
+                }
+                return Ok((entry.addrs.clone(), true));
+            }
         }
     }
 
-    if v4.is_none() && v6.is_none() {
-        return Err(anyhow!("no A/AAAA addresses for {host}:{port}"));
+    let addrs = lookup_negative(q, negative_ttl)?;
+    cache().lock().unwrap().insert(
+        q.clone(),
+        CacheEntry {
+            addrs: addrs.clone(),
+            at: Instant::now(),
+            refreshing: false,
+        },
+    );
+    Ok((addrs, false))
+}
+
+fn is_bogon_v4(ip: Ipv4Addr, allow_loopback: bool) -> bool {
+    let o = ip.octets();
+    if ip.is_loopback() {
+        return !allow_loopback;
     }
-    Ok((v4, v6))
+    ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || o[0] == 0 // "this network"
+        || (o[0] == 100 && (o[1] & 0xc0) == 64) // 100.64.0.0/10 shared (CGN)
+        || (o[0] == 192 && o[1] == 0 && o[2] == 0) // 192.0.0.0/24 IETF
+        || (o[0] == 198 && (o[1] & 0xfe) == 18) // 198.18.0.0/15 benchmarking
+        || o[0] >= 240 // 240.0.0.0/4 reserved
+}
+
+/// Private, loopback, link-local or otherwise reserved (non-routable) address.
+/// IPv4-mapped IPv6 addresses are judged by their IPv4 part.
+pub fn is_bogon(ip: IpAddr, allow_loopback: bool) -> bool {
+    match ip {
+        IpAddr::V4(v4) => is_bogon_v4(v4, allow_loopback),
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_bogon_v4(v4, allow_loopback);
+            }
+            let s = v6.segments();
+            if v6.is_loopback() {
+                return !allow_loopback;
+            }
+            v6.is_unspecified()
+                || v6.is_multicast()
+                || (s[0] & 0xfe00) == 0xfc00 // fc00::/7 unique local
+                || (s[0] & 0xffc0) == 0xfe80 // fe80::/10 link-local
+                || (s[0] == 0x2001 && s[1] == 0x0db8) // 2001:db8::/32 documentation
+                || (s[0] == 0x0100 && s[1..4] == [0, 0, 0]) // 100::/64 discard
+        }
+    }
+}
+
+/// Drop bogon addresses in place; returns how many were dropped.
+fn drop_bogons((v4, v6): &mut Addrs, allow_loopback: bool) -> usize {
+    let before = v4.len() + v6.len();
+    v4.retain(|a| !is_bogon(a.ip(), allow_loopback));
+    v6.retain(|a| !is_bogon(a.ip(), allow_loopback));
+    before - v4.len() - v6.len()
 }
 
-/// Resolve per-attempt targets based on IpVersion choice.
-pub fn resolve_targets(
+/// Pick targets honoring the family choice and address caps.
+///
+/// Auto interleaves the families (v4, v6, v4, ...) so an overall cap still
+/// covers both; with the default caps this is one IPv4 then one IPv6.
+fn select(
     host: &str,
     port: u16,
+    (mut v4, mut v6): Addrs,
     family: IpVersion,
-) -> Result<Vec<(IpVersion, SocketAddr)>> {
+    per_family: usize,
+    total: usize,
+) -> Result<(Vec<(IpVersion, SocketAddr)>, usize)> {
     match family {
-        IpVersion::Auto => {
-            // “smart” mode: try both families with fallback
-            let (v4, v6) = resolve_peers_for_both(host, port)?;
-            let mut out = Vec::with_capacity(2);
-            if let Some(a) = v4 {
-                out.push((IpVersion::Ipv4, a));
-            }
-            if let Some(a) = v6 {
-                out.push((IpVersion::Ipv6, a));
+        IpVersion::Auto => {}
+        IpVersion::Ipv4 => v6.clear(),
+        IpVersion::Ipv6 => v4.clear(),
+    }
+    let available = v4.len() + v6.len();
+    if available == 0 {
+        return Err(match family {
+            IpVersion::Auto => anyhow!("no A/AAAA addresses for {host}:{port}"),
+            _ => anyhow!("no matching address for {host}:{port} ({:?})", family),
+        });
+    }
+
+    let per_family = per_family.max(1);
+    let total = if total == 0 { usize::MAX } else { total };
+    let mut v4 = v4.into_iter().take(per_family);
+    let mut v6 = v6.into_iter().take(per_family);
+    let mut out = Vec::new();
+    loop {
+        let (a, b) = (v4.next(), v6.next());
+        if a.is_none() && b.is_none() {
+            break;
+        }
+        out.extend(a.map(|a| (IpVersion::Ipv4, a)));
+        out.extend(b.map(|b| (IpVersion::Ipv6, b)));
+    }
+    out.truncate(total);
+    Ok((out, available))
+}
+
+/// Resolver consulted first for `att` (`resolver_mode`).
+fn primary_resolver(att: &ConnectionConfig) -> &'static str {
+    match att.resolver_mode {
+        ResolverMode::System => RESOLVER_SYSTEM,
+        ResolverMode::DoH { .. } => RESOLVER_DOH,
+    }
+}
+
+/// Resolver consulted last for `att`, i.e. the one a resolution failure is
+/// attributed to.
+pub fn final_resolver(att: &ConnectionConfig) -> &'static str {
+    match att.resolver_fallback {
+        ResolverFallback::None => primary_resolver(att),
+        ResolverFallback::System => RESOLVER_SYSTEM_FALLBACK,
+    }
+}
+
+/// Resolve per-attempt targets, consulting the fallback resolver if the
+/// primary fails. With `skip_private_addresses`, bogon addresses are dropped
+/// before the caps apply; a host left without addresses fails with
+/// [`BogonSkipped`] (no fallback: another resolver is no safer).
+///
+/// A host listed in `resolve_override` is not looked up at all: its pinned
+/// address is used as is (family filter applies, bogon filter does not).
+pub fn resolve_targets(host: &str, att: &ConnectionConfig) -> Result<Resolved> {
+    if let Some(&ip) = att.resolve_override.get(host) {
+        let addr = SocketAddr::new(ip, att.port);
+        let addrs = if ip.is_ipv4() {
+            (vec![addr], vec![])
+        } else {
+            (vec![], vec![addr])
+        };
+        let (targets, available) = select(host, att.port, addrs, att.ip_version, 1, 1)?;
+        return Ok(Resolved {
+            targets,
+            available,
+            resolver: RESOLVER_OVERRIDE,
+            stale: false,
+            skipped_bogon: false,
+        });
+    }
+
+    let ttl = Duration::from_secs(att.dns_cache_ttl_secs);
+    let stale_ttl = Duration::from_secs(att.dns_stale_ttl_secs);
+    let negative_ttl = Duration::from_secs(att.dns_negative_ttl_secs);
+    let resolve = |resolver, mode: &ResolverMode| {
+        let query = Query {
+            host: host.to_string(),
+            port: att.port,
+            mode: mode.clone(),
+            verify_peer: att.verify_peer,
+        };
+        let (mut addrs, stale) = cached_lookup(&query, ttl, stale_ttl, negative_ttl)?;
+        let skipped = if att.skip_private_addresses {
+            drop_bogons(&mut addrs, att.allow_loopback)
+        } else {
+            0
+        };
+        if skipped > 0 && addrs.0.is_empty() && addrs.1.is_empty() {
+            return Err(BogonSkipped {
+                host: host.to_string(),
+                skipped,
             }
-            Ok(out)
+            .into());
         }
-        IpVersion::Ipv4 | IpVersion::Ipv6 => {
-            let a = resolve_peer(host, port, family)?;
-            let fam = if a.is_ipv4() {
-                IpVersion::Ipv4
-            } else {
-                IpVersion::Ipv6
-            };
-            Ok(vec![(fam, a)])
+        if skipped > 0 {
+            debug!("[{host}] dropped {skipped} private/reserved addresses");
         }
+        select(
+            host,
+            att.port,
+            addrs,
+            att.ip_version,
+            att.max_addrs_per_family,
+            att.max_addrs_total,
+        )
+        .map(|(targets, available)| Resolved {
+            targets,
+            available,
+            resolver,
+            stale,
+            skipped_bogon: skipped > 0,
+        })
+    };
+
+    match resolve(primary_resolver(att), &att.resolver_mode) {
+        Ok(r) => Ok(r),
+        Err(e) if e.is::<BogonSkipped>() => Err(e),
+        Err(e) => match att.resolver_fallback {
+            ResolverFallback::None => Err(e),
+            ResolverFallback::System => {
+                debug!("[{host}] resolution failed ({e:#}), retrying system resolver");
+                resolve(RESOLVER_SYSTEM_FALLBACK, &ResolverMode::System)
+                    .map_err(|e2| e2.context(format!("fallback after: {e:#}")))
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doh_failures_are_final_only_for_nxdomain() {
+        let doh = ResolverMode::DoH {
+            url: "https://doh.invalid/dns-query".into(),
+        };
+        let nx = anyhow::Error::new(doh::NxDomain("gone.test".into()));
+        // The DoH server's own name not resolving, as from `doh::lookup`
+        let server = anyhow::Error::new(std::io::Error::other("Name or service not known"))
+            .context("resolving DoH server doh.invalid");
+
+        assert!(!is_transient(&doh, &nx));
+        assert!(is_transient(&doh, &server));
+        assert!(!is_transient(&ResolverMode::System, &server));
+    }
+
+    #[test]
+    fn system_failures_are_transient_only_for_eai_again() {
+        let again = anyhow::Error::new(std::io::Error::other(
+            "failed to lookup address information: Temporary failure in name resolution",
+        ));
+        assert!(is_transient(&ResolverMode::System, &again));
     }
 }
@@ -0,0 +1,56 @@
+//! Hosts to re-run after the whole list was processed (`global_retry_rounds`).
+//!
+//! Probes report each host's final outcome; a host whose last failure was
+//! transient (see [`FailureCategory::is_retryable`]) is kept for the next
+//! round, and dropped again as soon as an attempt for it succeeds.
+
+use std::collections::BTreeMap;
+use std::sync::{Mutex, OnceLock};
+
+use crate::types::FailureCategory;
+
+static PENDING: OnceLock<Mutex<BTreeMap<String, FailureCategory>>> = OnceLock::new();
+
+/// Enable tracking (no-op otherwise).
+pub fn init(enabled: bool) {
+    if enabled {
+        let _ = PENDING.set(Mutex::new(BTreeMap::new()));
+    }
+}
+
+/// Final outcome of one pass over `host`: `None` if some attempt succeeded,
+/// else the category of the last failure.
+pub fn record_outcome(host: &str, failure: Option<FailureCategory>) {
+    let Some(pending) = PENDING.get() else {
+        return;
+    };
+    let mut pending = pending.lock().unwrap();
+    match failure {
+        Some(c) if c.is_retryable() => {
+            pending.insert(host.to_string(), c);
+        }
+        _ => {
+            pending.remove(host);
+        }
+    }
+}
+
+/// Hosts to retry in the next round with the failure that put them there, in
+/// name order; clears the set.
+pub fn take_pending() -> Vec<(String, FailureCategory)> {
+    PENDING
+        .get()
+        .map(|p| {
+            std::mem::take(&mut *p.lock().unwrap())
+                .into_iter()
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Whether `host` failed again in the round just run.
+pub fn is_pending(host: &str) -> bool {
+    PENDING
+        .get()
+        .is_some_and(|p| p.lock().unwrap().contains_key(host))
+}
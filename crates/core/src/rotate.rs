@@ -1,6 +1,14 @@
 use std::fs::{self, create_dir_all, rename, File, OpenOptions};
-use std::io::{Result as IoResult, Write};
+use std::io::{BufWriter, Result as IoResult, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::types::TimeRotation;
 
 pub trait NewFileHook: Send {
     /// Called whenever a new active file is created and is empty.
@@ -9,49 +17,282 @@ pub trait NewFileHook: Send {
     }
 }
 
-/// Size-capped writer:
+/// When a [`RotatingWriter`] starts a new file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RotationPolicy {
+    /// Before the file would exceed this many bytes
+    Size(u64),
+    /// At every UTC midnight
+    Daily,
+    /// At the start of every UTC hour
+    Hourly,
+    /// Whichever comes first: `bytes`, or the next multiple of `interval`
+    /// since the Unix epoch (so a day starts at UTC midnight)
+    SizeOrTime { bytes: u64, interval: Duration },
+}
+
+impl RotationPolicy {
+    /// `bytes`, plus a new file every `time` period unless it is `None`.
+    pub fn size_or(bytes: u64, time: TimeRotation) -> Self {
+        let interval = match time {
+            TimeRotation::None => return Self::Size(bytes),
+            TimeRotation::Hourly => Duration::from_secs(3_600),
+            TimeRotation::Daily => Duration::from_secs(86_400),
+        };
+        Self::SizeOrTime { bytes, interval }
+    }
+
+    fn max_bytes(&self) -> u64 {
+        match *self {
+            Self::Size(bytes) | Self::SizeOrTime { bytes, .. } => bytes,
+            Self::Daily | Self::Hourly => u64::MAX,
+        }
+    }
+
+    /// Length of a time period in seconds, if rotating on time.
+    fn period_secs(&self) -> Option<u64> {
+        match *self {
+            Self::Size(_) => None,
+            Self::Daily => Some(86_400),
+            Self::Hourly => Some(3_600),
+            Self::SizeOrTime { interval, .. } => Some(interval.as_secs().max(1)),
+        }
+    }
+}
+
+/// Start (Unix seconds) of the period `t` falls in.
+fn period_of(period_secs: u64, t: SystemTime) -> u64 {
+    let secs = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    secs - secs % period_secs
+}
+
+/// `YYYYMMDDTHHMMSSZ` for Unix seconds `secs`, sorting chronologically.
+fn utc_stamp(secs: u64) -> String {
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let tod = secs % 86_400;
+    format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z",
+        tod / 3_600,
+        tod / 60 % 60,
+        tod % 60
+    )
+}
+
+/// Index of a rotated file named `base.N` or, with time rotation,
+/// `base.N.<stamp>`, either possibly gzipped (`.gz`); None for anything else,
+/// including a compression still in progress (`.gz.tmp`).
+fn rotated_index(name: &str, base: &str) -> Option<u64> {
+    let rest = name.strip_prefix(base)?.strip_prefix('.')?;
+    if rest.ends_with(".tmp") {
+        return None;
+    }
+    rest.split('.').next()?.parse().ok()
+}
+
+/// Background gzip jobs of rotated files, with the file each one gzips,
+/// joined by [`wait_compressions`].
+static COMPRESSIONS: Mutex<Vec<(PathBuf, JoinHandle<()>)>> = Mutex::new(Vec::new());
+
+/// `path` with `ext` appended to its file name.
+fn with_ext(path: &Path, ext: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(ext);
+    PathBuf::from(name)
+}
+
+/// Replace `path` by `path.gz`, written as `path.gz.tmp` and renamed once
+/// complete, so a `.gz` file is never partial.
+fn gzip_file(path: &Path) -> IoResult<()> {
+    let gz = with_ext(path, "gz");
+    let tmp = with_ext(path, "gz.tmp");
+    let mut input = File::open(path)?;
+    let mut enc = GzEncoder::new(BufWriter::new(File::create(&tmp)?), Compression::default());
+    std::io::copy(&mut input, &mut enc)?;
+    enc.finish()?.flush()?;
+    rename(&tmp, &gz)?;
+    fs::remove_file(path)
+}
+
+/// Gzip `path` on a background thread, then run `done` (e.g. the pruning
+/// that had to leave `path` alone while it was being compressed).
+fn compress_in_background(path: PathBuf, done: impl FnOnce() + Send + 'static) {
+    let gzipped = path.clone();
+    let job = std::thread::spawn(move || {
+        if let Err(e) = gzip_file(&gzipped) {
+            log::error!("compressing {} failed: {e}", gzipped.display());
+        }
+        done();
+    });
+    let mut jobs = COMPRESSIONS.lock().unwrap();
+    jobs.retain(|(_, j)| !j.is_finished());
+    jobs.push((path, job));
+}
+
+/// Whether a background job is still gzipping `path`.
+fn compressing(path: &Path) -> bool {
+    COMPRESSIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|(p, j)| p == path && !j.is_finished())
+}
+
+/// Block until every rotated file queued for compression is gzipped; call
+/// before exiting, or the process would leave them uncompressed.
+pub fn wait_compressions() {
+    let jobs = std::mem::take(&mut *COMPRESSIONS.lock().unwrap());
+    for (_, job) in jobs {
+        let _ = job.join();
+    }
+}
+
+/// Delete the oldest rotated files of `base` beyond `max_files`, except those
+/// still being compressed: their job prunes again once it is done. Failures
+/// are only logged, a file left behind is retried on the next pruning.
+fn prune_rotated(dir: &Path, base: &str, max_files: usize) {
+    // Oldest first; a file being compressed may briefly exist both plain
+    // and gzipped, so count indices rather than files
+    let rotated: Vec<(u64, PathBuf)> = rotated_files(dir, base)
+        .into_iter()
+        .filter_map(|p| Some((rotated_index(p.file_name()?.to_str()?, base)?, p)))
+        .collect();
+    let mut indices: Vec<u64> = rotated.iter().map(|(idx, _)| *idx).collect();
+    indices.dedup();
+    let Some(excess) = indices.len().checked_sub(max_files).filter(|&n| n > 0) else {
+        return;
+    };
+    let keep_from = indices.get(excess).copied().unwrap_or(u64::MAX);
+    let busy: Vec<u64> = rotated
+        .iter()
+        .filter(|(_, path)| compressing(path))
+        .map(|(idx, _)| *idx)
+        .collect();
+    for (_, path) in rotated
+        .iter()
+        .filter(|(idx, _)| *idx < keep_from && !busy.contains(idx))
+    {
+        match fs::remove_file(path) {
+            // Another pruning (a compression job's) got there first
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => log::warn!("pruning rotated file {} failed: {e}", path.display()),
+            Ok(()) => {}
+        }
+    }
+}
+
+/// Existing files of a rotated set, oldest first: `base.1`, `base.2`, ...,
+/// `base` (rotated names may carry a time suffix, `base.N.<stamp>`, and a
+/// `.gz` extension).
+pub fn rotated_files(dir: &Path, base: &str) -> Vec<PathBuf> {
+    let mut numbered: Vec<(u64, PathBuf)> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let idx = rotated_index(entry.file_name().to_str()?, base)?;
+            Some((idx, entry.path()))
+        })
+        .collect();
+    numbered.sort();
+    let mut files: Vec<PathBuf> = numbered.into_iter().map(|(_, p)| p).collect();
+    let active = dir.join(base);
+    if active.exists() {
+        files.push(active);
+    }
+    files
+}
+
+/// Size- and/or time-capped writer (see [`RotationPolicy`]):
 ///   base, base.1, base.2, ...
+/// With time rotation, rotated files carry the UTC start of the period they
+/// cover: base.1.20240501T000000Z, ...; with `compress_rotated`, they are
+/// gzipped in the background: base.1.gz, ... With `max_files`, only the
+/// newest that many rotated files are kept.
 pub struct RotatingWriter<H: NewFileHook> {
     dir: PathBuf,
     base: String,
     max_bytes: u64,
+    period_secs: Option<u64>,
+    compress_rotated: bool,
+    max_files: Option<usize>,
 
     file: File,
     size: u64,
     next_index: u64,
+    /// Start of the period the active file covers (time rotation only)
+    period: Option<u64>,
 
     hook: Option<H>,
 }
 
 impl<H: NewFileHook> RotatingWriter<H> {
+    /// Writer rotating on size only.
     pub fn new<P: AsRef<Path>>(
         dir: P,
         base: &str,
         max_bytes: u64,
+        hook: Option<H>,
+    ) -> IoResult<Self> {
+        Self::with_policy(dir, base, RotationPolicy::Size(max_bytes), false, hook)
+    }
+
+    /// Writer rotating per `policy`, gzipping rotated files if
+    /// `compress_rotated` (see [`wait_compressions`]).
+    pub fn with_policy<P: AsRef<Path>>(
+        dir: P,
+        base: &str,
+        policy: RotationPolicy,
+        compress_rotated: bool,
         mut hook: Option<H>,
     ) -> IoResult<Self> {
         let dir = dir.as_ref().to_path_buf();
         create_dir_all(&dir)?;
 
-        // discover next index
-        let mut max_idx = 0u64;
-        if let Ok(rd) = fs::read_dir(&dir) {
-            for entry in rd.flatten() {
-                if let Some(name) = entry.file_name().to_str() {
-                    if let Some(s) = name.strip_prefix(&(base.to_string() + ".")) {
-                        if let Ok(i) = s.parse::<u64>() {
-                            max_idx = max_idx.max(i);
-                        }
-                    }
+        // Finish what an earlier run left uncompressed (e.g. killed while
+        // compressing; its partial `.gz.tmp` is simply overwritten)
+        if compress_rotated {
+            for path in rotated_files(&dir, base) {
+                if path.extension().is_none_or(|e| e != "gz") && path != dir.join(base) {
+                    compress_in_background(path, || {});
                 }
             }
         }
+
+        // discover next index
+        let max_idx = fs::read_dir(&dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| rotated_index(entry.file_name().to_str()?, base))
+            .max()
+            .unwrap_or(0);
         let next_index = max_idx.saturating_add(1);
 
         let path = dir.join(base);
         let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
         let mut size = file.metadata().map(|m| m.len()).unwrap_or(0);
 
+        // A file left by an earlier run belongs to the period it was last
+        // written in, and is rotated on the first write of a later one
+        let period_secs = policy.period_secs();
+        let period = period_secs.map(|p| {
+            let written = match size {
+                0 => None,
+                _ => file.metadata().and_then(|m| m.modified()).ok(),
+            };
+            period_of(p, written.unwrap_or_else(SystemTime::now))
+        });
+
         // Only call hook if the file is empty (so we can write headers like qlog JSON-SEQ).
         if size == 0 {
             if let Some(h) = hook.as_mut() {
@@ -63,14 +304,50 @@ impl<H: NewFileHook> RotatingWriter<H> {
         Ok(Self {
             dir,
             base: base.into(),
-            max_bytes,
+            max_bytes: policy.max_bytes(),
+            period_secs,
+            compress_rotated,
+            max_files: None,
             file,
             size,
             next_index,
+            period,
             hook,
         })
     }
 
+    /// Keep at most `max_files` rotated files (None: all), deleting the
+    /// lowest-numbered ones on rotation. Numbering continues from the highest
+    /// remaining index, also after a restart.
+    pub fn with_max_files(mut self, max_files: Option<usize>) -> Self {
+        self.max_files = max_files;
+        self
+    }
+
+    /// Name the active file will carry once rotated (`base.N`, or
+    /// `base.N.<stamp>` with time rotation; plus `.gz` when compressed). The
+    /// newest file keeps the bare `base` name until it is rotated.
+    pub fn final_name(&self) -> String {
+        let name = self.rotated_name();
+        match self.compress_rotated {
+            true => name + ".gz",
+            false => name,
+        }
+    }
+
+    /// Name the active file is renamed to on rotation (before compression).
+    fn rotated_name(&self) -> String {
+        match self.period {
+            Some(start) => format!("{}.{}.{}", self.base, self.next_index, utc_stamp(start)),
+            None => format!("{}.{}", self.base, self.next_index),
+        }
+    }
+
+    /// All files of this set on disk (see [`rotated_files`]).
+    pub fn files(&self) -> Vec<PathBuf> {
+        rotated_files(&self.dir, &self.base)
+    }
+
     #[inline]
     fn current_path(&self) -> PathBuf {
         self.dir.join(&self.base)
@@ -82,12 +359,25 @@ impl<H: NewFileHook> RotatingWriter<H> {
         let cur = self.current_path();
 
         if cur.exists() {
-            let numbered = self.dir.join(format!("{}.{}", self.base, self.next_index));
+            let numbered = self.dir.join(self.rotated_name());
             if numbered.exists() {
                 let _ = fs::remove_file(&numbered);
             }
             rename(&cur, &numbered)?;
             self.next_index += 1;
+            if self.compress_rotated {
+                let prune = self
+                    .max_files
+                    .map(|max| (self.dir.clone(), self.base.clone(), max));
+                compress_in_background(numbered, move || {
+                    if let Some((dir, base, max_files)) = prune {
+                        prune_rotated(&dir, &base, max_files);
+                    }
+                });
+            }
+            if let Some(max_files) = self.max_files {
+                prune_rotated(&self.dir, &self.base, max_files);
+            }
         }
 
         let mut fresh = OpenOptions::new().create(true).append(true).open(&cur)?;
@@ -96,8 +386,17 @@ impl<H: NewFileHook> RotatingWriter<H> {
         }
         self.size = fresh.metadata().map(|m| m.len()).unwrap_or(0);
         self.file = fresh;
+        self.period = self.period_secs.map(|p| period_of(p, SystemTime::now()));
         Ok(())
     }
+
+    /// A new time period began since the active file was opened.
+    fn period_ended(&self) -> bool {
+        match (self.period_secs, self.period) {
+            (Some(p), Some(start)) => period_of(p, SystemTime::now()) != start,
+            _ => false,
+        }
+    }
 }
 
 impl<H: NewFileHook> Write for RotatingWriter<H> {
@@ -107,7 +406,7 @@ impl<H: NewFileHook> Write for RotatingWriter<H> {
         }
 
         // Ensure the whole chunk goes into a single file.
-        if self.size + buf.len() as u64 > self.max_bytes {
+        if self.size.saturating_add(buf.len() as u64) > self.max_bytes || self.period_ended() {
             self.rotate()?;
         }
 
@@ -123,3 +422,135 @@ impl<H: NewFileHook> Write for RotatingWriter<H> {
         self.file.flush()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Header;
+    impl NewFileHook for Header {
+        fn on_new_file(&mut self, _path: &Path, file: &mut File) -> IoResult<()> {
+            file.write_all(b"header\n")
+        }
+    }
+
+    #[test]
+    fn utc_stamps_sort_chronologically() {
+        assert_eq!(utc_stamp(0), "19700101T000000Z");
+        assert_eq!(utc_stamp(951_782_400), "20000229T000000Z");
+        assert_eq!(utc_stamp(1_714_521_600 + 3_661), "20240501T010101Z");
+    }
+
+    #[test]
+    fn time_rotation_names_files_by_period_and_keeps_hook() {
+        let dir = std::env::temp_dir().join(format!("quic-lab-rotate-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let policy = RotationPolicy::size_or(1 << 20, TimeRotation::Daily);
+        let mut w =
+            RotatingWriter::with_policy(&dir, "x.log", policy, false, Some(Header)).unwrap();
+        w.write_all(b"old\n").unwrap();
+
+        // Pretend the active file was opened on the first day of the epoch
+        w.period = Some(0);
+        assert_eq!(w.final_name(), "x.log.1.19700101T000000Z");
+        w.write_all(b"new\n").unwrap();
+        w.flush().unwrap();
+
+        let files = rotated_files(&dir, "x.log");
+        assert_eq!(
+            files,
+            [dir.join("x.log.1.19700101T000000Z"), dir.join("x.log")]
+        );
+        assert_eq!(fs::read_to_string(&files[0]).unwrap(), "header\nold\n");
+        assert_eq!(fs::read_to_string(&files[1]).unwrap(), "header\nnew\n");
+        assert_eq!(w.final_name().split('.').nth(2), Some("2"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compressed_rotations_are_listed_and_complete() {
+        use std::io::Read;
+
+        let dir = std::env::temp_dir().join(format!("quic-lab-gzip-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let policy = RotationPolicy::Size(12);
+        let mut w = RotatingWriter::with_policy(&dir, "x.log", policy, true, Some(Header)).unwrap();
+        assert_eq!(w.final_name(), "x.log.1.gz");
+        w.write_all(b"one\n").unwrap();
+        w.write_all(b"two\n").unwrap();
+        w.flush().unwrap();
+        wait_compressions();
+
+        let gz = dir.join("x.log.1.gz");
+        assert_eq!(
+            rotated_files(&dir, "x.log"),
+            [gz.clone(), dir.join("x.log")]
+        );
+        let mut text = String::new();
+        flate2::read::GzDecoder::new(File::open(&gz).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "header\none\n");
+        // A restart continues the numbering past the compressed file
+        drop(w);
+        let w = RotatingWriter::with_policy(&dir, "x.log", policy, true, Some(Header)).unwrap();
+        assert_eq!(w.final_name(), "x.log.2.gz");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn max_files_prunes_oldest_and_keeps_numbering() {
+        let dir = std::env::temp_dir().join(format!("quic-lab-prune-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let policy = RotationPolicy::Size(12);
+        let mut w = RotatingWriter::with_policy(&dir, "x.log", policy, false, Some(Header))
+            .unwrap()
+            .with_max_files(Some(2));
+        for line in [b"one\n", b"two\n", b"six\n", b"ten\n"] {
+            w.write_all(line).unwrap();
+        }
+        w.flush().unwrap();
+
+        assert_eq!(
+            rotated_files(&dir, "x.log"),
+            [dir.join("x.log.2"), dir.join("x.log.3"), dir.join("x.log")]
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("x.log.2")).unwrap(),
+            "header\ntwo\n"
+        );
+        // A restart numbers past the highest kept file
+        drop(w);
+        let w = RotatingWriter::with_policy(&dir, "x.log", policy, false, Some(Header)).unwrap();
+        assert_eq!(w.final_name(), "x.log.4");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn max_files_leaves_files_being_compressed_alone() {
+        let dir = std::env::temp_dir().join(format!("quic-lab-prune-gz-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let policy = RotationPolicy::Size(12);
+        let mut w = RotatingWriter::with_policy(&dir, "x.log", policy, true, Some(Header))
+            .unwrap()
+            .with_max_files(Some(1));
+        for line in [b"one\n", b"two\n", b"six\n", b"ten\n"] {
+            w.write_all(line).unwrap();
+        }
+        w.flush().unwrap();
+        wait_compressions();
+
+        // Only the newest rotation is left, gzipped, with no partial `.gz.tmp`
+        let mut names: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["x.log", "x.log.3.gz"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
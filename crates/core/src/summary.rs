@@ -0,0 +1,300 @@
+//! End-of-run aggregates over all hosts, written once at exit to
+//! `<out_dir>/summary.json`.
+//!
+//! Sections are omitted when nothing fed them (e.g. `servers` when no response
+//! carried a `server` header).
+
+use std::collections::{BTreeMap, HashSet};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::throttle::ThrottleStats;
+use crate::types::FailureCategory;
+
+const FILE_NAME: &str = "summary.json";
+
+/// Final outcome of every host: succeeded, or the category of its last
+/// failure. A host re-run by a retry round counts once, with its last outcome,
+/// and so does a host probed by both compare arms.
+#[derive(Default, Serialize)]
+struct HostCounts {
+    ok: u64,
+    failed: BTreeMap<FailureCategory, u64>,
+}
+
+impl HostCounts {
+    fn record(&mut self, failure: Option<FailureCategory>) {
+        match failure {
+            None => self.ok += 1,
+            Some(c) => *self.failed.entry(c).or_default() += 1,
+        }
+    }
+
+    fn retract(&mut self, failure: FailureCategory) {
+        if let Some(n) = self.failed.get_mut(&failure) {
+            *n = n.saturating_sub(1);
+        }
+    }
+}
+
+/// Hosts whose latest probe returned an error (`run.errors`, the progress
+/// line's error count). A host re-run by a retry round counts by its last
+/// run, so a round that fixes it uncounts it.
+#[derive(Default)]
+pub struct HostErrors {
+    hosts: Mutex<HashSet<String>>,
+    count: AtomicU64,
+}
+
+impl HostErrors {
+    /// Note whether `host`'s latest probe returned an error; `true` if that
+    /// changed the count.
+    pub fn record(&self, host: &str, errored: bool) -> bool {
+        let mut hosts = self.hosts.lock().unwrap();
+        let changed = if errored {
+            hosts.insert(host.to_string())
+        } else {
+            hosts.remove(host)
+        };
+        if changed {
+            self.count.store(hosts.len() as u64, Ordering::Relaxed);
+        }
+        changed
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+/// Run-level totals, from the runner once the scan (retry rounds included)
+/// is over.
+#[derive(Serialize)]
+struct RunTotals {
+    /// Domains in the input list
+    domains: u64,
+    /// Hosts completed or skipped (budget, interrupt), retry-round re-runs
+    /// included
+    processed: u64,
+    /// Hosts whose final outcome succeeded / failed (`hosts` summed up)
+    succeeded: u64,
+    failed: u64,
+    /// Hosts whose last probe returned an error (the progress line's error
+    /// count)
+    errors: u64,
+    elapsed_secs: f64,
+    /// `processed` per second
+    hosts_per_sec: f64,
+}
+
+#[derive(Serialize)]
+struct SummaryFile<'a> {
+    /// Omitted if the run ended early (watchdog abort)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    run: Option<RunTotals>,
+    hosts: &'a HostCounts,
+    /// Hosts per final HTTP status, over the hosts that succeeded
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    statuses: &'a BTreeMap<u16, u64>,
+    /// Hosts per normalized `server` response header value
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    servers: &'a BTreeMap<String, u64>,
+    /// Workers reported by the watchdog (`worker_stall_secs`)
+    stalled_workers: u64,
+    /// Hosts not probed because `max_total_connections` was used up
+    budget_skipped_hosts: u64,
+    /// Retry-round re-runs not started because `max_total_connections` was
+    /// used up; these hosts keep their earlier outcome in `hosts`
+    retry_skipped_hosts: u64,
+    /// Waits for rate-limit tokens (`requests_per_second`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    throttle: Option<ThrottleStats>,
+}
+
+struct Summary {
+    path: PathBuf,
+    strip_server_versions: bool,
+    hosts: Mutex<HostCounts>,
+    statuses: Mutex<BTreeMap<u16, u64>>,
+    servers: Mutex<BTreeMap<String, u64>>,
+    stalled_workers: AtomicU64,
+    /// `(domains, processed, errors, elapsed)` from [`record_run`]
+    run: OnceLock<(u64, u64, u64, Duration)>,
+    throttle: OnceLock<ThrottleStats>,
+}
+
+static GLOBAL: OnceLock<Summary> = OnceLock::new();
+
+/// Lowercase and trim a `server` value; with `strip_version`, keep only the
+/// product token ("nginx/1.25.3" and "Apache/2.4 (Ubuntu)" become "nginx",
+/// "apache").
+fn normalize_server(value: &str, strip_version: bool) -> String {
+    let value = value.trim();
+    let value = if strip_version {
+        value
+            .split(|c: char| c == '/' || c.is_whitespace())
+            .next()
+            .unwrap_or(value)
+    } else {
+        value
+    };
+    value.to_lowercase()
+}
+
+/// Enable aggregation; results are kept in memory until [`shutdown`].
+pub fn init(out_dir: &str, enabled: bool, strip_server_versions: bool) {
+    if !enabled {
+        return;
+    }
+    let _ = GLOBAL.set(Summary {
+        path: PathBuf::from(out_dir).join(FILE_NAME),
+        strip_server_versions,
+        hosts: Mutex::new(HostCounts::default()),
+        statuses: Mutex::new(BTreeMap::new()),
+        servers: Mutex::new(BTreeMap::new()),
+        stalled_workers: AtomicU64::new(0),
+        run: OnceLock::new(),
+        throttle: OnceLock::new(),
+    });
+}
+
+/// Count one host's final outcome (no-op when disabled).
+pub fn record_outcome(failure: Option<FailureCategory>) {
+    if let Some(s) = GLOBAL.get() {
+        s.hosts.lock().unwrap().record(failure);
+    }
+}
+
+/// Uncount a failure about to be superseded by a retry round's outcome.
+pub fn retract_failure(failure: FailureCategory) {
+    if let Some(s) = GLOBAL.get() {
+        s.hosts.lock().unwrap().retract(failure);
+    }
+}
+
+/// Count the final HTTP status of a host that succeeded (no-op when
+/// disabled). Retried hosts had failed and compare records the status of one
+/// arm, so no host is counted twice.
+pub fn record_status(status: u16) {
+    if let Some(s) = GLOBAL.get() {
+        *s.statuses.lock().unwrap().entry(status).or_default() += 1;
+    }
+}
+
+/// Count one host's `server` response header (no-op when disabled).
+pub fn record_server(value: &str) {
+    if let Some(s) = GLOBAL.get() {
+        let key = normalize_server(value, s.strip_server_versions);
+        if key.is_empty() {
+            return;
+        }
+        *s.servers.lock().unwrap().entry(key).or_default() += 1;
+    }
+}
+
+/// Count a worker the watchdog found stalled (no-op when disabled).
+pub fn record_stalled_worker() {
+    if let Some(s) = GLOBAL.get() {
+        s.stalled_workers.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Record the run totals once the scan is over (no-op when disabled):
+/// `domains` in the input, hosts `processed` (retry rounds included), hosts
+/// whose probe returned an error and the scan's wall time.
+pub fn record_run(domains: u64, processed: u64, errors: u64, elapsed: Duration) {
+    if let Some(s) = GLOBAL.get() {
+        let _ = s.run.set((domains, processed, errors, elapsed));
+    }
+}
+
+/// Record the rate limiter's wait totals at the end of the scan (no-op when
+/// disabled).
+pub fn record_throttle(stats: ThrottleStats) {
+    if let Some(s) = GLOBAL.get() {
+        let _ = s.throttle.set(stats);
+    }
+}
+
+/// Path of `summary.json`, if enabled.
+pub fn path() -> Option<PathBuf> {
+    GLOBAL.get().map(|s| s.path.clone())
+}
+
+/// Write `summary.json`, if enabled.
+pub fn shutdown() -> Result<()> {
+    let Some(s) = GLOBAL.get() else {
+        return Ok(());
+    };
+    let hosts = s.hosts.lock().unwrap();
+    let statuses = s.statuses.lock().unwrap();
+    let servers = s.servers.lock().unwrap();
+
+    let run = s
+        .run
+        .get()
+        .map(|&(domains, processed, errors, elapsed)| RunTotals {
+            domains,
+            processed,
+            succeeded: hosts.ok,
+            failed: hosts.failed.values().sum(),
+            errors,
+            elapsed_secs: elapsed.as_secs_f64(),
+            hosts_per_sec: if elapsed.is_zero() {
+                0.0
+            } else {
+                processed as f64 / elapsed.as_secs_f64()
+            },
+        });
+    let mut w = BufWriter::new(std::fs::File::create(&s.path)?);
+    let file = SummaryFile {
+        run,
+        hosts: &hosts,
+        statuses: &statuses,
+        servers: &servers,
+        stalled_workers: s.stalled_workers.load(Ordering::Relaxed),
+        budget_skipped_hosts: crate::budget::skipped(),
+        retry_skipped_hosts: crate::budget::retry_skipped(),
+        throttle: s.throttle.get().copied(),
+    };
+    serde_json::to_writer_pretty(&mut w, &file)?;
+    w.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_fixed_by_a_retry_round_counts_once() {
+        let mut counts = HostCounts::default();
+        let errors = HostErrors::default();
+        let failed = |c: &HostCounts| c.failed.values().sum::<u64>();
+
+        // First pass: "a" fails transiently, "b" for good, both with an error
+        counts.record(Some(FailureCategory::NoResponse));
+        assert!(errors.record("a", true));
+        counts.record(Some(FailureCategory::HandshakeFailed));
+        assert!(errors.record("b", true));
+
+        // Retry round, as the runner does it: the re-run records its outcome,
+        // then the failure that queued the host is retracted
+        counts.record(None);
+        counts.retract(FailureCategory::NoResponse);
+        assert!(errors.record("a", false));
+        assert_eq!((counts.ok, failed(&counts), errors.count()), (1, 1, 1));
+
+        // A host failing again stays counted once
+        counts.record(Some(FailureCategory::HandshakeFailed));
+        counts.retract(FailureCategory::HandshakeFailed);
+        assert!(!errors.record("b", true));
+        assert_eq!((counts.ok, failed(&counts), errors.count()), (1, 1, 1));
+    }
+}
@@ -1,18 +1,68 @@
+use governor::clock::Clock;
 use governor::{DefaultDirectRateLimiter, Quota};
 use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+
+use crate::types::DelayDistribution;
+
+/// Longest sleep between token checks, so a cancellation is noticed promptly.
+const WAIT_SLICE: Duration = Duration::from_millis(50);
+
+/// Why [`RateLimit::until_ready_timeout`] returned without a token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotReady {
+    TimedOut,
+    Cancelled,
+}
+
+impl std::fmt::Display for NotReady {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NotReady::TimedOut => f.write_str("throttle: timed out waiting for a token"),
+            NotReady::Cancelled => f.write_str("throttle: cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for NotReady {}
+
+/// Whether a probe gave up on a cancelled wait ([`NotReady::Cancelled`]): the
+/// scan is stopping, so the host counts as not probed rather than failed.
+pub fn is_cancelled(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<NotReady>() == Some(&NotReady::Cancelled)
+}
+
+/// Time workers spent waiting for tokens, from [`RateLimit::stats`]: calls
+/// that had to wait at all, and their total wait.
+#[derive(Debug, Clone, Copy, Default, serde::Serialize)]
+pub struct ThrottleStats {
+    pub blocked_count: u64,
+    pub blocked_ns: u64,
+}
 
 /// Simple wrapper around governor's direct limiter.
-/// `None` means throttling is disabled.
+/// `None` means throttling is disabled. Clones share the limiter, the
+/// cancellation flag and the wait counters.
 #[derive(Clone)]
 pub struct RateLimit {
     inner: Option<Arc<DefaultDirectRateLimiter>>,
+    cancelled: Arc<AtomicBool>,
+    /// `(blocked_count, blocked_ns)`, see [`ThrottleStats`]
+    blocked: Arc<(AtomicU64, AtomicU64)>,
 }
 
 impl RateLimit {
     /// Disabled limiter (no throttling).
     pub fn disabled() -> Self {
-        Self { inner: None }
+        Self {
+            inner: None,
+            cancelled: Arc::default(),
+            blocked: Arc::default(),
+        }
     }
 
     /// Global, process-wide RPS limiter with a short burst.
@@ -30,13 +80,114 @@ impl RateLimit {
 
         Self {
             inner: Some(Arc::new(lim)),
+            cancelled: Arc::default(),
+            blocked: Arc::default(),
         }
     }
 
-    /// Block until a token is available (before each network attempt).
+    /// Block until a token is available (before each network attempt), or
+    /// the limiter is cancelled.
     pub fn until_ready(&self) {
-        if let Some(lim) = &self.inner {
-            let _ = lim.until_ready();
+        let _ = self.until_ready_timeout(Duration::MAX);
+    }
+
+    /// Block until a token is available, for at most `timeout`. Fails at once
+    /// (even unthrottled) once [`cancel`](Self::cancel) was called, so
+    /// throttled workers stop on shutdown.
+    pub fn until_ready_timeout(&self, timeout: Duration) -> Result<(), NotReady> {
+        let start = Instant::now();
+        let mut waited = false;
+        let res = loop {
+            if self.is_cancelled() {
+                break Err(NotReady::Cancelled);
+            }
+            let Some(lim) = &self.inner else {
+                break Ok(());
+            };
+            let wait = match lim.check() {
+                Ok(()) => break Ok(()),
+                Err(not_until) => not_until.wait_time_from(lim.clock().now()),
+            };
+            let left = timeout.saturating_sub(start.elapsed());
+            if left.is_zero() {
+                break Err(NotReady::TimedOut);
+            }
+            waited = true;
+            std::thread::sleep(wait.min(left).min(WAIT_SLICE));
+        };
+        if waited {
+            let (count, ns) = &*self.blocked;
+            count.fetch_add(1, Ordering::Relaxed);
+            ns.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        }
+        res
+    }
+
+    /// Waits for tokens so far, over all clones.
+    pub fn stats(&self) -> ThrottleStats {
+        let (count, ns) = &*self.blocked;
+        ThrottleStats {
+            blocked_count: count.load(Ordering::Relaxed),
+            blocked_ns: ns.load(Ordering::Relaxed),
         }
     }
+
+    /// Make every current and future wait fail with [`NotReady::Cancelled`].
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// Overall time budget for one host (`host_deadline_ms`), across resolution,
+/// all connection configs and addresses. Checked before each of them; a
+/// connection already running is not cut short.
+#[derive(Debug, Clone, Copy)]
+pub struct HostDeadline {
+    ms: u64,
+    at: Option<Instant>,
+}
+
+impl HostDeadline {
+    /// Start the clock now; `ms == 0` means no deadline.
+    pub fn start(ms: u64) -> Self {
+        Self {
+            ms,
+            at: (ms > 0).then(|| Instant::now() + Duration::from_millis(ms)),
+        }
+    }
+
+    pub fn expired(&self) -> bool {
+        self.at.is_some_and(|at| Instant::now() >= at)
+    }
+
+    /// `d`, shortened to the time left.
+    pub fn clamp(&self, d: Duration) -> Duration {
+        match self.at {
+            Some(at) => d.min(at.saturating_duration_since(Instant::now())),
+            None => d,
+        }
+    }
+
+    pub fn error(&self) -> anyhow::Error {
+        anyhow!("host_deadline: {} ms exceeded", self.ms)
+    }
+}
+
+/// Sample one inter-attempt delay with mean `mean_ms`.
+///
+/// Randomised shapes avoid lockstep retries across workers; `Poisson` gives
+/// exponential gaps, i.e. Poisson arrivals in aggregate.
+pub fn sample_delay(dist: DelayDistribution, mean_ms: u64) -> Duration {
+    let mean = mean_ms as f64;
+    let ms = match dist {
+        DelayDistribution::Fixed => mean,
+        DelayDistribution::Uniform => rand::random::<f64>() * 2.0 * mean,
+        // Inverse CDF; 1 - u is in (0, 1] so ln() stays finite
+        DelayDistribution::Poisson => -mean * (1.0 - rand::random::<f64>()).ln(),
+    };
+    Duration::from_secs_f64(ms / 1000.0)
 }
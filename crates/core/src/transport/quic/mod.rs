@@ -12,24 +12,34 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::io::ErrorKind;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
 use std::net::SocketAddr;
+use std::time::Duration;
+use std::time::Instant;
 
 use log::debug;
 use mio::net::UdpSocket;
 use mio::Interest;
 use mio::Registry;
 use mio::Token;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use rustc_hash::FxHashMap;
 use slab::Slab;
 
 use tquic::PacketInfo;
 use tquic::PacketSendHandler;
 
-pub use quic::{run_probe, AppProtocol};
+use crate::types::EgressImpairment;
+
+pub(crate) use quic::open_connection;
+pub use quic::{run_probe, AppProtocol, CloseLinger};
 pub mod quic;
 
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync + 'static>>;
@@ -44,6 +54,33 @@ pub struct QuicSocket {
 
     /// Local address of the initial socket.
     local_addr: SocketAddr,
+
+    /// UDP payload bytes handed to the kernel so far, and the largest
+    /// single datagram among them.
+    sent_bytes: Cell<u64>,
+    max_datagram: Cell<usize>,
+
+    /// Sends refused with `WouldBlock` (kernel send buffer full).
+    would_block: Cell<u64>,
+
+    /// Bytes of the first batch of datagrams tquic asked us to send (the
+    /// client Initial flight, padding included).
+    initial_flight: Cell<Option<u64>>,
+
+    /// Egress fault injection; datagrams wait in `delayed` until due.
+    impairment: Option<EgressImpairment>,
+    delayed: RefCell<VecDeque<Delayed>>,
+    impaired_count: Cell<u64>,
+    loss_rng: RefCell<StdRng>,
+    dropped: Cell<u64>,
+}
+
+/// An outgoing datagram held back by the egress impairment.
+struct Delayed {
+    due: Instant,
+    buf: Vec<u8>,
+    src: SocketAddr,
+    dst: SocketAddr,
 }
 
 impl QuicSocket {
@@ -63,9 +100,32 @@ impl QuicSocket {
             socks,
             addrs,
             local_addr,
+            sent_bytes: Cell::new(0),
+            max_datagram: Cell::new(0),
+            would_block: Cell::new(0),
+            initial_flight: Cell::new(None),
+            impairment: None,
+            delayed: RefCell::new(VecDeque::new()),
+            impaired_count: Cell::new(0),
+            loss_rng: RefCell::new(StdRng::seed_from_u64(0)),
+            dropped: Cell::new(0),
         })
     }
 
+    /// Delay/reorder/drop outgoing datagrams (testing aid; None = send at once).
+    pub fn with_impairment(mut self, impairment: Option<EgressImpairment>) -> Self {
+        if let Some(imp) = &impairment {
+            self.loss_rng = RefCell::new(StdRng::seed_from_u64(imp.loss_seed));
+        }
+        self.impairment = impairment;
+        self
+    }
+
+    /// Outgoing datagrams dropped by the impairment so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.get()
+    }
+
     pub fn new_client_socket(is_ipv4: bool, registry: &Registry) -> Result<Self> {
         let local = match is_ipv4 {
             true => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
@@ -79,6 +139,93 @@ impl QuicSocket {
         self.local_addr
     }
 
+    /// Total UDP payload bytes sent on this socket.
+    pub fn sent_bytes(&self) -> u64 {
+        self.sent_bytes.get()
+    }
+
+    /// Largest UDP payload sent in one datagram (None until anything was
+    /// sent), PMTU probes included.
+    pub fn max_datagram(&self) -> Option<u16> {
+        match self.max_datagram.get() {
+            0 => None,
+            n => Some(n.min(u16::MAX as usize) as u16),
+        }
+    }
+
+    /// Sends the kernel refused with `WouldBlock` so far: local send-side
+    /// backpressure, as opposed to loss on the path.
+    pub fn send_would_block_count(&self) -> u64 {
+        self.would_block.get()
+    }
+
+    fn count_would_block(&self) {
+        debug!("socket send would block");
+        self.would_block.set(self.would_block.get() + 1);
+    }
+
+    fn count_sent(&self, len: usize) {
+        self.sent_bytes.set(self.sent_bytes.get() + len as u64);
+        self.max_datagram.set(self.max_datagram.get().max(len));
+    }
+
+    /// Size of the first flight (None until anything was sent).
+    pub fn initial_flight_bytes(&self) -> Option<u64> {
+        self.initial_flight.get()
+    }
+
+    /// Time until the next held-back datagram is due, if any.
+    pub fn impairment_timeout(&self) -> Option<Duration> {
+        self.delayed
+            .borrow()
+            .front()
+            .map(|d| d.due.saturating_duration_since(Instant::now()))
+    }
+
+    /// Send held-back datagrams that are due (all of them if `all`).
+    pub fn flush_impaired(&self, all: bool) {
+        let now = Instant::now();
+        let mut delayed = self.delayed.borrow_mut();
+        while let Some(d) = delayed.front() {
+            if !all && d.due > now {
+                break;
+            }
+            match self.send_to(&d.buf, d.src, d.dst) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    self.count_would_block();
+                    break;
+                }
+                // Treated as loss; recovery will resend
+                Err(e) => debug!("impaired send_to failed: {:?}", e),
+                Ok(_) => self.count_sent(d.buf.len()),
+            }
+            delayed.pop_front();
+        }
+    }
+
+    /// Queue a datagram according to the impairment, ordered by due time.
+    fn hold(&self, imp: &EgressImpairment, pkt: &[u8], info: &PacketInfo) {
+        let n = self.impaired_count.get() + 1;
+        self.impaired_count.set(n);
+        let mut delay = imp.delay_ms;
+        if imp.reorder_every > 0 && n.is_multiple_of(imp.reorder_every) {
+            delay += imp.reorder_ms;
+        }
+        let due = Instant::now() + Duration::from_millis(delay);
+
+        let mut delayed = self.delayed.borrow_mut();
+        let at = delayed.partition_point(|d| d.due <= due);
+        delayed.insert(
+            at,
+            Delayed {
+                due,
+                buf: pkt.to_vec(),
+                src: info.src,
+                dst: info.dst,
+            },
+        );
+    }
+
     /// Receive data from the socket.
     pub fn recv_from(
         &self,
@@ -119,11 +266,30 @@ impl QuicSocket {
 
 impl PacketSendHandler for QuicSocket {
     fn on_packets_send(&self, pkts: &[(Vec<u8>, PacketInfo)]) -> tquic::Result<usize> {
+        // Counted as emitted, before any impairment
+        if self.initial_flight.get().is_none() && !pkts.is_empty() {
+            let bytes = pkts.iter().map(|(pkt, _)| pkt.len() as u64).sum();
+            self.initial_flight.set(Some(bytes));
+        }
+
+        if let Some(imp) = &self.impairment {
+            for (pkt, info) in pkts {
+                if imp.loss_rate > 0.0 && self.loss_rng.borrow_mut().random_bool(imp.loss_rate) {
+                    // Reported as sent; the peer never sees it
+                    self.dropped.set(self.dropped.get() + 1);
+                    continue;
+                }
+                self.hold(imp, pkt, info);
+            }
+            self.flush_impaired(false);
+            return Ok(pkts.len());
+        }
+
         let mut count = 0;
         for (pkt, info) in pkts {
             if let Err(e) = self.send_to(pkt, info.src, info.dst) {
                 if e.kind() == ErrorKind::WouldBlock {
-                    debug!("socket send would block");
+                    self.count_would_block();
                     return Ok(count);
                 }
                 return Err(tquic::Error::InvalidOperation(format!(
@@ -132,6 +298,7 @@ impl PacketSendHandler for QuicSocket {
                 )));
             }
             debug!("written {} bytes", pkt.len());
+            self.count_sent(pkt.len());
             count += 1;
         }
         Ok(count)
@@ -17,7 +17,7 @@ use std::fs;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::rc::Rc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use log::debug;
 use log::error;
@@ -25,6 +25,7 @@ use mio::event::Event;
 use serde_json::json;
 use tquic::Config;
 use tquic::Connection;
+use tquic::ConnectionId;
 use tquic::Endpoint;
 use tquic::PacketInfo;
 use tquic::TlsConfig;
@@ -34,21 +35,84 @@ use crate::config::{ConnectionConfig, GeneralConfig, IOConfig};
 use crate::recorder::Recorder;
 use crate::transport::quic::QuicSocket;
 use crate::transport::quic::Result;
-use crate::types::{BasicStats, MetaRecord};
-use crate::{qlog, shard2};
+use crate::types::{BasicStats, EgressImpairment, MetaRecord};
+use crate::{budget, errors, progress, qlog, shard2};
 
 /// Application protocol hook that runs on top of QUIC.
 /// Implementations may drive HTTP/3 or anything else.
 pub trait AppProtocol {
     fn on_connected(&mut self, _conn: &mut Connection) {}
+    /// A resumed handshake can carry 0-RTT data (`enable_early_data` and a
+    /// stored session): start sending now, before `on_connected`. Streams
+    /// lost to a rejection are resent as 1-RTT. Default: wait for the handshake.
+    fn on_early_data(&mut self, _conn: &mut Connection) {}
     fn on_stream_readable(&mut self, _conn: &mut Connection, _stream_id: u64) {}
     fn on_stream_writable(&mut self, _conn: &mut Connection, _stream_id: u64) {}
     fn on_stream_closed(&mut self, _conn: &mut Connection, _stream_id: u64) {}
     fn on_conn_closed(&mut self, _conn: &mut Connection) {}
+
+    /// Time until the app wants `on_timeout` (None = no app timer). Lets an
+    /// app act without network events, e.g. send a request every N ms.
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+    fn on_timeout(&mut self, _conn: &mut Connection) {}
 }
 
 impl dyn AppProtocol {}
 
+/// Deferred graceful close for apps: keeps the connection open for
+/// `post_finish_ms` + `close_linger_ms` after the app is done, so late peer
+/// frames (trailers, GOAWAY) can still be read and the final ACKs make it
+/// into the qlog. Wire `timeout`/`on_timeout` into the app's `AppProtocol`
+/// timer methods.
+pub struct CloseLinger {
+    linger: Duration,
+    done: bool,
+    close_at: Option<Instant>,
+}
+
+impl CloseLinger {
+    pub fn new(cfg: &ConnectionConfig) -> Self {
+        Self {
+            linger: Duration::from_millis(cfg.post_finish_ms.saturating_add(cfg.close_linger_ms)),
+            done: false,
+            close_at: None,
+        }
+    }
+
+    /// Close with NO_ERROR now, or once the linger has elapsed.
+    pub fn close(&mut self, conn: &mut Connection) {
+        self.done = true;
+        if self.linger.is_zero() {
+            let _ = conn.close(true, 0x00, b"ok");
+        } else if self.close_at.is_none() {
+            self.close_at = Some(Instant::now() + self.linger);
+        }
+    }
+
+    /// The app is done ([`close`](Self::close) was called): whatever the
+    /// peer sends from now on arrived after the last response.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.close_at
+            .map(|t| t.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn on_timeout(&mut self, conn: &mut Connection) {
+        if self.close_at.is_some_and(|t| t <= Instant::now()) {
+            self.close_at = None;
+            let _ = conn.close(true, 0x00, b"ok");
+        }
+    }
+}
+
+/// The app is reached both from transport callbacks and from the event loop (timers).
+type SharedApp = Rc<RefCell<Box<dyn AppProtocol>>>;
+
 // A simple http client over QUIC.
 struct Client {
     /// QUIC endpoint.
@@ -63,11 +127,65 @@ struct Client {
     /// Client context.
     context: Rc<RefCell<ClientContext>>,
 
+    /// Application protocol, for app timers.
+    app: SharedApp,
+
     /// Packet read buffer.
     recv_buf: Vec<u8>,
+
+    /// Poll timeout bounds (`min_poll_interval_ms` / `max_poll_interval_ms`).
+    min_poll: Option<Duration>,
+    max_poll: Option<Duration>,
+}
+
+/// Transport and TLS settings of one attempt to `peer`, from its
+/// `ConnectionConfig` (the ALPN list may depend on the peer's family).
+///
+/// Each attempt builds its own `Endpoint` from this, so per-attempt transport
+/// parameters already apply and the `Config` override of `Endpoint::connect`
+/// stays unused. It only becomes useful once an endpoint is shared across
+/// attempts; this function is what such an override would be built from.
+fn transport_config(connection_config: &ConnectionConfig, peer: &SocketAddr) -> Result<Config> {
+    let mut config = Config::new()?;
+    config.set_max_idle_timeout(connection_config.max_idle_timeout_ms);
+    config.set_initial_max_data(connection_config.initial_max_data);
+    config.set_initial_max_stream_data_bidi_local(
+        connection_config.initial_max_stream_data_bidi_local,
+    );
+    config.set_initial_max_stream_data_bidi_remote(
+        connection_config.initial_max_stream_data_bidi_remote,
+    );
+    config.set_initial_max_stream_data_uni(connection_config.initial_max_stream_data_uni);
+    config.set_initial_max_streams_bidi(connection_config.initial_max_streams_bidi);
+    config.set_initial_max_streams_uni(connection_config.initial_max_streams_uni);
+    config.set_max_ack_delay(connection_config.max_ack_delay);
+    config.set_active_connection_id_limit(connection_config.active_connection_id_limit);
+    config.set_send_udp_payload_size(connection_config.send_udp_payload_size);
+
+    config.enable_multipath(connection_config.enable_multipath);
+    config.set_multipath_algorithm(connection_config.multipath_algorithm.parse().unwrap());
+    config.set_congestion_control_algorithm(
+        connection_config
+            .cc_algorithm
+            .parse()
+            .map_err(|_| format!("unknown cc_algorithm {:?}", connection_config.cc_algorithm))?,
+    );
+
+    // TLS + ALPN
+    let alpn_wire: Vec<Vec<u8>> = connection_config
+        .alpn_for(peer)
+        .iter()
+        .map(|s| s.as_bytes().to_vec())
+        .collect();
+    let mut tls_config =
+        TlsConfig::new_client_config(alpn_wire, connection_config.enable_early_data)?;
+    tls_config.set_verify(connection_config.verify_peer);
+    config.set_tls_config(tls_config);
+    Ok(config)
 }
 
 impl Client {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         host: &str,
         socket_addr: &SocketAddr,
@@ -75,61 +193,53 @@ impl Client {
         general_config: &GeneralConfig,
         connection_config: &ConnectionConfig,
         recorder: &Recorder,
+        traced: bool,
         app: Box<dyn AppProtocol>,
     ) -> Result<Self> {
-        let mut config = Config::new()?;
-        config.set_max_idle_timeout(connection_config.max_idle_timeout_ms);
-        config.set_initial_max_data(connection_config.initial_max_data);
-        config.set_initial_max_stream_data_bidi_local(
-            connection_config.initial_max_stream_data_bidi_local,
-        );
-        config.set_initial_max_stream_data_bidi_remote(
-            connection_config.initial_max_stream_data_bidi_remote,
+        let config = transport_config(connection_config, socket_addr)?;
+
+        let context = Rc::new(RefCell::new(ClientContext {
+            finish: false,
+            handshake_bytes: HandshakeBytes::default(),
+            first_datagram_packets: None,
+            early_data_pending: false,
+            early_data_sent: false,
+            loop_start: None,
+            poll_wait: Duration::ZERO,
+        }));
+        let app: SharedApp = Rc::new(RefCell::new(app));
+
+        let poll = mio::Poll::new()?;
+        let registry = poll.registry();
+        let sock = Rc::new(
+            QuicSocket::new_client_socket(socket_addr.is_ipv4(), registry)?
+                .with_impairment(EgressImpairment::from_config(connection_config)),
         );
-        config.set_initial_max_stream_data_uni(connection_config.initial_max_stream_data_uni);
-        config.set_initial_max_streams_bidi(connection_config.initial_max_streams_bidi);
-        config.set_initial_max_streams_uni(connection_config.initial_max_streams_uni);
-        config.set_max_ack_delay(connection_config.max_ack_delay);
-        config.set_active_connection_id_limit(connection_config.active_connection_id_limit);
-        config.set_send_udp_payload_size(connection_config.send_udp_payload_size);
-
-        config.enable_multipath(connection_config.enable_multipath);
-        config.set_multipath_algorithm(connection_config.multipath_algorithm.parse().unwrap());
-
-        // TLS + ALPN
-        let alpn_wire: Vec<Vec<u8>> = connection_config
-            .alpn
-            .iter()
-            .map(|s| s.as_bytes().to_vec())
-            .collect();
-        let mut tls_config = TlsConfig::new_client_config(alpn_wire, false)?;
-        tls_config.set_verify(connection_config.verify_peer);
-        config.set_tls_config(tls_config);
 
-        let context = Rc::new(RefCell::new(ClientContext { finish: false }));
         let handlers = ClientHandler::new(
             host,
             socket_addr,
             io_config,
             general_config,
+            connection_config,
             recorder,
+            traced,
             context.clone(),
-            app,
+            app.clone(),
+            sock.clone(),
         );
 
-        let poll = mio::Poll::new()?;
-        let registry = poll.registry();
-        let sock = Rc::new(QuicSocket::new_client_socket(
-            socket_addr.is_ipv4(),
-            registry,
-        )?);
-
         Ok(Client {
             endpoint: Endpoint::new(Box::new(config), false, Box::new(handlers), sock.clone()),
             poll,
             sock,
             context,
+            app,
             recv_buf: vec![0u8; connection_config.max_receive_buffer_size],
+            min_poll: (connection_config.min_poll_interval_ms > 0)
+                .then(|| Duration::from_millis(connection_config.min_poll_interval_ms)),
+            max_poll: (connection_config.max_poll_interval_ms > 0)
+                .then(|| Duration::from_millis(connection_config.max_poll_interval_ms)),
         })
     }
 
@@ -138,6 +248,51 @@ impl Client {
         context.finish()
     }
 
+    /// Hand the connection to the app for 0-RTT once TLS has early-data keys
+    /// (after the first flight). Returns whether the app was started.
+    fn start_early_data(&mut self, conn_index: u64) -> bool {
+        if !self.context.borrow().early_data_pending {
+            return false;
+        }
+        let Some(conn) = self.endpoint.conn_get_mut(conn_index) else {
+            return false;
+        };
+        if conn.is_established() || conn.is_closed() {
+            // Too late for 0-RTT; `on_connected` takes over
+            self.context.borrow_mut().early_data_pending = false;
+            return false;
+        }
+        if !conn.is_in_early_data() {
+            return false;
+        }
+        let mut context = self.context.borrow_mut();
+        context.early_data_pending = false;
+        context.early_data_sent = true;
+        drop(context);
+        self.app.borrow_mut().on_early_data(conn);
+        true
+    }
+
+    /// Earliest of the transport and app timers, clamped to the configured bounds.
+    fn poll_timeout(&self) -> Option<Duration> {
+        let timeout = [
+            self.endpoint.timeout(),
+            self.app.borrow().timeout(),
+            self.sock.impairment_timeout(),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let timeout = match (timeout, self.max_poll) {
+            (Some(t), Some(max)) => Some(t.min(max)),
+            (t, max) => t.or(max),
+        };
+        match (timeout, self.min_poll) {
+            (Some(t), Some(min)) => Some(t.max(min)),
+            (t, _) => t,
+        }
+    }
+
     fn process_read_event(&mut self, event: &Event) -> Result<()> {
         loop {
             if self.context.borrow().finish() {
@@ -156,6 +311,16 @@ impl Client {
                 }
             };
             debug!("socket recv recv {} bytes from {:?}", len, remote);
+            {
+                let mut context = self.context.borrow_mut();
+                context
+                    .handshake_bytes
+                    .on_recv(len as u64, self.sock.sent_bytes());
+                if context.first_datagram_packets.is_none() {
+                    context.first_datagram_packets =
+                        Some(coalesced_packet_count(&self.recv_buf[..len]));
+                }
+            }
 
             let pkt_buf = &mut self.recv_buf[..len];
             let pkt_info = PacketInfo {
@@ -177,6 +342,108 @@ impl Client {
 
 struct ClientContext {
     finish: bool,
+    handshake_bytes: HandshakeBytes,
+    /// QUIC packets coalesced into the first datagram received
+    first_datagram_packets: Option<u32>,
+    /// A session was applied with early data on; the app has not been told
+    early_data_pending: bool,
+    /// The app was started in 0-RTT (`AppProtocol::on_early_data`)
+    early_data_sent: bool,
+    /// Event loop start, and the time since spent blocked in `poll`
+    loop_start: Option<Instant>,
+    poll_wait: Duration,
+}
+
+/// Datagram bytes exchanged until the handshake completes.
+///
+/// Until it has validated our address the server may send at most 3x what it
+/// received (RFC 9000, Section 8.1). If the server's bytes ever reach that
+/// budget, it had to wait for more client bytes: the handshake was
+/// amplification-limited (e.g. a certificate chain too large for the budget).
+#[derive(Default)]
+struct HandshakeBytes {
+    sent: u64,
+    recv: u64,
+    amplification_limited: bool,
+    done: bool,
+}
+
+impl HandshakeBytes {
+    /// Count a received datagram; `sent` is our total so far.
+    fn on_recv(&mut self, len: u64, sent: u64) {
+        if self.done {
+            return;
+        }
+        self.sent = sent;
+        self.recv += len;
+        // 5% slack for datagrams the server counted that we lost, or vice versa
+        if sent > 0 && self.recv * 100 >= sent * 3 * 95 {
+            self.amplification_limited = true;
+        }
+    }
+}
+
+const QUIC_V2: u32 = 0x6b33_43cf;
+
+/// Decode a QUIC variable-length integer (RFC 9000, Section 16) at `*pos`.
+fn read_varint(buf: &[u8], pos: &mut usize) -> Option<u64> {
+    let len = 1usize << (buf.get(*pos)? >> 6);
+    let bytes = buf.get(*pos..*pos + len)?;
+    *pos += len;
+    Some(
+        bytes[1..]
+            .iter()
+            .fold(u64::from(bytes[0] & 0x3f), |v, &b| (v << 8) | u64::from(b)),
+    )
+}
+
+/// Size of the long-header packet at the start of `buf`, or `None` if it has
+/// no Length field (Retry, Version Negotiation) or is truncated.
+fn long_header_packet_len(buf: &[u8]) -> Option<usize> {
+    let version = u32::from_be_bytes(buf.get(1..5)?.try_into().ok()?);
+    if version == 0 {
+        return None;
+    }
+    let mut pos = 5;
+    // DCID, SCID
+    for _ in 0..2 {
+        pos += 1 + usize::from(*buf.get(pos)?);
+    }
+    // Type bits differ between v1 and v2 (RFC 9369, Section 3.2)
+    let (initial, retry) = if version == QUIC_V2 { (1, 0) } else { (0, 3) };
+    let ty = (buf[0] >> 4) & 0x03;
+    if ty == retry {
+        return None;
+    }
+    if ty == initial {
+        let token_len = read_varint(buf, &mut pos)?;
+        pos = pos.checked_add(usize::try_from(token_len).ok()?)?;
+    }
+    let len = read_varint(buf, &mut pos)?;
+    let end = pos.checked_add(usize::try_from(len).ok()?)?;
+    (end <= buf.len()).then_some(end)
+}
+
+/// Number of QUIC packets coalesced into one datagram (RFC 9000, Section
+/// 12.2), an implementation fingerprint. A short-header packet, Retry or
+/// Version Negotiation runs to the end of the datagram; trailing bytes
+/// without the fixed bit (padding) are not counted.
+fn coalesced_packet_count(mut buf: &[u8]) -> u32 {
+    let mut n = 0;
+    while let Some(&first) = buf.first() {
+        if first & 0x40 == 0 {
+            break;
+        }
+        n += 1;
+        if first & 0x80 == 0 {
+            break;
+        }
+        let Some(len) = long_header_packet_len(buf) else {
+            break;
+        };
+        buf = &buf[len..];
+    }
+    n
 }
 
 impl ClientContext {
@@ -192,21 +459,44 @@ impl ClientContext {
 struct ClientHandler {
     host: String,
     peer_addr: SocketAddr,
+    /// ALPN list offered to this peer (see `ConnectionConfig::alpn_for`)
+    alpn_offered: Vec<String>,
     session_root: PathBuf,
+    enable_early_data: bool,
+    /// Congestion control algorithm as configured, lowercased
+    cc_algorithm: String,
+    /// Set once a stored session was applied (offered for resumption).
+    session_offered: bool,
+    /// Set once a stored session was applied while early data is enabled.
+    early_data_attempted: bool,
+    /// Server-chosen CID (our DCID) when the handshake completed.
+    server_cid: Option<ConnectionId>,
+    egress_impairment: Option<EgressImpairment>,
+    /// When tquic created the connection, and how long the handshake took
+    t_created: Option<Instant>,
+    handshake_time: Option<Duration>,
+    /// Feed the process-wide qlog/keylog sinks; off for connections the tool
+    /// makes for itself (DoH lookups)
+    traced: bool,
     recorder: Recorder,
     context: Rc<RefCell<ClientContext>>,
-    app: Box<dyn AppProtocol>,
+    app: SharedApp,
+    sock: Rc<QuicSocket>,
 }
 
 impl ClientHandler {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         host: &str,
         peer_addr: &SocketAddr,
         io_config: &IOConfig,
         general_config: &GeneralConfig,
+        connection_config: &ConnectionConfig,
         recorder: &Recorder,
+        traced: bool,
         context: Rc<RefCell<ClientContext>>,
-        app: Box<dyn AppProtocol>,
+        app: SharedApp,
+        sock: Rc<QuicSocket>,
     ) -> Self {
         let base = PathBuf::from(&io_config.out_dir);
         let session_root = if general_config.save_session_files {
@@ -233,31 +523,48 @@ impl ClientHandler {
         Self {
             host: host.to_string(),
             peer_addr: peer_addr.clone(),
+            alpn_offered: connection_config.alpn_for(peer_addr).to_vec(),
             session_root,
+            enable_early_data: connection_config.enable_early_data,
+            cc_algorithm: connection_config.cc_algorithm.to_ascii_lowercase(),
+            session_offered: false,
+            early_data_attempted: false,
+            server_cid: None,
+            egress_impairment: EgressImpairment::from_config(connection_config),
+            t_created: None,
+            handshake_time: None,
+            traced,
             recorder: recorder.clone(),
             context,
             app,
+            sock,
         }
     }
+
+    /// The qlog mux, if this connection is traced.
+    fn qlog(&self) -> Option<&'static qlog::QlogMux> {
+        qlog::qlog().filter(|_| self.traced)
+    }
 }
 
 impl TransportHandler for ClientHandler {
     fn on_conn_created(&mut self, conn: &mut Connection) {
         debug!("{} connection is created", conn.trace_id());
         let id = conn.trace_id().to_string();
+        self.t_created = Some(Instant::now());
 
-        // qlog
-        if let Some(w) = qlog::PerConnSqlog::new(&id) {
-            conn.set_qlog(
-                Box::new(w),
-                "client qlog".into(),
-                format!("host={} id={}", self.host, id),
-            );
-        }
-
-        // keylog
-        if let Some(kl) = crate::keylog::PerConnKeylog::new() {
-            conn.set_keylog(Box::new(kl));
+        // qlog and keylog
+        if self.traced {
+            if let Some(w) = qlog::PerConnSqlog::new(&id) {
+                conn.set_qlog(
+                    Box::new(w),
+                    "client qlog".into(),
+                    format!("host={} id={}", self.host, id),
+                );
+            }
+            if let Some(kl) = crate::keylog::PerConnKeylog::new() {
+                conn.set_keylog(Box::new(kl));
+            }
         }
 
         // session resume
@@ -268,14 +575,19 @@ impl TransportHandler for ClientHandler {
             let _ = fs::create_dir_all(&sdir);
             let session_path = sdir.join(format!("{key}.session"));
             if let Ok(session) = fs::read(&session_path) {
-                if let Err(e) = conn.set_session(&session) {
-                    error!("{} session resumption failed: {:?}", conn.trace_id(), e);
+                match conn.set_session(&session) {
+                    Ok(()) => {
+                        self.session_offered = true;
+                        self.early_data_attempted = self.enable_early_data;
+                        self.context.borrow_mut().early_data_pending = self.enable_early_data;
+                    }
+                    Err(e) => error!("{} session resumption failed: {:?}", conn.trace_id(), e),
                 }
             }
         }
 
         // qlog: mark connection created
-        if let Some(q) = qlog::qlog() {
+        if let Some(q) = self.qlog() {
             let msg = format!("conn_created host={} peer={}", self.host, self.peer_addr);
             q.info(&id, &msg);
         }
@@ -284,6 +596,9 @@ impl TransportHandler for ClientHandler {
     fn on_conn_established(&mut self, conn: &mut Connection) {
         let id = conn.trace_id().to_string();
         debug!("{} connection is established", id);
+        self.server_cid = conn.dcid().ok();
+        self.context.borrow_mut().handshake_bytes.done = true;
+        self.handshake_time = self.t_created.map(|t| t.elapsed());
 
         // If connection crashes, we still have a session file
         if !self.session_root.as_os_str().is_empty() {
@@ -296,7 +611,7 @@ impl TransportHandler for ClientHandler {
             }
         }
 
-        if let Some(q) = qlog::qlog() {
+        if let Some(q) = self.qlog() {
             let host = self.host.clone();
             let peer = self.peer_addr.to_string();
             let alpn = {
@@ -322,7 +637,7 @@ impl TransportHandler for ClientHandler {
             q.info(&id, &msg);
         }
 
-        self.app.on_connected(conn);
+        self.app.borrow_mut().on_connected(conn);
     }
 
     fn on_conn_closed(&mut self, conn: &mut Connection) {
@@ -330,6 +645,7 @@ impl TransportHandler for ClientHandler {
         debug!("{} connection is closed", id);
         let mut context = self.context.try_borrow_mut().unwrap();
         context.set_finish(true);
+        let hs_bytes = &context.handshake_bytes;
 
         // Persist session
         if !self.session_root.as_os_str().is_empty() {
@@ -344,38 +660,85 @@ impl TransportHandler for ClientHandler {
             }
         }
 
-        // Recorder file
+        let rtt = active_path_rtt(conn);
+        let loop_wall = context.loop_start.map(|t| t.elapsed());
         let s = conn.stats();
-        let meta = MetaRecord {
-            host: self.host.clone(),
-            peer_addr: self.peer_addr.clone(),
-            alpn: {
-                let v: &[u8] = conn.application_proto();
-                if v.is_empty() {
-                    None
-                } else {
-                    Some(String::from_utf8_lossy(v).into_owned())
-                }
-            },
-            handshake_ok: conn.is_established(),
-            local_close: conn.local_error().map(|e| format!("{e:?}")),
-            peer_close: conn.peer_error().map(|e| format!("{e:?}")),
-            enable_multipath: conn.is_multipath(),
-            stats: Some(BasicStats {
-                bytes_sent: s.sent_bytes,
-                bytes_recv: s.recv_bytes,
-                bytes_lost: s.lost_bytes,
-                packets_sent: s.sent_count,
-                packets_recv: s.recv_count,
-                packets_lost: s.lost_count,
-            }),
-        };
+        // Recorder file (not even built when the recorder is off)
+        if self.recorder.is_enabled() {
+            // 0-RTT outcome; BoringSSL reports "accepted" or the rejection reason
+            let early_data_reason = if self.early_data_attempted {
+                conn.early_data_reason().ok().flatten().map(str::to_string)
+            } else {
+                None
+            };
+
+            let meta = MetaRecord {
+                host: self.host.clone(),
+                peer_addr: self.peer_addr,
+                alpn_offered: self.alpn_offered.clone(),
+                alpn: {
+                    let v: &[u8] = conn.application_proto();
+                    if v.is_empty() {
+                        None
+                    } else {
+                        Some(String::from_utf8_lossy(v).into_owned())
+                    }
+                },
+                handshake_ok: conn.is_established(),
+                session_offered: self.session_offered,
+                resumed: conn.is_established() && conn.is_resumed(),
+                local_close: conn.local_error().map(|e| format!("{e:?}")),
+                peer_close: conn.peer_error().map(|e| format!("{e:?}")),
+                close_reason: Some(errors::close_reason(conn)),
+                alpn_mismatch: errors::alpn_mismatch(conn),
+                tls_version: errors::tls_version(conn),
+                tls_version_anomaly: errors::tls_version_anomaly(conn),
+                server_cid_len: self.server_cid.map(|cid| cid.len() as u8),
+                server_cid_changed: self
+                    .server_cid
+                    .and_then(|cid| conn.dcid().ok().map(|now| now != cid)),
+                server_zero_len_cid: self.server_cid.map(|cid| cid.is_empty()),
+                initial_flight_bytes: self.sock.initial_flight_bytes(),
+                observed_max_datagram: self.sock.max_datagram(),
+                first_datagram_packet_count: context.first_datagram_packets,
+                handshake_bytes_sent: hs_bytes.sent,
+                handshake_bytes_recv: hs_bytes.recv,
+                amplification_limited: hs_bytes.amplification_limited,
+                enable_multipath: conn.is_multipath(),
+                cc_algorithm: self.cc_algorithm.clone(),
+                early_data_attempted: self.early_data_attempted,
+                early_data_accepted: self
+                    .early_data_attempted
+                    .then(|| early_data_reason.as_deref() == Some("accepted")),
+                early_data_reason,
+                early_data_sent: context.early_data_sent,
+                loop_wall_ms: loop_wall.map(|d| d.as_secs_f64() * 1000.0),
+                loop_busy_ms: loop_wall
+                    .map(|d| d.saturating_sub(context.poll_wait).as_secs_f64() * 1000.0),
+                stats: Some(BasicStats {
+                    bytes_sent: s.sent_bytes,
+                    bytes_recv: s.recv_bytes,
+                    bytes_lost: s.lost_bytes,
+                    packets_sent: s.sent_count,
+                    packets_recv: s.recv_count,
+                    packets_lost: s.lost_count,
+                    min_rtt_us: rtt.map(|r| r.min),
+                    smoothed_rtt_us: rtt.map(|r| r.smoothed),
+                    rtt_variance_us: rtt.map(|r| r.variance),
+                    handshake_time_us: self.handshake_time.map(|d| d.as_micros() as u64),
+                    send_would_block_count: self.sock.send_would_block_count(),
+                }),
+                egress_impairment: self.egress_impairment,
+                egress_dropped: self.sock.dropped(),
+                error: None,
+            };
 
-        if let Err(e) = self.recorder.write_for_key(&id, &meta) {
-            log::error!("write result for {} failed: {}", id, e);
+            if let Err(e) = self.recorder.write_for_key(&id, &meta) {
+                log::error!("write result for {} failed: {}", id, e);
+            }
         }
 
-        if let Some(q) = qlog::qlog() {
+        if let Some(q) = self.qlog() {
             let msg = format!(
                 "conn_closed handsh_ok={} local={:?} peer={:?} sent={} recv={} lost={}",
                 conn.is_established(),
@@ -388,7 +751,7 @@ impl TransportHandler for ClientHandler {
             q.info(&id, &msg);
         }
 
-        self.app.on_conn_closed(conn);
+        self.app.borrow_mut().on_conn_closed(conn);
     }
 
     fn on_stream_created(&mut self, conn: &mut Connection, stream_id: u64) {
@@ -396,49 +759,146 @@ impl TransportHandler for ClientHandler {
     }
 
     fn on_stream_readable(&mut self, conn: &mut Connection, stream_id: u64) {
-        self.app.on_stream_readable(conn, stream_id);
+        self.app.borrow_mut().on_stream_readable(conn, stream_id);
     }
 
     fn on_stream_writable(&mut self, conn: &mut Connection, stream_id: u64) {
-        self.app.on_stream_writable(conn, stream_id);
+        self.app.borrow_mut().on_stream_writable(conn, stream_id);
     }
 
     fn on_stream_closed(&mut self, conn: &mut Connection, stream_id: u64) {
         debug!("{} stream {} is closed", conn.trace_id(), stream_id);
-        self.app.on_stream_closed(conn, stream_id);
+        self.app.borrow_mut().on_stream_closed(conn, stream_id);
     }
 
     fn on_new_token(&mut self, _conn: &mut Connection, _token: Vec<u8>) {}
 }
 
-fn open_connection(
+/// RTT estimates of a path (µs).
+#[derive(Clone, Copy)]
+struct Rtt {
+    min: u64,
+    smoothed: u64,
+    variance: u64,
+}
+
+/// RTT estimates of the active path, once it has an RTT sample (tquic keeps
+/// its initial guess until the first ACK).
+fn active_path_rtt(conn: &mut Connection) -> Option<Rtt> {
+    let path = conn.get_active_path().ok()?;
+    let (local, remote) = (path.local_addr(), path.remote_addr());
+    let stats = conn.get_path_stats(local, remote).ok()?;
+    (stats.acked_count > 0).then_some(Rtt {
+        min: stats.min_rtt,
+        smoothed: stats.srtt,
+        variance: stats.rttvar,
+    })
+}
+
+/// Stand-in for the `MetaRecord` normally written on close, for attempts that
+/// failed before the connection could close (or exist).
+fn failure_meta(
+    host: &str,
+    peer_addr: &SocketAddr,
+    cfg: &ConnectionConfig,
+    error: String,
+) -> MetaRecord {
+    MetaRecord {
+        host: host.to_string(),
+        peer_addr: *peer_addr,
+        alpn_offered: cfg.alpn_for(peer_addr).to_vec(),
+        alpn: None,
+        handshake_ok: false,
+        session_offered: false,
+        resumed: false,
+        local_close: None,
+        peer_close: None,
+        close_reason: None,
+        alpn_mismatch: None,
+        tls_version: None,
+        tls_version_anomaly: None,
+        server_cid_len: None,
+        server_cid_changed: None,
+        server_zero_len_cid: None,
+        initial_flight_bytes: None,
+        observed_max_datagram: None,
+        first_datagram_packet_count: None,
+        handshake_bytes_sent: 0,
+        handshake_bytes_recv: 0,
+        amplification_limited: false,
+        enable_multipath: cfg.enable_multipath,
+        cc_algorithm: cfg.cc_algorithm.to_ascii_lowercase(),
+        early_data_attempted: false,
+        early_data_accepted: None,
+        early_data_reason: None,
+        early_data_sent: false,
+        loop_wall_ms: None,
+        loop_busy_ms: None,
+        stats: None,
+        egress_impairment: EgressImpairment::from_config(cfg),
+        egress_dropped: 0,
+        error: Some(error),
+    }
+}
+
+/// Open and drive one connection. Exactly one `MetaRecord` is written per
+/// call: by `on_conn_closed`, or a synthetic one if that never fired. An
+/// untraced connection (`traced = false`) stays out of the qlog and keylog.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn open_connection(
     host: &str,
     socket_addr: &SocketAddr,
     io_config: &IOConfig,
     general_config: &GeneralConfig,
     connection_config: &ConnectionConfig,
     recorder: &Recorder,
+    traced: bool,
     app: Box<dyn AppProtocol>,
 ) -> Result<()> {
-    // Create client
-    let mut client = Client::new(
+    progress::mark(host);
+    // `on_conn_closed` writes the record and marks the client finished.
+    let (res, closed) = match Client::new(
         host,
         socket_addr,
         io_config,
         general_config,
         connection_config,
         recorder,
+        traced,
         app,
-    )?;
+    ) {
+        Ok(mut client) => {
+            let res = drive(&mut client, host, socket_addr);
+            (res, client.finish())
+        }
+        Err(e) => (Err(e), false),
+    };
+    progress::mark(host);
+
+    match &res {
+        Err(e) if !closed => {
+            let meta = failure_meta(host, socket_addr, connection_config, format!("{e:?}"));
+            if let Err(we) = recorder.write_for_key(host, &meta) {
+                error!("write failure record for {} failed: {}", host, we);
+            }
+        }
+        _ => {}
+    }
+    res
+}
+
+/// Connect and run the event loop until the connection is closed.
+fn drive(client: &mut Client, host: &str, socket_addr: &SocketAddr) -> Result<()> {
+    client.context.borrow_mut().loop_start = Some(Instant::now());
 
     // Connect to server
-    client.endpoint.connect(
+    let conn_index = client.endpoint.connect(
         client.sock.local_addr(),
         socket_addr.clone(),
         Option::from(host),
         None,
         None,
-        None,
+        None, // endpoint config is already this attempt's; see `transport_config`
     )?;
 
     // Run event loop
@@ -446,11 +906,19 @@ fn open_connection(
     loop {
         // Process connections.
         client.endpoint.process_connections()?;
+        if client.start_early_data(conn_index) {
+            // Send the app's 0-RTT data right behind the first flight
+            client.endpoint.process_connections()?;
+        }
         if client.finish() {
+            client.sock.flush_impaired(true);
             break;
         }
 
-        client.poll.poll(&mut events, client.endpoint.timeout())?;
+        let timeout = client.poll_timeout();
+        let t_poll = Instant::now();
+        client.poll.poll(&mut events, timeout)?;
+        client.context.borrow_mut().poll_wait += t_poll.elapsed();
 
         // Process IO events
         for event in events.iter() {
@@ -463,6 +931,14 @@ fn open_connection(
         // Note: Since `poll()` doesn't clearly tell if there was a timeout when it returns,
         // it is up to the endpoint to check for a timeout and deal with it.
         client.endpoint.on_timeout(Instant::now());
+        client.sock.flush_impaired(false);
+
+        // App timers
+        let app_due = client.app.borrow().timeout().is_some_and(|t| t.is_zero());
+        match client.endpoint.conn_get_mut(conn_index) {
+            Some(conn) if app_due => client.app.borrow_mut().on_timeout(conn),
+            _ => {}
+        }
     }
     Ok(())
 }
@@ -479,5 +955,140 @@ pub fn run_probe<A>(
 where
     A: AppProtocol + 'static,
 {
-    open_connection(host, addr, io, general, cfg, recorder, Box::new(app))
+    if !budget::take() {
+        return Err("connection budget (max_total_connections) exhausted".into());
+    }
+    open_connection(host, addr, io, general, cfg, recorder, true, Box::new(app))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qlog::QlogMux;
+    use crate::types::{QlogFraming, QlogTimeFormat};
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    /// Writer whose bytes can still be read after it was boxed away.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Idle;
+
+    impl AppProtocol for Idle {}
+
+    /// Long-header packet of `ty` with 8-byte CIDs and a `payload`-byte body.
+    fn long_packet(version: u32, ty: u8, token: &[u8], payload: usize) -> Vec<u8> {
+        let mut p = vec![0xc0 | (ty << 4)];
+        p.extend_from_slice(&version.to_be_bytes());
+        p.push(8);
+        p.extend_from_slice(&[1; 8]);
+        p.push(8);
+        p.extend_from_slice(&[2; 8]);
+        if ty == if version == QUIC_V2 { 1 } else { 0 } {
+            p.push(token.len() as u8);
+            p.extend_from_slice(token);
+        }
+        p.extend_from_slice(&(0x4000 | payload as u16).to_be_bytes());
+        p.resize(p.len() + payload, 0xaa);
+        p
+    }
+
+    #[test]
+    fn varint_lengths() {
+        for (bytes, v) in [
+            (&[0x25][..], 37),
+            (&[0x7b, 0xbd], 15293),
+            (&[0x9d, 0x7f, 0x3e, 0x7d], 494878333),
+        ] {
+            let mut pos = 0;
+            assert_eq!(read_varint(bytes, &mut pos), Some(v));
+            assert_eq!(pos, bytes.len());
+        }
+        assert_eq!(read_varint(&[0x40], &mut 0), None);
+    }
+
+    #[test]
+    fn counts_coalesced_long_header_packets() {
+        let mut d = long_packet(1, 0, b"tok", 100);
+        d.extend(long_packet(1, 2, &[], 300));
+        assert_eq!(coalesced_packet_count(&d), 2);
+
+        // A trailing short-header packet runs to the end
+        d.extend_from_slice(&[0x40, 1, 2, 3]);
+        assert_eq!(coalesced_packet_count(&d), 3);
+    }
+
+    #[test]
+    fn stops_at_padding_and_truncation() {
+        let mut d = long_packet(1, 0, &[], 50);
+        d.extend_from_slice(&[0; 20]);
+        assert_eq!(coalesced_packet_count(&d), 1);
+
+        let mut d = long_packet(1, 0, &[], 50);
+        d.truncate(40);
+        assert_eq!(coalesced_packet_count(&d), 1);
+        assert_eq!(coalesced_packet_count(&[]), 0);
+    }
+
+    #[test]
+    fn untraced_connection_stays_out_of_the_qlog() {
+        // The process-wide mux; no other test in this crate sets it
+        let out = SharedBuf::default();
+        qlog::init_with_mux(
+            QlogMux::with_writer(out.clone(), QlogTimeFormat::Relative, QlogFraming::Ndjson)
+                .unwrap(),
+        );
+        // A bound socket that never answers: each handshake times out
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = silent.local_addr().unwrap();
+        let general = GeneralConfig {
+            save_recorder_files: false,
+            save_error_records: false,
+            save_qlog_files: false,
+            save_keylog_files: false,
+            save_session_files: false,
+            ..GeneralConfig::default()
+        };
+        let cfg = ConnectionConfig {
+            max_idle_timeout_ms: 200,
+            ..ConnectionConfig::default()
+        };
+        let recorder = Recorder::new(std::env::temp_dir(), false, false).unwrap();
+
+        for (host, traced) in [("untraced.test", false), ("traced.test", true)] {
+            let _ = open_connection(
+                host,
+                &addr,
+                &IOConfig::default(),
+                &general,
+                &cfg,
+                &recorder,
+                traced,
+                Box::new(Idle),
+            );
+        }
+        qlog::shutdown().unwrap();
+
+        let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        assert!(text.contains("conn_created host=traced.test"));
+        assert!(!text.contains("untraced.test"));
+    }
+
+    #[test]
+    fn quic_v2_type_bits() {
+        let mut d = long_packet(QUIC_V2, 1, &[], 60);
+        d.extend(long_packet(QUIC_V2, 3, &[], 60));
+        assert_eq!(coalesced_packet_count(&d), 2);
+    }
 }
@@ -0,0 +1,102 @@
+//! Append-only `<out_dir>/triage.csv`: one row per completed host with the
+//! few columns needed for spreadsheet triage, `host,status,handshake_ms,
+//! error_category`, without parsing the recorder's JSONL.
+//!
+//! A host re-run by a retry round gets another row; the last one wins.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Result as IoResult, Write};
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+use crate::types::FailureCategory;
+
+const FILE_NAME: &str = "triage.csv";
+const HEADER: &str = "host,status,handshake_ms,error_category\n";
+const FLUSH_EVERY: u32 = 100; // flush every N hosts
+
+struct Inner {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    since_flush: u32,
+}
+
+static GLOBAL: OnceLock<Mutex<Inner>> = OnceLock::new();
+
+/// Open (append) the file, if enabled; the header goes into a new file only.
+pub fn init(out_dir: &str, enabled: bool) -> anyhow::Result<()> {
+    if !enabled {
+        return Ok(());
+    }
+    let path = PathBuf::from(out_dir).join(FILE_NAME);
+    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    let fresh = file.metadata()?.len() == 0;
+    let mut writer = BufWriter::new(file);
+    if fresh {
+        writer.write_all(HEADER.as_bytes())?;
+    }
+    let _ = GLOBAL.set(Mutex::new(Inner {
+        path,
+        writer,
+        since_flush: 0,
+    }));
+    Ok(())
+}
+
+/// Quote a field if it holds a comma, quote or line break (RFC 4180).
+fn field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Append the final outcome of a host that just completed: the last HTTP
+/// status, the last handshake duration and, if it failed, the category.
+pub fn record(
+    host: &str,
+    status: Option<u16>,
+    handshake_ms: Option<u64>,
+    category: Option<FailureCategory>,
+) -> anyhow::Result<()> {
+    let Some(inner) = GLOBAL.get() else {
+        return Ok(());
+    };
+    let category = category
+        .map(serde_json::to_value)
+        .transpose()?
+        .and_then(|v| v.as_str().map(str::to_string))
+        .unwrap_or_default();
+    let line = format!(
+        "{},{},{},{}\n",
+        field(host),
+        status.map(|s| s.to_string()).unwrap_or_default(),
+        handshake_ms.map(|ms| ms.to_string()).unwrap_or_default(),
+        category
+    );
+
+    let mut g = inner.lock().unwrap();
+    g.writer.write_all(line.as_bytes())?;
+    g.since_flush += 1;
+    if g.since_flush >= FLUSH_EVERY {
+        g.writer.flush()?;
+        g.since_flush = 0;
+    }
+    Ok(())
+}
+
+/// Path of the file, if enabled.
+pub fn path() -> Option<PathBuf> {
+    GLOBAL.get().map(|inner| inner.lock().unwrap().path.clone())
+}
+
+/// Flush the tail; the writer lives in a never-dropped `OnceLock`.
+pub fn shutdown() -> IoResult<()> {
+    if let Some(inner) = GLOBAL.get() {
+        let mut g = inner.lock().unwrap();
+        g.writer.flush()?;
+        g.since_flush = 0;
+    }
+    Ok(())
+}
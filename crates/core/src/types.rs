@@ -17,6 +17,229 @@ impl Default for IpVersion {
     }
 }
 
+/// Local egress fault injection (testing aid), from the `impair_*` and
+/// `send_loss_*` settings.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct EgressImpairment {
+    /// Delay applied to every outgoing datagram
+    pub delay_ms: u64,
+    /// Every Nth datagram is held `reorder_ms` longer, so later ones overtake it
+    pub reorder_every: u64,
+    pub reorder_ms: u64,
+    /// Probability of dropping an outgoing datagram, and the RNG seed
+    pub loss_rate: f64,
+    pub loss_seed: u64,
+}
+
+impl EgressImpairment {
+    /// None unless some impairment is configured.
+    pub fn from_config(cfg: &ConnectionConfig) -> Option<Self> {
+        let imp = Self {
+            delay_ms: cfg.impair_delay_ms,
+            reorder_every: cfg.impair_reorder_every,
+            reorder_ms: cfg.impair_reorder_ms,
+            loss_rate: cfg.send_loss_rate.clamp(0.0, 1.0),
+            loss_seed: cfg.send_loss_seed,
+        };
+        let active = imp.delay_ms > 0
+            || (imp.reorder_every > 0 && imp.reorder_ms > 0)
+            || imp.loss_rate > 0.0;
+        active.then_some(imp)
+    }
+}
+
+/// How request priorities are signalled (config values: "header", "frame", "both").
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PrioritySignal {
+    /// `priority` request header field
+    #[default]
+    Header,
+    /// PRIORITY_UPDATE frame on the control stream after the request
+    Frame,
+    Both,
+}
+
+/// How a connection's User-Agent is picked from `user_agent_pool` (config
+/// values: "round_robin", "random").
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserAgentSelection {
+    /// Pool entries in turn, across all hosts
+    #[default]
+    RoundRobin,
+    Random,
+}
+
+/// Why an attempt ended without a completed handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureCategory {
+    /// Name resolution failed; there was no address to try
+    ResolveFailed,
+    /// The connection attempt errored locally before it could close
+    LocalError,
+    /// Packets were sent but none came back (UDP blocked, no listener)
+    NoResponse,
+    /// The peer answered but the handshake did not complete
+    HandshakeFailed,
+    /// Every address was private/reserved (`skip_private_addresses`)
+    SkippedBogon,
+    /// `host_deadline_ms` ran out before the next resolution or connection
+    HostDeadline,
+}
+
+impl FailureCategory {
+    /// Classify an attempt from the packet count seen at connection close
+    /// (`None` if the connection never reached close). `None` when the
+    /// handshake completed.
+    pub fn classify(handshake_ok: bool, packets_recv: Option<u64>) -> Option<Self> {
+        match (handshake_ok, packets_recv) {
+            (true, _) => None,
+            (false, None) => Some(Self::LocalError),
+            (false, Some(0)) => Some(Self::NoResponse),
+            (false, Some(_)) => Some(Self::HandshakeFailed),
+        }
+    }
+
+    /// Worth another try in a later `global_retry_rounds` round: the failure
+    /// may be transient rather than an answer from the peer or our own policy.
+    pub fn is_retryable(self) -> bool {
+        match self {
+            Self::ResolveFailed | Self::LocalError | Self::NoResponse | Self::HostDeadline => true,
+            Self::HandshakeFailed | Self::SkippedBogon => false,
+        }
+    }
+}
+
+/// How a connection that reached close ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// We closed it: application close, or a transport close with NO_ERROR
+    LocalClose,
+    /// The peer sent CONNECTION_CLOSE
+    PeerClose,
+    /// No packets within `max_idle_timeout_ms`
+    IdleTimeout,
+    /// The handshake did not complete within tquic's handshake timeout
+    HandshakeTimeout,
+    /// The peer sent a stateless reset
+    Reset,
+    /// Our QUIC stack closed it with a transport error (or no cause was visible)
+    Error,
+}
+
+/// Where host names are resolved (config values: "system", or
+/// `{ doh = { url = "https://..." } }`).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResolverMode {
+    /// The system resolver (`getaddrinfo`)
+    #[default]
+    System,
+    /// DNS-over-HTTPS (RFC 8484) over HTTP/3 against `url`
+    DoH { url: String },
+}
+
+/// What to do when name resolution fails (config values: "none", "system").
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResolverFallback {
+    /// Report the DNS failure as-is (strict single-resolver runs)
+    #[default]
+    None,
+    /// Query the system resolver once more before giving up
+    System,
+}
+
+/// Timestamps in the aggregated qlog (config values: "relative", "absolute").
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QlogTimeFormat {
+    /// ms since the session's `reference_time` (what qvis expects)
+    #[default]
+    Relative,
+    /// `reference_time: 0` and per-event epoch ms
+    Absolute,
+}
+
+/// Record framing of the aggregated qlog (config values: "json-seq", "ndjson").
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QlogFraming {
+    /// RFC 7464: RS before each record, plus a qlog header (what qvis expects)
+    #[default]
+    JsonSeq,
+    /// One event object per line, no RS and no header (grep/jq-friendly)
+    Ndjson,
+}
+
+/// How much of each connection trace the qlog keeps (config values: "full",
+/// "qvis", "packets_only").
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QlogProfile {
+    /// Events as tquic emits them, `raw` payloads included
+    Full,
+    /// What qvis and the custom stats need: heavy fields pruned, noisy
+    /// events dropped
+    #[default]
+    Qvis,
+    /// Only packet events (pruned as for `Qvis`), plus `meta:*`/`loglevel:*`
+    PacketsOnly,
+}
+
+/// Where connection traces go (config values: "aggregate", "per_connection").
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QlogMode {
+    /// All connections in the rotated `quic-lab.sqlog`, told apart by `group_id`
+    #[default]
+    Aggregate,
+    /// One `qlog_files/<shard>/<trace_id>.sqlog` per connection (e.g. for qvis)
+    PerConnection,
+}
+
+/// Time-based rotation of the log and qlog files on top of their size cap
+/// (config values: "none", "hourly", "daily"; periods start on the UTC hour/day).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeRotation {
+    #[default]
+    None,
+    Hourly,
+    Daily,
+}
+
+/// Shape of the inter-attempt delay (config values: "fixed", "uniform", "poisson").
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DelayDistribution {
+    /// Always exactly the configured delay
+    #[default]
+    Fixed,
+    /// Uniform in [0, 2 × delay]
+    Uniform,
+    /// Exponential with mean = delay, i.e. Poisson arrivals
+    Poisson,
+}
+
+/// Which probe the runner executes per host (config values: "h3", "soak",
+/// "compare").
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProbeKind {
+    /// One-shot HTTP/3 GET(s), `probes::h3`
+    #[default]
+    H3,
+    /// Long-lived connection with periodic requests, `probes::soak`
+    Soak,
+    /// `h3` with two configured arms per host, diffing the outcomes,
+    /// `probes::compare`
+    Compare,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct ProbeOutcome {
     /// true if failure looked like timeout/ICMP "no QUIC here", so trying the other family makes sense
@@ -24,6 +247,14 @@ pub struct ProbeOutcome {
 }
 
 impl ProbeOutcome {
+    /// Outcome of a connection config whose last failure was `failure`
+    /// (None: it did not fail on transport, e.g. an ALPN mismatch).
+    pub fn from_failure(failure: Option<FailureCategory>) -> Self {
+        match failure {
+            Some(c) if c.is_retryable() => Self::retryable_fail(),
+            _ => Self::nonretryable_fail(),
+        }
+    }
     pub fn success() -> Self {
         Self { retryable: false }
     }
@@ -39,6 +270,62 @@ impl ProbeOutcome {
 pub struct Http3Result {
     pub attempted: bool,
     pub status: Option<u16>,
+
+    /// Parallel request streams asked for by the config vs actually opened
+    pub streams_requested: u64,
+    pub streams_opened: u64,
+    /// Most measurement streams in flight at once (bounded by `max_concurrent_streams`)
+    pub peak_concurrent_streams: u64,
+    pub streams: Vec<StreamResult>,
+    /// Cold request issued before measurement when `warmup_request` is set
+    pub warmup: Option<StreamResult>,
+
+    /// Aggregate body bytes over all streams and the resulting goodput
+    pub bytes_total: u64,
+    pub goodput_bps: Option<f64>,
+
+    /// The server sent a PRIORITY_UPDATE frame, which only clients may send;
+    /// the connection was closed with H3_FRAME_UNEXPECTED
+    pub peer_priority_update: bool,
+    /// H3 events received after every request completed (`post_finish_ms`),
+    /// e.g. "goaway" or "headers on stream 0", up to a cap
+    pub late_events: Vec<String>,
+}
+
+/// Per-stream outcome of a (possibly parallel) HTTP/3 fetch.
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamResult {
+    pub stream_id: u64,
+    pub path: String,
+    pub status: Option<u16>,
+    pub bytes: u64,
+    /// Time from opening the stream to the first body byte / to stream end
+    pub ttfb_ms: Option<f64>,
+    pub duration_ms: Option<f64>,
+    pub rate_bps: Option<f64>,
+    pub finished: bool,
+    /// `priority` response header, the server's view of the stream priority
+    pub server_priority: Option<String>,
+    /// `server` response header, as sent
+    pub server: Option<String>,
+    /// Every response header received on the stream, up to a cap
+    pub response_headers: ResponseHeaders,
+    /// Trailer section (a header block after the final response headers),
+    /// also part of `response_headers`
+    pub trailers: Option<ResponseHeaders>,
+    /// Hex-encoded start of the body (`capture_body_bytes`, first response
+    /// only) and whether the body went on past it
+    pub body_prefix: Option<String>,
+    pub body_truncated: bool,
+}
+
+/// Response header fields of one request stream, names lowercased, in the
+/// order received (interim responses and trailers included).
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ResponseHeaders {
+    pub headers: Vec<(String, String)>,
+    /// Headers were dropped after the count or byte cap was reached
+    pub truncated: bool,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -46,19 +333,145 @@ pub struct ProbeRecord {
     pub host: String,
     pub fam: String,
     pub peer_addr: String,
+    /// Resolver that produced `peer_addr` ("system" or "system-fallback")
+    pub resolver: &'static str,
+    /// `peer_addr` came from a stale DNS cache entry (`dns_stale_ttl_secs`)
+    pub dns_stale: bool,
+    /// Private/reserved addresses were dropped (`skip_private_addresses`); with
+    /// category `skipped_bogon` the host had nothing else and was not probed
+    pub skipped_bogon: bool,
+    /// Addresses the resolver returned vs selected after `max_addrs_*` caps
+    pub addrs_available: usize,
+    pub addrs_selected: usize,
 
     pub t_start_ms: u128,
     pub t_handshake_ok_ms: Option<u128>,
     pub t_end_ms: u128,
 
+    /// The host resolved (false only for records written on DNS failure or a
+    /// bogon skip, or a host deadline hit before any resolution)
+    pub resolved: bool,
+    pub handshake_ok: bool,
+    /// Set whenever `handshake_ok` is false, unless the connection was closed
+    /// for losing a Happy Eyeballs race (`race.lost`)
+    pub category: Option<FailureCategory>,
+    /// Probing stopped at `host_deadline_ms` (category `host_deadline`)
+    pub host_deadline_hit: bool,
+    /// Retry of this connection config (0 = first try, up to `max_retries`)
+    pub retry: u32,
+    /// After a failure with addresses left: whether the next one was tried
+    /// (see `timeout_is_retryable`)
+    pub retry_next_addr: Option<bool>,
+
+    /// User-Agent sent on this connection (null if it never connected)
+    pub user_agent: Option<String>,
+    /// ALPN list offered on this attempt (empty if nothing was sent)
+    pub alpn_offered: Vec<String>,
     pub alpn: Option<String>,
     pub http3: Http3Result,
 
     pub error: Option<String>,
+    /// Earlier failed attempts for this host (other address or config), so a
+    /// later success does not hide e.g. "IPv6 broken, IPv4 fine"
+    pub prior_errors: Vec<PriorError>,
+    /// Set on the connections of a Happy Eyeballs race
+    /// (`happy_eyeballs_delay_ms`)
+    pub race: Option<RaceResult>,
+    /// Handshake failed on ALPN alone: the host speaks QUIC, not our protocol
+    pub alpn_mismatch: bool,
+    /// QPACK (header compression) failure, e.g. "peer QPACK_DECODER_STREAM_ERROR"
+    pub qpack_error: Option<String>,
+    /// Response headers used QPACK dynamic-table references (None = no headers
+    /// received; true only detectable with `qpack_max_table_capacity` > 0)
+    pub qpack_dynamic_used: Option<bool>,
     pub cfg: ConnectionConfig,
 }
 
-/// Pretty labels for logs
+/// One arm's outcome for a host in `probe = "compare"`, from its last record
+/// (race losers aside): what `triage.csv` would report for it.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ArmOutcome {
+    pub fam: String,
+    pub peer_addr: String,
+    pub handshake_ok: bool,
+    pub handshake_ms: Option<u64>,
+    /// Last HTTP status seen over all of the arm's records
+    pub status: Option<u16>,
+    pub alpn: Option<String>,
+    pub category: Option<FailureCategory>,
+}
+
+impl ArmOutcome {
+    pub fn from_records(records: &[ProbeRecord]) -> Self {
+        let Some(last) = records
+            .iter()
+            .rev()
+            .find(|r| !r.race.as_ref().is_some_and(|race| race.lost))
+        else {
+            return Self::default();
+        };
+        Self {
+            fam: last.fam.clone(),
+            peer_addr: last.peer_addr.clone(),
+            handshake_ok: last.handshake_ok,
+            handshake_ms: last
+                .t_handshake_ok_ms
+                .map(|t| t.saturating_sub(last.t_start_ms) as u64),
+            status: records.iter().rev().find_map(|r| r.http3.status),
+            alpn: last.alpn.clone(),
+            category: last.category,
+        }
+    }
+}
+
+/// A host whose two `compare` arms disagree (`<out_dir>/diffs.jsonl`).
+#[derive(Debug, Clone, Serialize)]
+pub struct DiffRecord {
+    pub host: String,
+    /// Fields that differ: "handshake_ok", "status", "alpn", "category",
+    /// "handshake_ms" (beyond `handshake_ms_threshold`)
+    pub differs: Vec<&'static str>,
+    pub a: ArmOutcome,
+    pub b: ArmOutcome,
+}
+
+/// Outcome of a Happy Eyeballs or connect race, as seen by one of its
+/// connections.
+#[derive(Debug, Clone, Serialize)]
+pub struct RaceResult {
+    /// Family ("IPv4"/"IPv6") whose handshake completed first, null if none did
+    pub winner: Option<String>,
+    /// Address whose handshake completed first
+    pub winner_addr: Option<String>,
+    /// Connections actually started in the race
+    pub raced: usize,
+    /// This connection was closed because another one won
+    pub lost: bool,
+}
+
+/// Outcome of re-running a host in a `global_retry_rounds` round (keyed by host).
+#[derive(Debug, Clone, Serialize)]
+pub struct RetryRoundRecord {
+    pub host: String,
+    /// 1 for the first retry round
+    pub round: u32,
+    /// The host no longer has a transient failure after this round
+    pub succeeded: bool,
+}
+
+/// One failed address/config attempt, as carried in `ProbeRecord::prior_errors`.
+#[derive(Debug, Clone, Serialize)]
+pub struct PriorError {
+    /// Index into `[[connection_config]]`
+    pub attempt: usize,
+    pub fam: String,
+    pub peer_addr: String,
+    pub error: String,
+}
+
+/// Pretty labels for logs and `ProbeRecord.fam`. Records of connected
+/// attempts carry the address's own family; "Auto" (both families,
+/// interleaved) only appears on records written before any address was tried.
 pub fn family_label(f: IpVersion) -> &'static str {
     match f {
         IpVersion::Auto => "Auto",
@@ -67,17 +480,93 @@ pub fn family_label(f: IpVersion) -> &'static str {
     }
 }
 
+/// Per-connection transport outcome, written by the transport on close.
+///
+/// Built only in the transport: `ClientHandler::on_conn_closed`, and
+/// `failure_meta` for attempts that never closed. Both set every field and
+/// none is skipped when serializing, so every line has the same schema.
+///
+/// Not recorded: GREASE tolerance. tquic offers no GREASE transport parameters
+/// or versions and drops the peer's `grease_quic_bit`, so it is indeterminate.
+/// Nor DATAGRAM support (RFC 9221): tquic can neither send
+/// `max_datagram_frame_size` nor keep the peer's, which it skips as unknown.
+/// Nor ECN: tquic sends no ECT marks and reads no codepoints, and drops the
+/// ECN counts of received ACK frames, so there is nothing to count.
+/// Nor the path MTU found by DPLPMTUD: tquic 1.6 keeps its probing state
+/// private (`observed_max_datagram` shows what was actually sent).
 #[derive(serde::Serialize)]
 pub struct MetaRecord {
     pub host: String,
     pub peer_addr: SocketAddr,
+    /// ALPN list we offered (`alpn`, or the `alpn_v4`/`alpn_v6` override)
+    pub alpn_offered: Vec<String>,
     pub alpn: Option<String>,
     pub handshake_ok: bool,
+    /// A stored session ticket was offered, and the server accepted it (a
+    /// rejected ticket completes as a full handshake: offered, not resumed)
+    pub session_offered: bool,
+    pub resumed: bool,
     pub local_close: Option<String>,
     pub peer_close: Option<String>,
+    /// Why the connection ended; null if it never reached close
+    pub close_reason: Option<CloseReason>,
+    /// Side that raised `no_application_protocol` ("peer" | "local"), if any
+    pub alpn_mismatch: Option<&'static str>,
+    /// Negotiated TLS version ("1.3" on any completed handshake), and the side
+    /// that aborted with a `protocol_version` alert if TLS 1.3 was refused
+    pub tls_version: Option<&'static str>,
+    pub tls_version_anomaly: Option<&'static str>,
+    /// Length of the server-chosen connection ID at handshake completion and
+    /// whether the CID in use differed by close (NEW_CONNECTION_ID rotation)
+    pub server_cid_len: Option<u8>,
+    pub server_cid_changed: Option<bool>,
+    /// The server chose a zero-length connection ID (no CID-based load
+    /// balancing or migration), null if the handshake never completed
+    pub server_zero_len_cid: Option<bool>,
+    /// Size of the client's first flight (Initial datagram(s), padding
+    /// included), null if nothing was sent
+    pub initial_flight_bytes: Option<u64>,
+    /// Largest UDP payload actually sent in one datagram (PMTU probes
+    /// included), to set against the configured `send_udp_payload_size`
+    pub observed_max_datagram: Option<u16>,
+    /// QUIC packets coalesced into the first datagram from the server (e.g.
+    /// Initial + Handshake), null if nothing was received
+    pub first_datagram_packet_count: Option<u32>,
+    /// UDP bytes sent/received until the handshake completed (or the close),
+    /// and whether the server ever hit its 3x anti-amplification budget
+    pub handshake_bytes_sent: u64,
+    pub handshake_bytes_recv: u64,
+    pub amplification_limited: bool,
     pub enable_multipath: bool,
+    /// Congestion control algorithm used (`ConnectionConfig::cc_algorithm`)
+    pub cc_algorithm: String,
+    /// 0-RTT: attempted = early data enabled and a session ticket was offered.
+    /// `accepted`/`reason` are null when 0-RTT was not attempted.
+    pub early_data_attempted: bool,
+    pub early_data_accepted: Option<bool>,
+    pub early_data_reason: Option<String>,
+    /// The application started sending in 0-RTT rather than after the handshake
+    pub early_data_sent: bool,
+    /// Wall time this connection's event loop ran until close, and the part
+    /// not spent waiting for the network (processing, i.e. worker cost)
+    pub loop_wall_ms: Option<f64>,
+    pub loop_busy_ms: Option<f64>,
     pub stats: Option<BasicStats>,
+    /// Egress impairment active on this connection (testing aid), if any,
+    /// and the datagrams it dropped
+    pub egress_impairment: Option<EgressImpairment>,
+    pub egress_dropped: u64,
+    /// Local error that ended the attempt before the connection closed. Only
+    /// set on the synthetic record written in that case (keyed by host).
+    pub error: Option<String>,
 }
+/// Connection-level transfer counters from `Connection::stats()`.
+///
+/// Loss-recovery detail beyond these is not available from tquic 1.x: the PTO
+/// counter is private to its recovery module (and reset on every ACK), lost
+/// frames are re-queued without a retransmission counter, and
+/// `PathStats::loss_event_count` is never incremented. `packets_lost` is the
+/// closest aggregate; use qlog `recovery:packet_lost` for per-event detail.
 #[derive(serde::Serialize)]
 pub struct BasicStats {
     pub bytes_sent: u64,
@@ -86,4 +575,13 @@ pub struct BasicStats {
     pub packets_sent: u64,
     pub packets_recv: u64,
     pub packets_lost: u64,
+    /// RTT estimates of the active path (µs); null before any packet was acked
+    pub min_rtt_us: Option<u64>,
+    pub smoothed_rtt_us: Option<u64>,
+    pub rtt_variance_us: Option<u64>,
+    /// Connection created to handshake completed (µs); null if it never did
+    pub handshake_time_us: Option<u64>,
+    /// Sends refused because the local UDP send buffer was full; the packets
+    /// stay queued in tquic, so this is local backpressure, not path loss
+    pub send_would_block_count: u64,
 }
@@ -0,0 +1,75 @@
+//! Compare probe: probe every host with two explicitly configured arms
+//! (`[compare.a]`, `[compare.b]`, e.g. IPv4 vs IPv6 or two transport
+//! settings) and write the hosts where the arms disagree to `diffs.jsonl`.
+//!
+//! Each arm is a full `h3` probe of its own, one after the other, writing its
+//! records (with the arm's settings in `cfg`) as for `probe = "h3"`. Both arms
+//! share the host deadline, and the host is accounted for once (triage row,
+//! summary counts, `global_retry_rounds`): as succeeded if either arm did.
+
+use anyhow::{anyhow, Context, Result};
+use core::config::{ConnectionConfig, GeneralConfig, IOConfig, SchedulerConfig};
+use core::diffs;
+use core::recorder::Recorder;
+use core::throttle::{self, HostDeadline, NotReady, RateLimit};
+use core::types::ArmOutcome;
+use log::{debug, error};
+
+use crate::h3::{self, HostTally};
+
+/// Probe `host` with both `arms` (`a`, then `b`) and record whether they
+/// differ. Without arms there is nothing to compare.
+pub fn probe(
+    host: &str,
+    scheduler_config: &SchedulerConfig,
+    io_config: &IOConfig,
+    general_config: &GeneralConfig,
+    arms: &[ConnectionConfig],
+    rl: &RateLimit,
+    recorder: &Recorder,
+) -> Result<()> {
+    let [a, b] = arms else {
+        return match arms.len() {
+            0 => Ok(()),
+            n => Err(anyhow!("compare probe needs two arms, got {n}")),
+        };
+    };
+
+    // Both arms share the host deadline
+    let deadline = HostDeadline::start(scheduler_config.host_deadline_ms);
+    let run_arm = |arm: &ConnectionConfig| {
+        let mut records = Vec::new();
+        let mut tally = HostTally::default();
+        let res = h3::probe_configs(
+            host,
+            scheduler_config,
+            io_config,
+            general_config,
+            std::slice::from_ref(arm),
+            rl,
+            recorder,
+            &deadline,
+            &mut tally,
+            Some(&mut records),
+        );
+        (ArmOutcome::from_records(&records), tally, res)
+    };
+    let (outcome_a, tally_a, res_a) = run_arm(a);
+    let (outcome_b, tally_b, res_b) = run_arm(b);
+    // Interrupted: the host counts as not probed, nothing to diff or account for
+    for res in [&res_a, &res_b] {
+        if res.as_ref().is_err_and(throttle::is_cancelled) {
+            return Err(NotReady::Cancelled.into());
+        }
+    }
+    HostTally::either(tally_a, tally_b).record(host);
+
+    match diffs::record(host, outcome_a, outcome_b) {
+        Ok(true) => debug!("[{}] compare arms differ", host),
+        Ok(false) => {}
+        Err(e) => error!("[{}] failed to write diff record: {e:#}", host),
+    }
+
+    res_a.context("arm a")?;
+    res_b.context("arm b")
+}
@@ -0,0 +1,376 @@
+//! Reusable extended-CONNECT (RFC 9220) building block for tunneling probes.
+//!
+//! MASQUE (`connect-udp`, `connect-ip`), WebTransport and similar protocols all
+//! open with the same exchange: wait for the server's SETTINGS, check that it
+//! advertises the extensions the protocol needs, send a CONNECT request carrying
+//! `:protocol`, and read the response status. `ConnectApp` implements that once;
+//! a concrete probe only describes itself with a `ConnectSpec`, e.g.
+//!
+//! ```ignore
+//! let spec = ConnectSpec::new("connect-udp", "/.well-known/masque/udp/{host}/{port}/")
+//!     .require_setting(SETTINGS_H3_DATAGRAM);
+//! connect::probe(host, &spec, ...)?;
+//! ```
+//!
+//! Like `template.rs`, results are written per connection via `Recorder`.
+
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::Result;
+use core::config::{ConnectionConfig, GeneralConfig, IOConfig, SchedulerConfig};
+use core::recorder::Recorder;
+use core::resolver::resolve_targets;
+use core::throttle::{sample_delay, HostDeadline, RateLimit};
+use core::transport::quic::{run_probe, AppProtocol, CloseLinger};
+use log::{debug, error};
+use serde::Serialize;
+use tquic::h3::connection::Http3Connection;
+use tquic::h3::{Header, Http3Config, Http3Event, NameValue};
+use tquic::Connection;
+
+/// `SETTINGS_ENABLE_CONNECT_PROTOCOL` (RFC 9220); always required.
+pub const SETTINGS_ENABLE_CONNECT_PROTOCOL: u64 = 0x08;
+
+/// `SETTINGS_H3_DATAGRAM` (RFC 9297); needed by MASQUE and WebTransport.
+pub const SETTINGS_H3_DATAGRAM: u64 = 0x33;
+
+/// `SETTINGS_WEBTRANSPORT_MAX_SESSIONS` (draft-ietf-webtrans-http3).
+pub const SETTINGS_WEBTRANSPORT_MAX_SESSIONS: u64 = 0xc671706a;
+
+/// Describes one tunneling protocol on top of extended CONNECT.
+#[derive(Debug, Clone)]
+pub struct ConnectSpec {
+    /// Value of the `:protocol` pseudo-header (e.g. "connect-udp", "webtransport").
+    pub protocol: String,
+    /// Request `:path`; `{host}` is replaced with the probed host.
+    pub path: String,
+    /// Peer SETTINGS that must be present and non-zero before CONNECT is sent.
+    pub required_settings: Vec<u64>,
+    /// Extra request headers (e.g. `capsule-protocol: ?1`).
+    pub extra_headers: Vec<(String, String)>,
+}
+
+impl ConnectSpec {
+    pub fn new(protocol: &str, path: &str) -> Self {
+        Self {
+            protocol: protocol.to_string(),
+            path: path.to_string(),
+            required_settings: vec![SETTINGS_ENABLE_CONNECT_PROTOCOL],
+            extra_headers: Vec::new(),
+        }
+    }
+
+    pub fn require_setting(mut self, id: u64) -> Self {
+        if !self.required_settings.contains(&id) {
+            self.required_settings.push(id);
+        }
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.extra_headers
+            .push((name.to_ascii_lowercase(), value.to_string()));
+        self
+    }
+
+    /// Required settings the peer did not advertise (or advertised as 0).
+    fn missing_settings(&self, peer: &[(u64, u64)]) -> Vec<u64> {
+        self.required_settings
+            .iter()
+            .copied()
+            .filter(|id| !peer.iter().any(|(k, v)| k == id && *v != 0))
+            .collect()
+    }
+
+    fn request_headers(&self, host: &str, user_agent: &str) -> Vec<Header> {
+        let path = self.path.replace("{host}", host);
+        let mut headers = vec![
+            Header::new(b":method", b"CONNECT"),
+            Header::new(b":protocol", self.protocol.as_bytes()),
+            Header::new(b":scheme", b"https"),
+            Header::new(b":authority", host.as_bytes()),
+            Header::new(b":path", path.as_bytes()),
+            Header::new(b"user-agent", user_agent.as_bytes()),
+        ];
+        for (name, value) in &self.extra_headers {
+            headers.push(Header::new(name.as_bytes(), value.as_bytes()));
+        }
+        headers
+    }
+}
+
+#[derive(Debug, Default)]
+struct ConnectState {
+    trace_id: Option<String>,
+    handshake_ok: bool,
+    peer_settings: Option<Vec<(u64, u64)>>,
+    missing_settings: Vec<u64>,
+    connect_sent: bool,
+    status: Option<u16>,
+}
+
+/// HTTP/3 app that performs a single extended CONNECT per `ConnectSpec`.
+struct ConnectApp {
+    host: String,
+    user_agent: String,
+    spec: ConnectSpec,
+    h3: Option<Http3Connection>,
+    linger: CloseLinger,
+    shared: Arc<Mutex<ConnectState>>,
+}
+
+impl ConnectApp {
+    fn new(
+        host: &str,
+        cfg: &ConnectionConfig,
+        spec: &ConnectSpec,
+        shared: Arc<Mutex<ConnectState>>,
+    ) -> Self {
+        Self {
+            host: host.to_string(),
+            user_agent: cfg.pick_user_agent().to_string(),
+            spec: spec.clone(),
+            h3: None,
+            linger: CloseLinger::new(cfg),
+            shared,
+        }
+    }
+
+    /// Once the peer SETTINGS arrived, check capabilities and send CONNECT.
+    fn maybe_send_connect(&mut self, conn: &mut Connection, st: &mut ConnectState) {
+        let Some(h3) = self.h3.as_mut() else {
+            return;
+        };
+        if st.peer_settings.is_some() {
+            return;
+        }
+        let Some(peer) = h3.peer_raw_settings() else {
+            return; // SETTINGS not received yet
+        };
+        st.peer_settings = Some(peer.to_vec());
+        st.missing_settings = self.spec.missing_settings(peer);
+
+        if !st.missing_settings.is_empty() {
+            debug!(
+                "[{}] {}: peer lacks settings {:x?}",
+                self.host, self.spec.protocol, st.missing_settings
+            );
+            let _ = conn.close(true, 0x00, b"nocap");
+            return;
+        }
+
+        let headers = self.spec.request_headers(&self.host, &self.user_agent);
+        let sent = h3
+            .stream_new(conn)
+            .and_then(|sid| h3.send_headers(conn, sid, &headers, false));
+        match sent {
+            Ok(()) => st.connect_sent = true,
+            Err(e) => {
+                error!("[{}] CONNECT send error: {:?}", self.host, e);
+                let _ = conn.close(true, 0x1, b"connect");
+            }
+        }
+    }
+}
+
+impl AppProtocol for ConnectApp {
+    fn on_connected(&mut self, conn: &mut Connection) {
+        {
+            let mut st = self.shared.lock().unwrap();
+            st.trace_id = Some(conn.trace_id().to_string());
+            st.handshake_ok = true;
+        }
+
+        let h3 = Http3Config::new().and_then(|cfg| Http3Connection::new_with_quic_conn(conn, &cfg));
+        match h3 {
+            Ok(h3) => self.h3 = Some(h3),
+            Err(e) => {
+                error!("[{}] http3 init error: {:?}", self.host, e);
+                let _ = conn.close(true, 0x1, b"h3init");
+            }
+        }
+    }
+
+    fn on_stream_readable(&mut self, conn: &mut Connection, _stream_id: u64) {
+        let shared = self.shared.clone();
+        let mut st = shared.lock().unwrap();
+
+        // Drive H3 (this is also what processes the peer's control stream).
+        while let Some(Ok((_sid, event))) = self.h3.as_mut().map(|h3| h3.poll(conn)) {
+            if let Http3Event::Headers { headers, .. } = event {
+                st.status = headers
+                    .iter()
+                    .find(|h| h.name() == b":status")
+                    .and_then(|h| std::str::from_utf8(h.value()).ok())
+                    .and_then(|s| s.parse().ok());
+                // The generic block stops at the CONNECT response.
+                self.linger.close(conn);
+                return;
+            }
+        }
+
+        self.maybe_send_connect(conn, &mut st);
+    }
+
+    fn on_conn_closed(&mut self, conn: &mut Connection) {
+        debug!(
+            "[{}] {}: closed (status={:?}, local={:?}, peer={:?})",
+            self.host,
+            self.spec.protocol,
+            self.shared.lock().unwrap().status,
+            conn.local_error(),
+            conn.peer_error()
+        );
+    }
+
+    fn timeout(&self) -> Option<std::time::Duration> {
+        self.linger.timeout()
+    }
+
+    fn on_timeout(&mut self, conn: &mut Connection) {
+        self.linger.on_timeout(conn);
+    }
+}
+
+/// Per-connection CONNECT outcome written via `Recorder`.
+#[derive(Debug, Serialize)]
+pub struct ConnectResult {
+    pub host: String,
+    pub protocol: String,
+    pub trace_id: Option<String>,
+    pub resolver: &'static str,
+    pub dns_stale: bool,
+    pub skipped_bogon: bool,
+    pub elapsed_ms: u128,
+    pub handshake_ok: bool,
+    /// Raw SETTINGS advertised by the server (None if never received)
+    pub peer_settings: Option<Vec<(u64, u64)>>,
+    /// Required settings the server did not advertise; CONNECT is skipped if any
+    pub missing_settings: Vec<u64>,
+    pub connect_sent: bool,
+    pub status: Option<u16>,
+}
+
+/// Entry point mirroring `h3::probe`, parameterised by the tunneling protocol.
+#[allow(clippy::too_many_arguments)]
+pub fn probe(
+    host: &str,
+    spec: &ConnectSpec,
+    scheduler_config: &SchedulerConfig,
+    io_config: &IOConfig,
+    general_config: &GeneralConfig,
+    connection_configs: &[ConnectionConfig],
+    rl: &RateLimit,
+    recorder: &Recorder,
+) -> Result<()> {
+    let deadline = HostDeadline::start(scheduler_config.host_deadline_ms);
+
+    for (idx, att) in connection_configs.iter().enumerate() {
+        if deadline.expired() {
+            return Err(deadline.error());
+        }
+        let resolved = resolve_targets(host, att)?;
+
+        let mut attempt_succeeded = false;
+
+        for &(_fam_eff, addr) in &resolved.targets {
+            if deadline.expired() {
+                return Err(deadline.error());
+            }
+            rl.until_ready_timeout(deadline.clamp(scheduler_config.throttle_max_wait()))
+                .map_err(|e| {
+                    if deadline.expired() {
+                        deadline.error()
+                    } else {
+                        e.into()
+                    }
+                })?;
+
+            let t_start = Instant::now();
+            let shared = Arc::new(Mutex::new(ConnectState::default()));
+            let app = ConnectApp::new(host, att, spec, shared.clone());
+
+            let res = run_probe(host, &addr, io_config, general_config, att, recorder, app);
+
+            let st = shared.lock().unwrap();
+            let record = ConnectResult {
+                host: host.to_string(),
+                protocol: spec.protocol.clone(),
+                trace_id: st.trace_id.clone(),
+                resolver: resolved.resolver,
+                dns_stale: resolved.stale,
+                skipped_bogon: resolved.skipped_bogon,
+                elapsed_ms: t_start.elapsed().as_millis(),
+                handshake_ok: st.handshake_ok,
+                peer_settings: st.peer_settings.clone(),
+                missing_settings: st.missing_settings.clone(),
+                connect_sent: st.connect_sent,
+                status: st.status,
+            };
+            let key = record.trace_id.as_deref().unwrap_or(host);
+            if let Err(e) = recorder.write_for_key(key, &record) {
+                error!(
+                    "[{}] connect: failed to write record for {}: {e}",
+                    host, key
+                );
+            }
+
+            if let Err(e) = res {
+                error!("[{}] connect: {} error: {e:?}", host, addr);
+                continue;
+            }
+
+            attempt_succeeded = true;
+            break;
+        }
+
+        if attempt_succeeded {
+            break;
+        } else if idx + 1 < connection_configs.len() && scheduler_config.inter_attempt_delay_ms > 0
+        {
+            std::thread::sleep(deadline.clamp(sample_delay(
+                scheduler_config.delay_distribution,
+                scheduler_config.inter_attempt_delay_ms,
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_settings_requires_nonzero_values() {
+        let spec = ConnectSpec::new("connect-udp", "/").require_setting(SETTINGS_H3_DATAGRAM);
+        let peer = [
+            (SETTINGS_ENABLE_CONNECT_PROTOCOL, 1),
+            (SETTINGS_H3_DATAGRAM, 0),
+        ];
+        assert_eq!(spec.missing_settings(&peer), vec![SETTINGS_H3_DATAGRAM]);
+        assert!(spec.missing_settings(&[(0x08, 1), (0x33, 1)]).is_empty());
+    }
+
+    #[test]
+    fn request_headers_carry_protocol_and_path() {
+        let spec =
+            ConnectSpec::new("connect-udp", "/masque/{host}/443/").header("Capsule-Protocol", "?1");
+        let headers = spec.request_headers("example.com", "ua");
+        let get = |n: &[u8]| {
+            headers
+                .iter()
+                .find(|h| h.name() == n)
+                .map(|h| h.value().to_vec())
+        };
+        assert_eq!(get(b":method").as_deref(), Some(&b"CONNECT"[..]));
+        assert_eq!(get(b":protocol").as_deref(), Some(&b"connect-udp"[..]));
+        assert_eq!(
+            get(b":path").as_deref(),
+            Some(&b"/masque/example.com/443/"[..])
+        );
+        assert_eq!(get(b"capsule-protocol").as_deref(), Some(&b"?1"[..]));
+    }
+}
@@ -1,47 +1,570 @@
-use anyhow::Result;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use core::budget;
+use core::by_ip::{self, HostOutcome};
 use core::config::{ConnectionConfig, GeneralConfig, IOConfig, SchedulerConfig};
+use core::errors;
 use core::recorder::Recorder;
-use core::resolver::resolve_targets;
-use core::throttle::RateLimit;
+use core::resolver::{final_resolver, resolve_targets, BogonSkipped, Resolved};
+use core::retry;
+use core::summary;
+use core::throttle::{self, sample_delay, HostDeadline, NotReady, RateLimit};
 
-use core::transport::quic::{run_probe, AppProtocol};
+use core::transport::quic::{self, run_probe, AppProtocol, CloseLinger};
+use core::triage;
+use core::types::{
+    family_label, FailureCategory, Http3Result, IpVersion, PriorError, PrioritySignal,
+    ProbeOutcome, ProbeRecord, RaceResult, ResponseHeaders, StreamResult,
+};
 use log::{debug, error};
-use tquic::h3::connection::Http3Connection;
-use tquic::h3::{Header, Http3Config, Http3Event, NameValue};
+use tquic::h3::connection::{Http3Connection, Http3Priority};
+use tquic::h3::{Header, Http3Config, Http3Error, Http3Event, NameValue};
 use tquic::Connection;
 
+pub(crate) fn epoch_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0)
+}
+
+pub(crate) fn ms_between(from: Instant, to: Instant) -> f64 {
+    to.duration_since(from).as_secs_f64() * 1000.0
+}
+
+/// Bits per second for `bytes` transferred over `ms` milliseconds.
+fn rate_bps(bytes: u64, ms: f64) -> Option<f64> {
+    (ms > 0.0).then(|| bytes as f64 * 8.0 / (ms / 1000.0))
+}
+
+/// Extract `:status` from a response header block.
+pub(crate) fn parse_status(headers: &[Header]) -> Option<u16> {
+    headers
+        .iter()
+        .find(|hdr| hdr.name() == b":status")
+        .and_then(|hdr| std::str::from_utf8(hdr.value()).ok())
+        .and_then(|s| s.parse::<u16>().ok())
+}
+
+/// Write (what is left of) a request body with FIN, keeping whatever the
+/// stream cannot take yet for `on_stream_writable`.
+fn send_request_body(
+    h3: &mut Http3Connection,
+    conn: &mut Connection,
+    st: &mut H3State,
+    sid: u64,
+    body: Bytes,
+) {
+    let rest = match h3.send_body(conn, sid, body.clone(), true) {
+        Ok(n) if n >= body.len() => None,
+        Ok(n) => Some(body.slice(n..)),
+        // Blocked, or only the header block got out: retry when writable
+        Err(Http3Error::Done | Http3Error::NoError) => Some(body),
+        Err(e) => {
+            debug!("send_body on stream {sid} failed: {e:?}");
+            None
+        }
+    };
+    if let Some(stream) = st.streams.get_mut(&sid) {
+        stream.pending_body = rest;
+    }
+}
+
+/// Lowercase hex encoding of `bytes`.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Caps on the response headers kept per stream, against servers sending
+/// huge or endless header blocks.
+const MAX_RESPONSE_HEADERS: usize = 64;
+const MAX_RESPONSE_HEADER_BYTES: usize = 16 * 1024;
+
+/// Append a header block to `dst`, lowercasing names, until a cap is hit.
+pub(crate) fn capture_headers(dst: &mut ResponseHeaders, headers: &[Header]) {
+    if dst.truncated {
+        return;
+    }
+    let mut bytes: usize = dst.headers.iter().map(|(n, v)| n.len() + v.len()).sum();
+    for hdr in headers {
+        let name = String::from_utf8_lossy(hdr.name()).to_ascii_lowercase();
+        let value = String::from_utf8_lossy(hdr.value()).into_owned();
+        bytes += name.len() + value.len();
+        if dst.headers.len() >= MAX_RESPONSE_HEADERS || bytes > MAX_RESPONSE_HEADER_BYTES {
+            dst.truncated = true;
+            return;
+        }
+        dst.headers.push((name, value));
+    }
+}
+
+/// Cap on the `late_events` kept per connection.
+const MAX_LATE_EVENTS: usize = 32;
+
+/// Note an H3 event that arrived after the last response, e.g. "goaway" or
+/// "headers on stream 0".
+pub(crate) fn record_late_event(dst: &mut Vec<String>, sid: u64, event: &Http3Event) {
+    if dst.len() >= MAX_LATE_EVENTS {
+        return;
+    }
+    dst.push(match event {
+        Http3Event::Headers { .. } => format!("headers on stream {sid}"),
+        Http3Event::Data => format!("data on stream {sid}"),
+        Http3Event::Finished => format!("fin on stream {sid}"),
+        Http3Event::Reset(code) => format!("reset {code:#x} on stream {sid}"),
+        Http3Event::GoAway => "goaway".into(),
+        Http3Event::PriorityUpdate => "priority_update".into(),
+    });
+}
+
+/// A header block on a stream that already has its final (non-1xx) status
+/// and carries no status of its own: the trailer section.
+pub(crate) fn is_trailer_block(status: Option<u16>, headers: &[Header]) -> bool {
+    status.is_some_and(|s| s >= 200) && parse_status(headers).is_none()
+}
+
+/// QPACK decoder limits from the connection config, advertised in SETTINGS.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct QpackLimits {
+    max_table_capacity: Option<u64>,
+    blocked_streams: Option<u64>,
+}
+
+impl QpackLimits {
+    pub(crate) fn new(cfg: &ConnectionConfig) -> Self {
+        Self {
+            max_table_capacity: cfg.qpack_max_table_capacity,
+            blocked_streams: cfg.qpack_blocked_streams,
+        }
+    }
+
+    /// HTTP/3 config with these limits applied (tquic defaults where unset).
+    pub(crate) fn h3_config(&self) -> tquic::h3::Result<Http3Config> {
+        let mut cfg = Http3Config::new()?;
+        if let Some(v) = self.max_table_capacity {
+            cfg.set_qpack_max_table_capacity(v);
+        }
+        if let Some(v) = self.blocked_streams {
+            cfg.set_qpack_blocked_streams(v);
+        }
+        Ok(cfg)
+    }
+
+    /// We let the peer's encoder use a dynamic table.
+    pub(crate) fn dynamic_table(&self) -> bool {
+        self.max_table_capacity.is_some_and(|c| c > 0)
+    }
+}
+
+/// Value of the first header named `name`, as (lossy) UTF-8.
+fn header_value(headers: &[Header], name: &[u8]) -> Option<String> {
+    headers
+        .iter()
+        .find(|hdr| hdr.name() == name)
+        .map(|hdr| String::from_utf8_lossy(hdr.value()).into_owned())
+}
+
+/// `priority` field value (RFC 9218, Section 4), e.g. `u=1, i`.
+fn priority_field(p: &Http3Priority) -> String {
+    if p.incremental {
+        format!("u={}, i", p.urgency)
+    } else {
+        format!("u={}", p.urgency)
+    }
+}
+
+/// Per-request-stream bookkeeping for throughput measurement.
+struct StreamState {
+    path: String,
+    t_open: Instant,
+    t_first_byte: Option<Instant>,
+    t_done: Option<Instant>,
+    bytes: u64,
+    status: Option<u16>,
+    finished: bool,
+    server_priority: Option<String>,
+    server: Option<String>,
+    response_headers: ResponseHeaders,
+    trailers: Option<ResponseHeaders>,
+    /// Body bytes kept so far (only on the stream capturing the body)
+    body_prefix: Option<Vec<u8>>,
+    body_truncated: bool,
+    /// Request body not yet accepted by the stream (flow control)
+    pending_body: Option<Bytes>,
+    /// Warm-up request: reported separately and excluded from the aggregate.
+    warmup: bool,
+}
+
+impl StreamState {
+    /// Append body bytes to the captured prefix, up to `limit` in total;
+    /// anything past it is dropped and flagged, the stream keeps draining.
+    fn keep_body(&mut self, data: &[u8], limit: usize) {
+        let Some(prefix) = self.body_prefix.as_mut() else {
+            return;
+        };
+        let room = limit.saturating_sub(prefix.len());
+        if data.len() > room {
+            self.body_truncated = true;
+        }
+        prefix.extend_from_slice(&data[..data.len().min(room)]);
+    }
+
+    fn to_result(&self, stream_id: u64) -> StreamResult {
+        let duration_ms = self.t_done.map(|t| ms_between(self.t_open, t));
+        StreamResult {
+            stream_id,
+            path: self.path.clone(),
+            status: self.status,
+            bytes: self.bytes,
+            ttfb_ms: self.t_first_byte.map(|t| ms_between(self.t_open, t)),
+            duration_ms,
+            rate_bps: duration_ms.and_then(|ms| rate_bps(self.bytes, ms)),
+            finished: self.finished,
+            server_priority: self.server_priority.clone(),
+            server: self.server.clone(),
+            response_headers: self.response_headers.clone(),
+            trailers: self.trailers.clone(),
+            body_prefix: self.body_prefix.as_deref().map(hex),
+            body_truncated: self.body_truncated,
+        }
+    }
+}
+
+/// State shared between `H3App` and `probe()`; snapshotted into a `ProbeRecord`.
+#[derive(Default)]
+struct H3State {
+    t_handshake_ok_ms: Option<u128>,
+    alpn: Option<String>,
+    /// Picked by `H3App::new` (`user_agent_pool`)
+    user_agent: Option<String>,
+    attempted: bool,
+    alpn_mismatch: bool,
+    /// Local or peer CONNECTION_CLOSE, if any
+    close_error: Option<String>,
+    /// QPACK failure, from the H3 layer or a QPACK close code
+    qpack_error: Option<String>,
+    /// Whether response headers referenced the QPACK dynamic table
+    qpack_dynamic_used: Option<bool>,
+    /// Packets received, taken at connection close
+    packets_recv: Option<u64>,
+    peer_priority_update: bool,
+    streams_requested: u64,
+    /// Measurement streams still to open once concurrency allows
+    streams_pending: u64,
+    peak_concurrent: u64,
+    streams: BTreeMap<u64, StreamState>,
+    /// Measurement streams have been opened (immediately, or after the warm-up).
+    measuring: bool,
+    /// Closed because the other connection of a Happy Eyeballs race won
+    race_lost: bool,
+    /// Events after every request completed (`post_finish_ms`)
+    late_events: Vec<String>,
+}
+
+impl H3State {
+    fn all_done(&self) -> bool {
+        self.streams_pending == 0
+            && !self.streams.is_empty()
+            && self.streams.values().all(|st| st.t_done.is_some())
+    }
+
+    /// Measurement streams opened but not yet done.
+    fn active(&self) -> u64 {
+        self.streams
+            .values()
+            .filter(|st| !st.warmup && st.t_done.is_none())
+            .count() as u64
+    }
+
+    fn to_result(&self) -> Http3Result {
+        let measured = || self.streams.iter().filter(|(_, st)| !st.warmup);
+        let streams: Vec<StreamResult> = measured().map(|(sid, st)| st.to_result(*sid)).collect();
+        let warmup = self
+            .streams
+            .iter()
+            .find(|(_, st)| st.warmup)
+            .map(|(sid, st)| st.to_result(*sid));
+
+        // Aggregate goodput over the span from the first open to the last completed stream.
+        let bytes_total = measured().map(|(_, st)| st.bytes).sum();
+        let first_open = measured().map(|(_, st)| st.t_open).min();
+        let last_done = measured().filter_map(|(_, st)| st.t_done).max();
+        let goodput_bps = match (first_open, last_done) {
+            (Some(a), Some(b)) => rate_bps(bytes_total, ms_between(a, b)),
+            _ => None,
+        };
+
+        Http3Result {
+            attempted: self.attempted,
+            status: streams.first().or(warmup.as_ref()).and_then(|st| st.status),
+            streams_requested: self.streams_requested,
+            streams_opened: streams.len() as u64,
+            peak_concurrent_streams: self.peak_concurrent,
+            streams,
+            warmup,
+            bytes_total,
+            goodput_bps,
+            peer_priority_update: self.peer_priority_update,
+            late_events: self.late_events.clone(),
+        }
+    }
+}
+
+/// How often a racing connection checks whether the other one already won.
+const RACE_POLL: Duration = Duration::from_millis(20);
+
+#[derive(Default)]
+struct RaceState {
+    winner: Option<SocketAddr>,
+    /// Connections whose `run_probe` has returned
+    ended: usize,
+}
+
+/// Handshake race between the connections of a Happy Eyeballs attempt or a
+/// connect race.
+#[derive(Default)]
+struct Race {
+    state: Mutex<RaceState>,
+    changed: Condvar,
+}
+
+impl Race {
+    /// Record `addr` as the winner unless another address won first.
+    fn claim(&self, addr: SocketAddr) -> bool {
+        let mut st = self.state.lock().unwrap();
+        let won = *st.winner.get_or_insert(addr) == addr;
+        self.changed.notify_all();
+        won
+    }
+
+    fn lost(&self, addr: SocketAddr) -> bool {
+        self.state.lock().unwrap().winner.is_some_and(|w| w != addr)
+    }
+
+    fn winner(&self) -> Option<SocketAddr> {
+        self.state.lock().unwrap().winner
+    }
+
+    fn end(&self) {
+        self.state.lock().unwrap().ended += 1;
+        self.changed.notify_all();
+    }
+}
+
+/// An `H3App`'s side of a race, until its handshake completes.
+struct Racer {
+    race: Arc<Race>,
+    addr: SocketAddr,
+    next_check: Instant,
+}
+
 /// HTTP/3 app protocol plugged into the QUIC engine.
 struct H3App {
     host: String,
-    path: String,
     user_agent: String,
+    method: String,
+    /// Sent after the headers of every request, if set
+    request_body: Option<Bytes>,
 
-    h3: Option<Http3Connection>,
-    req_stream: Option<u64>,
+    /// Paths to fetch, assigned round-robin to `parallel_streams` request streams.
+    paths: Vec<String>,
+    parallel_streams: u64,
+    /// In-flight cap for the measurement streams (`parallel_streams` if uncapped)
+    max_concurrent: u64,
+    /// Issue one warm-up GET first and measure on the warmed connection.
+    warmup_request: bool,
+    /// Body bytes to keep from the first request stream (0 = none)
+    capture_body_bytes: usize,
+    qpack: QpackLimits,
+    /// Request priority and how it is signalled (None = no signal)
+    priority: Option<Http3Priority>,
+    priority_signal: PrioritySignal,
 
-    // simple state for result extraction
-    status: Option<u16>,
-    headers_seen: bool,
+    h3: Option<Http3Connection>,
+    linger: CloseLinger,
+    shared: Arc<Mutex<H3State>>,
+    racer: Option<Racer>,
 }
 
 impl H3App {
-    fn new(host: &str, path: &str, user_agent: &str) -> Self {
+    fn new(host: &str, cfg: &ConnectionConfig, shared: Arc<Mutex<H3State>>) -> Self {
+        let paths = if cfg.stream_paths.is_empty() {
+            vec![cfg.path.clone()]
+        } else {
+            cfg.stream_paths.clone()
+        };
+        let user_agent = cfg.pick_user_agent().to_string();
+        shared.lock().unwrap().user_agent = Some(user_agent.clone());
         Self {
             host: host.to_string(),
-            path: path.to_string(),
-            user_agent: user_agent.to_string(),
+            user_agent,
+            method: cfg.method.clone(),
+            request_body: cfg
+                .request_body
+                .as_ref()
+                .map(|b| Bytes::copy_from_slice(b.as_bytes())),
+            paths,
+            parallel_streams: cfg.parallel_streams.max(1),
+            max_concurrent: match cfg.max_concurrent_streams {
+                0 => cfg.parallel_streams.max(1),
+                n => n,
+            },
+            warmup_request: cfg.warmup_request,
+            capture_body_bytes: cfg.capture_body_bytes,
+            qpack: QpackLimits::new(cfg),
+            priority: cfg
+                .priority_urgency
+                .map(|u| Http3Priority::new(u, cfg.priority_incremental)),
+            priority_signal: cfg.priority_signal,
             h3: None,
-            req_stream: None,
-            status: None,
-            headers_seen: false,
+            linger: CloseLinger::new(cfg),
+            shared,
+            racer: None,
+        }
+    }
+
+    /// Take part in `race` as the connection to `addr`: close as soon as the
+    /// other connection completes its handshake first.
+    fn racing(mut self, race: Arc<Race>, addr: SocketAddr) -> Self {
+        self.racer = Some(Racer {
+            race,
+            addr,
+            next_check: Instant::now(),
+        });
+        self
+    }
+
+    /// Open up to `count` GET request streams, assigning paths round-robin.
+    /// Closes the connection if not even the first stream could be opened.
+    fn open_streams(
+        &self,
+        h3: &mut Http3Connection,
+        conn: &mut Connection,
+        st: &mut H3State,
+        count: u64,
+        warmup: bool,
+    ) {
+        // tquic does not expose the peer's MAX_STREAMS, so a failing
+        // stream_new marks the negotiated limit.
+        for _ in 0..count {
+            let opened = st.streams.values().filter(|s| s.warmup == warmup).count();
+            let path = &self.paths[opened % self.paths.len()];
+
+            let new_stream = match &self.priority {
+                Some(p) => h3.stream_new_with_priority(conn, p),
+                None => h3.stream_new(conn),
+            };
+            let sid = match new_stream {
+                Ok(s) => s,
+                Err(e) if opened > 0 => {
+                    debug!(
+                        "[{}] stream limit reached after {opened} streams: {e:?}",
+                        self.host
+                    );
+                    break;
+                }
+                Err(e) => {
+                    error!("http3 stream_new error: {:?}", e);
+                    let _ = conn.close(true, 0x1, b"h3sid");
+                    return;
+                }
+            };
+
+            // Build request headers.
+            let mut headers = vec![
+                Header::new(b":method", self.method.as_bytes()),
+                Header::new(b":scheme", b"https"),
+                Header::new(b":authority", self.host.as_bytes()),
+                Header::new(b":path", path.as_bytes()),
+                Header::new(b"user-agent", self.user_agent.as_bytes()),
+                Header::new(b"accept", b"*/*"),
+            ];
+            let priority = self.priority.as_ref();
+            if let Some(p) = priority.filter(|_| self.priority_signal != PrioritySignal::Frame) {
+                headers.push(Header::new(b"priority", priority_field(p).as_bytes()));
+            }
+
+            let fin = self.request_body.is_none();
+            if let Err(e) = h3.send_headers(conn, sid, &headers, fin) {
+                if opened > 0 {
+                    debug!("[{}] send_headers on stream {sid} failed: {e:?}", self.host);
+                    break;
+                }
+                error!("send_headers error: {:?}", e);
+                let _ = conn.close(true, 0x1, b"hdr");
+                return;
+            }
+
+            // Only the first request of the connection keeps its body
+            let capture = st.streams.is_empty() && self.capture_body_bytes > 0;
+            st.streams.insert(
+                sid,
+                StreamState {
+                    path: path.clone(),
+                    t_open: Instant::now(),
+                    t_first_byte: None,
+                    t_done: None,
+                    bytes: 0,
+                    status: None,
+                    finished: false,
+                    server_priority: None,
+                    server: None,
+                    response_headers: ResponseHeaders::default(),
+                    trailers: None,
+                    body_prefix: capture.then(Vec::new),
+                    body_truncated: false,
+                    pending_body: None,
+                    warmup,
+                },
+            );
+            if let Some(body) = &self.request_body {
+                send_request_body(h3, conn, st, sid, body.clone());
+            }
+
+            let update = priority
+                .filter(|_| self.priority_signal != PrioritySignal::Header)
+                .map(|p| h3.send_priority_update_for_request(conn, sid, p));
+            if let Some(Err(e)) = update {
+                debug!(
+                    "[{}] PRIORITY_UPDATE for stream {sid} failed: {e:?}",
+                    self.host
+                );
+            }
         }
     }
+
+    /// Open pending measurement streams as far as `max_concurrent` allows.
+    /// Hitting the peer's stream limit ends the measurement with what is open.
+    fn fill_streams(&self, h3: &mut Http3Connection, conn: &mut Connection, st: &mut H3State) {
+        let want = self
+            .max_concurrent
+            .saturating_sub(st.active())
+            .min(st.streams_pending);
+        if want > 0 {
+            let before = st.streams.len() as u64;
+            self.open_streams(h3, conn, st, want, false);
+            let opened = st.streams.len() as u64 - before;
+            st.streams_pending = if opened < want {
+                0
+            } else {
+                st.streams_pending - opened
+            };
+        }
+        st.peak_concurrent = st.peak_concurrent.max(st.active());
+    }
 }
 
-impl AppProtocol for H3App {
-    fn on_connected(&mut self, conn: &mut Connection) {
-        // Initialize H3 over QUIC and send a minimal GET request.
-        let h3_cfg = match Http3Config::new() {
+impl H3App {
+    /// Initialize H3 over QUIC and send the request(s): at handshake
+    /// completion, or earlier in 0-RTT.
+    fn start(&mut self, conn: &mut Connection, st: &mut H3State) {
+        st.attempted = true;
+        let h3_cfg = match self.qpack.h3_config() {
             Ok(c) => c,
             Err(e) => {
                 error!("http3 config error: {:?}", e);
@@ -59,38 +582,53 @@ impl AppProtocol for H3App {
             }
         };
 
-        let sid = match h3.stream_new(conn) {
-            Ok(s) => s,
-            Err(e) => {
-                error!("http3 stream_new error: {:?}", e);
-                let _ = conn.close(true, 0x1, b"h3sid");
-                return;
-            }
-        };
+        st.streams_requested = self.parallel_streams;
+        if self.warmup_request {
+            self.open_streams(&mut h3, conn, st, 1, true);
+        } else {
+            st.measuring = true;
+            st.streams_pending = self.parallel_streams;
+            self.fill_streams(&mut h3, conn, st);
+        }
 
-        // Build request headers.
-        let headers = [
-            Header::new(b":method", b"GET"),
-            Header::new(b":scheme", b"https"),
-            Header::new(b":authority", self.host.as_bytes()),
-            Header::new(b":path", self.path.as_bytes()),
-            Header::new(b"user-agent", self.user_agent.as_bytes()),
-            Header::new(b"accept", b"*/*"),
-        ];
+        self.h3 = Some(h3);
+    }
+}
 
-        if let Err(e) = h3.send_headers(conn, sid, &headers, true /* fin: no body */) {
-            error!("send_headers error: {:?}", e);
-            let _ = conn.close(true, 0x1, b"hdr");
+impl AppProtocol for H3App {
+    fn on_connected(&mut self, conn: &mut Connection) {
+        let shared = self.shared.clone();
+        let mut st = shared.lock().unwrap();
+        st.t_handshake_ok_ms = Some(epoch_ms());
+        let alpn = conn.application_proto();
+        if !alpn.is_empty() {
+            st.alpn = Some(String::from_utf8_lossy(alpn).into_owned());
+        }
+        if let Some(r) = self.racer.take().filter(|r| !r.race.claim(r.addr)) {
+            debug!("[{}] {} lost the race", self.host, r.addr);
+            st.race_lost = true;
+            let _ = conn.close(true, 0x00, b"race");
             return;
         }
+        // Already running if the requests went out as 0-RTT
+        if self.h3.is_none() {
+            self.start(conn, &mut st);
+        }
+    }
 
-        self.h3 = Some(h3);
-        self.req_stream = Some(sid);
+    fn on_early_data(&mut self, conn: &mut Connection) {
+        // No requests on a connection that may still lose the race
+        if self.racer.is_some() {
+            return;
+        }
+        let shared = self.shared.clone();
+        let mut st = shared.lock().unwrap();
+        self.start(conn, &mut st);
     }
 
     fn on_stream_readable(&mut self, conn: &mut Connection, _stream_id: u64) {
         // Drive H3 by polling events until Done.
-        let Some(h3) = self.h3.as_mut() else {
+        let Some(mut h3) = self.h3.take() else {
             return;
         };
 
@@ -100,61 +638,415 @@ impl AppProtocol for H3App {
                 Err(e) => {
                     // Http3Error::Done => no more events now.
                     debug!("h3.poll: {:?}", e);
+                    let mut st = self.shared.lock().unwrap();
+                    if let Some(q) = errors::qpack_h3_error(&e) {
+                        st.qpack_error.get_or_insert(q);
+                    }
+                    // tquic's decoder is static-table only: with a dynamic
+                    // table advertised, a decode failure is a dynamic reference
+                    if matches!(e, Http3Error::QpackDecompressionFailed)
+                        && self.qpack.dynamic_table()
+                    {
+                        st.qpack_dynamic_used = Some(true);
+                    }
                     break;
                 }
             };
 
             let (sid, event) = ev;
+            let mut st = self.shared.lock().unwrap();
+            if self.linger.is_done() {
+                record_late_event(&mut st.late_events, sid, &event);
+            }
             match event {
                 Http3Event::Headers { headers, fin } => {
-                    // extract :status
-                    for hdr in headers.iter() {
-                        if hdr.name() == b":status" {
-                            if let Ok(s) = std::str::from_utf8(hdr.value()) {
-                                if let Ok(code) = s.parse::<u16>() {
-                                    self.status = Some(code);
-                                }
-                            }
+                    // Decoded, so static/literal representations only
+                    st.qpack_dynamic_used.get_or_insert(false);
+                    if let Some(stream) = st.streams.get_mut(&sid) {
+                        if is_trailer_block(stream.status, &headers) {
+                            capture_headers(stream.trailers.get_or_insert_default(), &headers);
                         }
+                        stream.status = parse_status(&headers).or(stream.status);
+                        stream.server_priority =
+                            header_value(&headers, b"priority").or(stream.server_priority.take());
+                        stream.server = header_value(&headers, b"server").or(stream.server.take());
+                        capture_headers(&mut stream.response_headers, &headers);
                     }
-                    self.headers_seen = true;
 
                     // if headers carried FIN, there is no body
                     if fin {
+                        if let Some(stream) = st.streams.get_mut(&sid) {
+                            stream.t_done.get_or_insert_with(Instant::now);
+                            stream.finished = true;
+                        }
                         let _ = h3.stream_close(conn, sid);
-                        let _ = conn.close(true, 0x00, b"ok");
                     }
                 }
 
                 Http3Event::Data => {
-                    // drain body
+                    // drain body, counting bytes per stream
                     let mut buf = [0u8; 8192];
                     loop {
                         match h3.recv_body(conn, sid, &mut buf) {
                             Ok(0) => break,
-                            Ok(_n) => { /* discard */ }
+                            Ok(n) => {
+                                if let Some(stream) = st.streams.get_mut(&sid) {
+                                    stream.t_first_byte.get_or_insert_with(Instant::now);
+                                    stream.bytes += n as u64;
+                                    stream.keep_body(&buf[..n], self.capture_body_bytes);
+                                }
+                            }
                             Err(_e) => break, // Done or error
                         }
                     }
                 }
 
                 Http3Event::Finished => {
+                    if let Some(stream) = st.streams.get_mut(&sid) {
+                        stream.t_done.get_or_insert_with(Instant::now);
+                        stream.finished = true;
+                    }
                     let _ = h3.stream_close(conn, sid);
-                    let _ = conn.close(true, 0x00, b"ok");
+                }
+
+                Http3Event::Reset(code) => {
+                    debug!("[{}] stream {sid} reset by peer: {code:#x}", self.host);
+                    if let Some(stream) = st.streams.get_mut(&sid) {
+                        stream.t_done.get_or_insert_with(Instant::now);
+                    }
                 }
 
                 _ => { /* ignore other events for probing */ }
             }
+
+            if st.measuring {
+                self.fill_streams(&mut h3, conn, &mut st);
+            }
+            if !st.all_done() {
+                continue;
+            }
+            if st.measuring {
+                // Close once every request stream has completed; keep
+                // draining events that are already queued.
+                self.linger.close(conn);
+                continue;
+            }
+            // Warm-up done: measure on the now-warm connection.
+            st.measuring = true;
+            st.streams_pending = self.parallel_streams;
+            self.fill_streams(&mut h3, conn, &mut st);
         }
+
+        self.h3 = Some(h3);
     }
 
-    fn on_stream_writable(&mut self, _conn: &mut Connection, _stream_id: u64) { /* Not used for GET without body. */
+    fn on_stream_writable(&mut self, conn: &mut Connection, stream_id: u64) {
+        // Only request bodies that did not fit at once are written here.
+        let Some(h3) = self.h3.as_mut() else {
+            return;
+        };
+        let mut st = self.shared.lock().unwrap();
+        let pending = st
+            .streams
+            .get_mut(&stream_id)
+            .and_then(|s| s.pending_body.take());
+        if let Some(body) = pending {
+            send_request_body(h3, conn, &mut st, stream_id, body);
+        }
     }
 
     fn on_stream_closed(&mut self, _conn: &mut Connection, _stream_id: u64) {}
 
-    fn on_conn_closed(&mut self, _conn: &mut Connection) {
-        debug!("h3 finished, status = {:?}", self.status);
+    fn timeout(&self) -> Option<std::time::Duration> {
+        let race_check = self
+            .racer
+            .as_ref()
+            .map(|r| r.next_check.saturating_duration_since(Instant::now()));
+        [self.linger.timeout(), race_check]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    fn on_timeout(&mut self, conn: &mut Connection) {
+        self.linger.on_timeout(conn);
+        let now = Instant::now();
+        let Some(r) = self.racer.as_mut().filter(|r| r.next_check <= now) else {
+            return;
+        };
+        if !r.race.lost(r.addr) {
+            r.next_check = now + RACE_POLL;
+            return;
+        }
+        debug!("[{}] {} lost the race", self.host, r.addr);
+        self.racer = None;
+        self.shared.lock().unwrap().race_lost = true;
+        let _ = conn.close(true, 0x00, b"race");
+    }
+
+    fn on_conn_closed(&mut self, conn: &mut Connection) {
+        let mut st = self.shared.lock().unwrap();
+        st.alpn_mismatch = errors::alpn_mismatch(conn).is_some();
+        st.packets_recv = Some(conn.stats().recv_count);
+        st.peer_priority_update = errors::peer_priority_update(conn);
+        st.close_error = conn
+            .local_error()
+            .or(conn.peer_error())
+            .map(|e| format!("{e:?}"));
+        if let Some(q) = errors::qpack_close(conn) {
+            st.qpack_error.get_or_insert(q);
+        }
+        debug!(
+            "h3 finished, status = {:?}, streams = {}",
+            st.streams.values().next().and_then(|s| s.status),
+            st.streams.len()
+        );
+    }
+}
+
+/// Record for an attempt that never connected (resolution failed, or the host
+/// deadline ran out first). `resolved` is the host's latest resolution, if
+/// any: a deadline hit after resolving keeps its resolver and address counts.
+fn unprobed_record(
+    host: &str,
+    att: &ConnectionConfig,
+    resolved: Option<&Resolved>,
+    category: FailureCategory,
+    error: String,
+    prior_errors: Vec<PriorError>,
+) -> ProbeRecord {
+    let t_ms = epoch_ms();
+    ProbeRecord {
+        host: host.to_string(),
+        fam: family_label(att.ip_version).to_string(),
+        peer_addr: String::new(),
+        resolver: resolved.map_or_else(|| final_resolver(att), |r| r.resolver),
+        dns_stale: resolved.is_some_and(|r| r.stale),
+        skipped_bogon: resolved.is_some_and(|r| r.skipped_bogon),
+        addrs_available: resolved.map_or(0, |r| r.available),
+        addrs_selected: resolved.map_or(0, |r| r.targets.len()),
+        t_start_ms: t_ms,
+        t_handshake_ok_ms: None,
+        t_end_ms: t_ms,
+        resolved: resolved.is_some(),
+        handshake_ok: false,
+        category: Some(category),
+        host_deadline_hit: false,
+        retry: 0,
+        retry_next_addr: None,
+        user_agent: None,
+        alpn_offered: Vec::new(),
+        alpn: None,
+        http3: H3State::default().to_result(),
+        error: Some(error),
+        prior_errors,
+        race: None,
+        alpn_mismatch: false,
+        qpack_error: None,
+        qpack_dynamic_used: None,
+        cfg: att.clone(),
+    }
+}
+
+fn write_unprobed(
+    recorder: &Recorder,
+    host: &str,
+    record: &ProbeRecord,
+    tally: &mut HostTally,
+    kept: Option<&mut Vec<ProbeRecord>>,
+) {
+    if let Some(kept) = kept {
+        kept.push(record.clone());
+    }
+    if let Err(we) = recorder.write_for_key(host, record) {
+        error!("[{}] failed to write probe record: {we}", host);
+    }
+    if let Err(we) = recorder.write_error_for_key(host, record) {
+        error!("[{}] failed to write error record: {we}", host);
+    }
+    *tally = HostTally {
+        failure: record.category,
+        ..HostTally::default()
+    };
+}
+
+/// Write the `host_deadline` marker record and return the error to abort with.
+#[allow(clippy::too_many_arguments)]
+fn host_deadline_hit(
+    host: &str,
+    att: &ConnectionConfig,
+    resolved: Option<&Resolved>,
+    deadline: &HostDeadline,
+    prior_errors: Vec<PriorError>,
+    recorder: &Recorder,
+    tally: &mut HostTally,
+    kept: Option<&mut Vec<ProbeRecord>>,
+) -> anyhow::Error {
+    let e = deadline.error();
+    let mut record = unprobed_record(
+        host,
+        att,
+        resolved,
+        FailureCategory::HostDeadline,
+        format!("{e:?}"),
+        prior_errors,
+    );
+    record.host_deadline_hit = true;
+    write_unprobed(recorder, host, &record, tally, kept);
+    e
+}
+
+/// One connection made for an attempt, before it becomes a `ProbeRecord`.
+struct Conn {
+    addr: SocketAddr,
+    t_start_ms: u128,
+    shared: Arc<Mutex<H3State>>,
+    res: quic::Result<()>,
+    /// Made as part of a Happy Eyeballs or connect race
+    raced: bool,
+}
+
+/// The IPv6 and IPv4 address to race for `att`, if Happy Eyeballs applies.
+fn race_pair(
+    att: &ConnectionConfig,
+    targets: &[(IpVersion, SocketAddr)],
+) -> Option<(SocketAddr, SocketAddr)> {
+    if att.happy_eyeballs_delay_ms == 0 || !matches!(att.ip_version, IpVersion::Auto) {
+        return None;
+    }
+    let v6 = targets.iter().find(|(f, _)| matches!(f, IpVersion::Ipv6))?;
+    let v4 = targets.iter().find(|(f, _)| matches!(f, IpVersion::Ipv4))?;
+    Some((v6.1, v4.1))
+}
+
+/// Happy Eyeballs: connect to `v6`, and also to `v4` unless `v6` completed
+/// its handshake within `happy_eyeballs_delay_ms` (a failed `v6` starts `v4`
+/// at once). Returns the connections made and the address that won, if any.
+#[allow(clippy::too_many_arguments)]
+fn race(
+    host: &str,
+    v6: SocketAddr,
+    v4: SocketAddr,
+    scheduler_config: &SchedulerConfig,
+    deadline: &HostDeadline,
+    io_config: &IOConfig,
+    general_config: &GeneralConfig,
+    att: &ConnectionConfig,
+    rl: &RateLimit,
+    recorder: &Recorder,
+) -> (Vec<Conn>, Option<SocketAddr>) {
+    let race = Arc::new(Race::default());
+    let connect = |addr| race_conn(host, addr, &race, io_config, general_config, att, recorder);
+
+    let conns = std::thread::scope(|s| {
+        let first = s.spawn(|| connect(v6));
+        let delay = Duration::from_millis(att.happy_eyeballs_delay_ms);
+        let st = race.state.lock().unwrap();
+        let (st, _) = race
+            .changed
+            .wait_timeout_while(st, delay, |st| st.winner.is_none() && st.ended == 0)
+            .unwrap();
+        let start_v4 = st.winner.is_none();
+        drop(st);
+        let second = (start_v4
+            && rl
+                .until_ready_timeout(deadline.clamp(scheduler_config.throttle_max_wait()))
+                .is_ok())
+        .then(|| {
+            debug!("[{}] no handshake with {} yet, racing {}", host, v6, v4);
+            s.spawn(|| connect(v4))
+        });
+        [Some(first), second]
+            .into_iter()
+            .flatten()
+            .map(|t| t.join().unwrap_or_else(|p| std::panic::resume_unwind(p)))
+            .collect()
+    });
+    (conns, race.winner())
+}
+
+/// Connect race: connect to all `addrs` at once (each extra one after its
+/// rate-limit token, none once a handshake completed) and keep the first
+/// handshake. Returns the connections made and the address that won, if any.
+#[allow(clippy::too_many_arguments)]
+fn race_all(
+    host: &str,
+    addrs: &[SocketAddr],
+    scheduler_config: &SchedulerConfig,
+    deadline: &HostDeadline,
+    io_config: &IOConfig,
+    general_config: &GeneralConfig,
+    att: &ConnectionConfig,
+    rl: &RateLimit,
+    recorder: &Recorder,
+) -> (Vec<Conn>, Option<SocketAddr>) {
+    let race = Arc::new(Race::default());
+    let connect = |addr| race_conn(host, addr, &race, io_config, general_config, att, recorder);
+
+    let conns = std::thread::scope(|s| {
+        let threads: Vec<_> = addrs
+            .iter()
+            .enumerate()
+            .map_while(|(i, &addr)| {
+                // The first address already holds a token
+                let start = i == 0
+                    || (race.winner().is_none()
+                        && rl
+                            .until_ready_timeout(
+                                deadline.clamp(scheduler_config.throttle_max_wait()),
+                            )
+                            .is_ok());
+                start.then(|| s.spawn(move || connect(addr)))
+            })
+            .collect();
+        debug!("[{}] racing {} connections", host, threads.len());
+        threads
+            .into_iter()
+            .map(|t| t.join().unwrap_or_else(|p| std::panic::resume_unwind(p)))
+            .collect()
+    });
+    (conns, race.winner())
+}
+
+/// `race` of a raced connection's record: the race's winner (if any), the
+/// connections it started, and whether this one lost.
+fn race_result(winner: Option<SocketAddr>, raced: usize, lost: bool) -> RaceResult {
+    RaceResult {
+        winner: winner.map(|w| {
+            let fam = if w.is_ipv6() {
+                IpVersion::Ipv6
+            } else {
+                IpVersion::Ipv4
+            };
+            family_label(fam).to_string()
+        }),
+        winner_addr: winner.map(|w| w.to_string()),
+        raced,
+        lost,
+    }
+}
+
+/// One connection taking part in `race`.
+fn race_conn(
+    host: &str,
+    addr: SocketAddr,
+    race: &Arc<Race>,
+    io_config: &IOConfig,
+    general_config: &GeneralConfig,
+    att: &ConnectionConfig,
+    recorder: &Recorder,
+) -> Conn {
+    let t_start_ms = epoch_ms();
+    let shared = Arc::new(Mutex::new(H3State::default()));
+    let app = H3App::new(host, att, shared.clone()).racing(race.clone(), addr);
+    let res = run_probe(host, &addr, io_config, general_config, att, recorder, app);
+    race.end();
+    Conn {
+        addr,
+        t_start_ms,
+        shared,
+        res,
+        raced: true,
     }
 }
 
@@ -168,37 +1060,651 @@ pub fn probe(
     rl: &RateLimit,
     recorder: &Recorder,
 ) -> Result<()> {
-    for (idx, att) in connection_configs.iter().enumerate() {
-        // Centralized resolution
-        let targets = resolve_targets(host, att.port, att.ip_version)?;
+    probe_records(
+        host,
+        scheduler_config,
+        io_config,
+        general_config,
+        connection_configs,
+        rl,
+        recorder,
+        None,
+    )
+}
+
+/// What probing a host came to, for the accounting done once per host: its
+/// `global_retry_rounds` outcome, summary counts and triage row.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct HostTally {
+    /// Category of the latest failed connection; `None` once a config succeeded
+    pub failure: Option<FailureCategory>,
+    /// Last HTTP status seen
+    pub status: Option<u16>,
+    /// Handshake time of the last connection
+    pub handshake_ms: Option<u64>,
+}
+
+impl HostTally {
+    /// The tally of a host probed twice (the compare arms): the first run
+    /// that succeeded, else the first.
+    pub(crate) fn either(a: Self, b: Self) -> Self {
+        if a.failure.is_some() && b.failure.is_none() {
+            b
+        } else {
+            a
+        }
+    }
+
+    /// Account for `host`: retry outcome, summary counts and triage row.
+    pub(crate) fn record(&self, host: &str) {
+        retry::record_outcome(host, self.failure);
+        summary::record_outcome(self.failure);
+        if let (None, Some(status)) = (self.failure, self.status) {
+            summary::record_status(status);
+        }
+        if let Err(e) = triage::record(host, self.status, self.handshake_ms, self.failure) {
+            error!("[{}] failed to write triage row: {e:#}", host);
+        }
+    }
+}
+
+/// [`probe`], also appending every `ProbeRecord` written to `kept`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn probe_records(
+    host: &str,
+    scheduler_config: &SchedulerConfig,
+    io_config: &IOConfig,
+    general_config: &GeneralConfig,
+    connection_configs: &[ConnectionConfig],
+    rl: &RateLimit,
+    recorder: &Recorder,
+    kept: Option<&mut Vec<ProbeRecord>>,
+) -> Result<()> {
+    let mut tally = HostTally::default();
+    let res = probe_configs(
+        host,
+        scheduler_config,
+        io_config,
+        general_config,
+        connection_configs,
+        rl,
+        recorder,
+        &HostDeadline::start(scheduler_config.host_deadline_ms),
+        &mut tally,
+        kept,
+    );
+    if !res.as_ref().is_err_and(throttle::is_cancelled) {
+        tally.record(host);
+    }
+    res
+}
+
+/// [`probe_records`] against a `deadline` the caller started, leaving the
+/// per-host accounting to the caller: `tally` gets what the probe came to.
+/// A rate-limit wait cancelled by an interrupt ends the probe without a
+/// record for it (see [`throttle::is_cancelled`]).
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn probe_configs(
+    host: &str,
+    scheduler_config: &SchedulerConfig,
+    io_config: &IOConfig,
+    general_config: &GeneralConfig,
+    connection_configs: &[ConnectionConfig],
+    rl: &RateLimit,
+    recorder: &Recorder,
+    deadline: &HostDeadline,
+    tally: &mut HostTally,
+    mut kept: Option<&mut Vec<ProbeRecord>>,
+) -> Result<()> {
+    // Whether any attempt got a 2xx/3xx response
+    let mut http_ok = false;
+    let mut prior_errors: Vec<PriorError> = Vec::new();
+    // Retries of the current config so far (`max_retries`)
+    let mut retries = 0;
+    let mut idx = 0;
+    // Latest resolution, for records of attempts that never connected
+    let mut last_resolved: Option<Resolved> = None;
+
+    while let Some(att) = connection_configs.get(idx) {
+        if deadline.expired() {
+            return Err(host_deadline_hit(
+                host,
+                att,
+                last_resolved.as_ref(),
+                deadline,
+                prior_errors,
+                recorder,
+                tally,
+                kept.as_deref_mut(),
+            ));
+        }
+
+        // Centralized resolution; a failure still gets a record
+        let resolved = match resolve_targets(host, att) {
+            Ok(r) => r,
+            Err(e) => {
+                let skipped_bogon = e.is::<BogonSkipped>();
+                let category = if skipped_bogon {
+                    FailureCategory::SkippedBogon
+                } else {
+                    FailureCategory::ResolveFailed
+                };
+                let mut record =
+                    unprobed_record(host, att, None, category, format!("{e:?}"), prior_errors);
+                record.skipped_bogon = skipped_bogon;
+                write_unprobed(recorder, host, &record, tally, kept.as_deref_mut());
+                return Err(e);
+            }
+        };
 
         let mut attempt_succeeded = false;
+        // Try no further addresses (raced connections still get their records)
+        let mut stop = false;
+
+        // A raced pair goes first (IPv6, IPv4), any other address after it
+        let mut targets = resolved.targets.clone();
+        let pair = race_pair(att, &targets);
+        if let Some((v6, v4)) = pair {
+            targets.retain(|&(_, a)| a != v6 && a != v4);
+            targets.splice(0..0, [(IpVersion::Ipv6, v6), (IpVersion::Ipv4, v4)]);
+        }
+        let mut race_v4 = pair.map(|(_, v4)| v4);
+        // Without Happy Eyeballs, the first `connect_race_width` addresses
+        // race each other
+        let mut race_width = match pair {
+            None if att.connect_race_width > 1 => att.connect_race_width.min(targets.len()),
+            _ => 0,
+        };
+        let mut raced: Vec<Conn> = Vec::new();
+        let mut race_winner = None;
+        let mut race_size = 0;
+
+        for (pos, &(fam_eff, addr)) in targets.iter().enumerate() {
+            let pending = raced.iter().position(|c| c.addr == addr);
+            let conn = match pending {
+                Some(i) => raced.swap_remove(i),
+                None if stop => break,
+                None => {
+                    if deadline.expired() {
+                        return Err(host_deadline_hit(
+                            host,
+                            att,
+                            Some(&resolved),
+                            deadline,
+                            prior_errors,
+                            recorder,
+                            tally,
+                            kept.as_deref_mut(),
+                        ));
+                    }
+                    if let Err(e) =
+                        rl.until_ready_timeout(deadline.clamp(scheduler_config.throttle_max_wait()))
+                    {
+                        if deadline.expired() {
+                            return Err(host_deadline_hit(
+                                host,
+                                att,
+                                Some(&resolved),
+                                deadline,
+                                prior_errors,
+                                recorder,
+                                tally,
+                                kept.as_deref_mut(),
+                            ));
+                        }
+                        // Interrupted: not probed, so no record or outcome
+                        if e == NotReady::Cancelled {
+                            return Err(e.into());
+                        }
+                        let record = unprobed_record(
+                            host,
+                            att,
+                            Some(&resolved),
+                            FailureCategory::LocalError,
+                            e.to_string(),
+                            prior_errors,
+                        );
+                        write_unprobed(recorder, host, &record, tally, kept.as_deref_mut());
+                        return Err(e.into());
+                    }
+
+                    if let Some(v4) = race_v4.take() {
+                        let winner;
+                        (raced, winner) = race(
+                            host,
+                            addr,
+                            v4,
+                            scheduler_config,
+                            deadline,
+                            io_config,
+                            general_config,
+                            att,
+                            rl,
+                            recorder,
+                        );
+                        race_winner = winner;
+                        race_size = raced.len();
+                        let i = raced.iter().position(|c| c.addr == addr).unwrap();
+                        raced.swap_remove(i)
+                    } else if race_width > 1 {
+                        let addrs: Vec<SocketAddr> = targets[..std::mem::take(&mut race_width)]
+                            .iter()
+                            .map(|&(_, a)| a)
+                            .collect();
+                        let winner;
+                        (raced, winner) = race_all(
+                            host,
+                            &addrs,
+                            scheduler_config,
+                            deadline,
+                            io_config,
+                            general_config,
+                            att,
+                            rl,
+                            recorder,
+                        );
+                        race_winner = winner;
+                        race_size = raced.len();
+                        let i = raced.iter().position(|c| c.addr == addr).unwrap();
+                        raced.swap_remove(i)
+                    } else {
+                        // Build the HTTP/3 app and open a QUIC connection that will drive it.
+                        let t_start_ms = epoch_ms();
+                        let shared = Arc::new(Mutex::new(H3State::default()));
+                        let app = H3App::new(host, att, shared.clone());
+
+                        let res =
+                            run_probe(host, &addr, io_config, general_config, att, recorder, app);
+                        Conn {
+                            addr,
+                            t_start_ms,
+                            shared,
+                            res,
+                            raced: false,
+                        }
+                    }
+                }
+            };
+            let Conn {
+                t_start_ms,
+                shared,
+                res,
+                raced: was_raced,
+                ..
+            } = conn;
 
-        for (_fam_eff, addr) in targets {
-            rl.until_ready();
+            let st = shared.lock().unwrap();
+            let handshake_ok = st.t_handshake_ok_ms.is_some();
+            // A race loser was closed by us, it did not fail
+            let category =
+                FailureCategory::classify(handshake_ok, st.packets_recv).filter(|_| !st.race_lost);
+            // Move on to the next address (typically the other family) only
+            // when it may help: not after the peer itself rejected us.
+            let retry_next_addr =
+                (pos + 1 < targets.len() && !handshake_ok && !was_raced).then(|| match category {
+                    Some(FailureCategory::NoResponse) => att.timeout_is_retryable,
+                    Some(FailureCategory::HandshakeFailed) => false,
+                    _ => res.is_err(),
+                });
+            let race = was_raced.then(|| race_result(race_winner, race_size, st.race_lost));
+            let record = ProbeRecord {
+                host: host.to_string(),
+                fam: family_label(fam_eff).to_string(),
+                peer_addr: addr.to_string(),
+                resolver: resolved.resolver,
+                dns_stale: resolved.stale,
+                skipped_bogon: resolved.skipped_bogon,
+                addrs_available: resolved.available,
+                addrs_selected: targets.len(),
+                t_start_ms,
+                t_handshake_ok_ms: st.t_handshake_ok_ms,
+                t_end_ms: epoch_ms(),
+                resolved: true,
+                handshake_ok,
+                category,
+                host_deadline_hit: false,
+                retry: retries,
+                retry_next_addr,
+                user_agent: st.user_agent.clone(),
+                alpn_offered: att.alpn_for(&addr).to_vec(),
+                alpn: st.alpn.clone(),
+                http3: st.to_result(),
+                error: res.as_ref().err().map(|e| format!("{e:?}")),
+                prior_errors: prior_errors.clone(),
+                race,
+                alpn_mismatch: st.alpn_mismatch,
+                qpack_error: st.qpack_error.clone(),
+                qpack_dynamic_used: st.qpack_dynamic_used,
+                cfg: att.clone(),
+            };
+            if let Some(kept) = kept.as_deref_mut() {
+                kept.push(record.clone());
+            }
+            if let Err(e) = recorder.write_for_key(host, &record) {
+                error!("[{}] failed to write probe record: {e}", host);
+            }
+            if st.race_lost {
+                continue;
+            }
+            let failed = record.error.is_some() || record.category.is_some();
+            tally.failure = record.category.or(record
+                .error
+                .is_some()
+                .then_some(FailureCategory::LocalError));
+            if let Err(e) = failed
+                .then(|| recorder.write_error_for_key(host, &record))
+                .transpose()
+            {
+                error!("[{}] failed to write error record: {e}", host);
+            }
+
+            let server = record
+                .http3
+                .streams
+                .iter()
+                .chain(&record.http3.warmup)
+                .find_map(|s| s.server.as_deref());
+            if let Some(server) = server {
+                summary::record_server(server);
+            }
 
-            // Build the HTTP/3 app and open a QUIC connection that will drive it.
-            let app = H3App::new(host, &att.path, &att.user_agent);
+            by_ip::record(
+                addr.ip(),
+                HostOutcome {
+                    host: host.to_string(),
+                    handshake_ok: record.t_handshake_ok_ms.is_some(),
+                    alpn: record.alpn.clone(),
+                    status: record.http3.status,
+                },
+            );
 
-            if let Err(e) = run_probe(host, &addr, io_config, general_config, att, recorder, app) {
+            // A failed attempt becomes context for whatever is tried next
+            let failure = match &record.error {
+                Some(e) => Some(e.clone()),
+                None if record.category == Some(FailureCategory::NoResponse) => {
+                    Some("no response".into())
+                }
+                None if st.t_handshake_ok_ms.is_none() => Some(
+                    st.close_error
+                        .clone()
+                        .unwrap_or_else(|| "handshake failed".into()),
+                ),
+                None => None,
+            };
+            if let Some(error) = failure {
+                prior_errors.push(PriorError {
+                    attempt: idx,
+                    fam: record.fam.clone(),
+                    peer_addr: record.peer_addr.clone(),
+                    error,
+                });
+            }
+
+            tally.status = record.http3.status.or(tally.status);
+            tally.handshake_ms = record
+                .t_handshake_ok_ms
+                .map(|t| t.saturating_sub(record.t_start_ms) as u64);
+            http_ok |= matches!(record.http3.status, Some(200..=399));
+
+            if st.alpn_mismatch && att.alpn_mismatch_is_final {
+                debug!(
+                    "[{}] ALPN mismatch on {}, skipping other addresses",
+                    host, addr
+                );
+                stop = true;
+                continue;
+            }
+
+            if let Err(e) = res {
                 error!("[{}] connect {} err: {e:?}", host, addr);
                 continue;
             }
 
-            // If we reached here cleanly, count as success for this address.
-            attempt_succeeded = true;
-            break;
+            if retry_next_addr == Some(true) {
+                debug!("[{}] no response from {}, trying next address", host, addr);
+                continue;
+            }
+
+            // A failed racer leaves the rest to the other racers, and to the
+            // addresses after the race if none of them wins
+            if was_raced && !handshake_ok {
+                continue;
+            }
+
+            // Done with this config: a success only if a handshake completed
+            // (a timed-out handshake also ends without a transport error)
+            attempt_succeeded |= handshake_ok;
+            stop = true;
         }
+        last_resolved = Some(resolved);
 
         if attempt_succeeded {
+            tally.failure = None;
             break;
-        } else if idx + 1 < connection_configs.len() && scheduler_config.inter_attempt_delay_ms > 0
+        }
+        // Every address failed transiently: try the same config again (the
+        // rate limiter still paces each new connection)
+        if ProbeOutcome::from_failure(tally.failure).retryable
+            && retries < scheduler_config.max_retries
+            && !budget::exhausted()
         {
-            std::thread::sleep(std::time::Duration::from_millis(
+            retries += 1;
+            let backoff = Duration::from_millis(
+                scheduler_config
+                    .retry_backoff_ms
+                    .saturating_mul(1 << (retries - 1).min(16)),
+            );
+            debug!(
+                "[{}] config {} failed transiently, retry {} in {:?}",
+                host, idx, retries, backoff
+            );
+            std::thread::sleep(deadline.clamp(backoff));
+            continue;
+        }
+        retries = 0;
+        idx += 1;
+        if idx < connection_configs.len() && scheduler_config.inter_attempt_delay_ms > 0 {
+            std::thread::sleep(deadline.clamp(sample_delay(
+                scheduler_config.delay_distribution,
                 scheduler_config.inter_attempt_delay_ms,
-            ));
+            )));
         }
     }
+    if general_config.http_status_counts_as_failure && !http_ok {
+        return Err(match tally.status {
+            Some(status) => anyhow!("HTTP status {status}"),
+            None => anyhow!("no HTTP response"),
+        });
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+
+    /// General config writing no files.
+    fn no_files() -> GeneralConfig {
+        GeneralConfig {
+            save_recorder_files: false,
+            save_error_records: false,
+            save_qlog_files: false,
+            save_keylog_files: false,
+            save_session_files: false,
+            ..GeneralConfig::default()
+        }
+    }
+
+    #[test]
+    fn race_result_names_winner_family_and_address() {
+        let v6: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+        let r = race_result(Some(v6), 3, true);
+        assert_eq!(r.winner.as_deref(), Some(family_label(IpVersion::Ipv6)));
+        assert_eq!(r.winner_addr.as_deref(), Some("[2001:db8::1]:443"));
+        assert_eq!((r.raced, r.lost), (3, true));
+
+        let none = race_result(None, 2, false);
+        assert_eq!((none.winner, none.winner_addr, none.raced), (None, None, 2));
+    }
+
+    #[test]
+    fn race_all_without_winner_keeps_every_connection() {
+        // Sockets that never answer: no racer completes a handshake
+        let silent: Vec<UdpSocket> = (0..2)
+            .map(|_| UdpSocket::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let addrs: Vec<SocketAddr> = silent.iter().map(|s| s.local_addr().unwrap()).collect();
+        let att = ConnectionConfig {
+            max_idle_timeout_ms: 200,
+            ..ConnectionConfig::default()
+        };
+        let scheduler = SchedulerConfig::default();
+        let recorder = Recorder::new(std::env::temp_dir(), false, false).unwrap();
+
+        let (conns, winner) = race_all(
+            "silent.test",
+            &addrs,
+            &scheduler,
+            &HostDeadline::start(scheduler.host_deadline_ms),
+            &IOConfig::default(),
+            &no_files(),
+            &att,
+            &RateLimit::disabled(),
+            &recorder,
+        );
+
+        assert_eq!(winner, None);
+        let mut raced: Vec<SocketAddr> = conns.iter().map(|c| c.addr).collect();
+        raced.sort();
+        assert_eq!(raced, addrs);
+        for c in &conns {
+            assert!(c.raced);
+            let st = c.shared.lock().unwrap();
+            assert!(st.t_handshake_ok_ms.is_none() && !st.race_lost);
+        }
+    }
+
+    #[test]
+    fn host_tally_either_prefers_a_success() {
+        let failed = |c| HostTally {
+            failure: Some(c),
+            ..HostTally::default()
+        };
+        let ok = HostTally {
+            status: Some(200),
+            ..HostTally::default()
+        };
+        let a = failed(FailureCategory::NoResponse);
+        let b = failed(FailureCategory::HandshakeFailed);
+
+        assert_eq!(HostTally::either(a, ok).status, Some(200));
+        assert_eq!(HostTally::either(ok, a).failure, None);
+        assert_eq!(
+            HostTally::either(a, b).failure,
+            Some(FailureCategory::NoResponse)
+        );
+    }
+
+    #[test]
+    fn expired_shared_deadline_stops_before_resolving() {
+        // A deadline used up by an earlier run (e.g. the other compare arm)
+        let deadline = HostDeadline::start(1);
+        std::thread::sleep(Duration::from_millis(5));
+        let recorder = Recorder::new(std::env::temp_dir(), false, false).unwrap();
+
+        let mut tally = HostTally::default();
+        let mut records = Vec::new();
+        let res = probe_configs(
+            "deadline.test",
+            &SchedulerConfig::default(),
+            &IOConfig::default(),
+            &no_files(),
+            &[ConnectionConfig::default()],
+            &RateLimit::disabled(),
+            &recorder,
+            &deadline,
+            &mut tally,
+            Some(&mut records),
+        );
+
+        assert!(res.is_err());
+        assert_eq!(tally.failure, Some(FailureCategory::HostDeadline));
+        assert_eq!(records.len(), 1);
+        assert!(records[0].host_deadline_hit && !records[0].resolved);
+    }
+
+    #[test]
+    fn cancelled_wait_leaves_no_record() {
+        let host = "cancelled.test";
+        let cfg = ConnectionConfig {
+            resolve_override: [(host.to_string(), IpAddr::V4(Ipv4Addr::LOCALHOST))].into(),
+            ..ConnectionConfig::default()
+        };
+        // As after an interrupt: every rate-limit wait fails
+        let rl = RateLimit::disabled();
+        rl.cancel();
+        let recorder = Recorder::new(std::env::temp_dir(), false, false).unwrap();
+
+        let mut tally = HostTally::default();
+        let mut records = Vec::new();
+        let res = probe_configs(
+            host,
+            &SchedulerConfig::default(),
+            &IOConfig::default(),
+            &no_files(),
+            std::slice::from_ref(&cfg),
+            &rl,
+            &recorder,
+            &HostDeadline::start(0),
+            &mut tally,
+            Some(&mut records),
+        );
+
+        assert!(res.is_err_and(|e| throttle::is_cancelled(&e)));
+        assert!(records.is_empty());
+        assert_eq!(tally.failure, None);
+    }
+
+    #[test]
+    fn single_address_no_response_is_retried() {
+        // A bound socket that never answers: every handshake times out
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let host = "silent.test";
+        let cfg = ConnectionConfig {
+            port: silent.local_addr().unwrap().port(),
+            resolve_override: [(host.to_string(), IpAddr::V4(Ipv4Addr::LOCALHOST))].into(),
+            max_idle_timeout_ms: 200,
+            ..ConnectionConfig::default()
+        };
+        let scheduler = SchedulerConfig {
+            max_retries: 1,
+            retry_backoff_ms: 0,
+            ..SchedulerConfig::default()
+        };
+        let recorder = Recorder::new(std::env::temp_dir(), false, false).unwrap();
+
+        let mut records = Vec::new();
+        let _ = probe_records(
+            host,
+            &scheduler,
+            &IOConfig::default(),
+            &no_files(),
+            std::slice::from_ref(&cfg),
+            &RateLimit::disabled(),
+            &recorder,
+            Some(&mut records),
+        );
+
+        let tries: Vec<(u32, Option<FailureCategory>)> =
+            records.iter().map(|r| (r.retry, r.category)).collect();
+        assert_eq!(
+            tries,
+            [
+                (0, Some(FailureCategory::NoResponse)),
+                (1, Some(FailureCategory::NoResponse))
+            ]
+        );
+    }
+}
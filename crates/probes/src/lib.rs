@@ -1 +1,7 @@
+pub mod compare;
+pub mod connect;
 pub mod h3;
+mod single;
+pub mod soak;
+
+pub use single::{probe_host, ProbeResult};
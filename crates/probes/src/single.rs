@@ -0,0 +1,75 @@
+//! In-process HTTP/3 check of one host, for programs embedding quic-lab
+//! (health probes, CI gates) rather than running a scan.
+//!
+//! ```ignore
+//! let result = probes::probe_host("example.com", &ConnectionConfig::default())?;
+//! assert!(result.handshake_ok());
+//! ```
+//!
+//! Runs the same code path as the runner's `h3` probe but writes no files: the
+//! recorder is disabled and the qlog/keylog sinks are only used if the embedding
+//! program set them up itself.
+
+use anyhow::Result;
+use core::config::{ConnectionConfig, GeneralConfig, IOConfig, SchedulerConfig};
+use core::recorder::Recorder;
+use core::throttle::RateLimit;
+use core::types::ProbeRecord;
+
+/// Outcome of [`probe_host`].
+#[derive(Debug, Clone)]
+pub struct ProbeResult {
+    /// The records a scan would have written, in order: one per address
+    /// tried, or a single one when the host was not probed (resolution failed)
+    pub records: Vec<ProbeRecord>,
+    /// Why the probe gave up early, if it did (e.g. resolution failure); the
+    /// last record carries the details
+    pub error: Option<String>,
+}
+
+impl ProbeResult {
+    /// Some address completed the QUIC handshake.
+    pub fn handshake_ok(&self) -> bool {
+        self.records.iter().any(|r| r.handshake_ok)
+    }
+
+    /// HTTP status of the last response, if any request got one.
+    pub fn status(&self) -> Option<u16> {
+        self.records.iter().rev().find_map(|r| r.http3.status)
+    }
+}
+
+/// Probe `host` once with `cfg` over HTTP/3 and return the records in memory.
+///
+/// No rate limit, delays or host deadline apply, and no session files are
+/// read or written. Errs only if the probe could not produce any record.
+pub fn probe_host(host: &str, cfg: &ConnectionConfig) -> Result<ProbeResult> {
+    let general = GeneralConfig {
+        save_recorder_files: false,
+        save_error_records: false,
+        save_qlog_files: false,
+        save_keylog_files: false,
+        save_session_files: false,
+        ..GeneralConfig::default()
+    };
+    let recorder = Recorder::new(std::env::temp_dir(), false, false)?;
+
+    let mut records = Vec::new();
+    let res = crate::h3::probe_records(
+        host,
+        &SchedulerConfig::default(),
+        &IOConfig::default(),
+        &general,
+        std::slice::from_ref(cfg),
+        &RateLimit::disabled(),
+        &recorder,
+        Some(&mut records),
+    );
+    match res {
+        Err(e) if records.is_empty() => Err(e),
+        res => Ok(ProbeResult {
+            records,
+            error: res.err().map(|e| format!("{e:#}")),
+        }),
+    }
+}
@@ -0,0 +1,618 @@
+//! Soak probe: hold one HTTP/3 connection open and issue a GET every
+//! `soak_interval_ms` for `soak_duration_ms`.
+//!
+//! Exercises long-lived connection behaviour (idle handling, key updates, CID
+//! rotation, migration). Each request becomes one `SoakSample`; the time series
+//! plus how the connection ended is written as a `SoakRecord` per connection.
+//!
+//! Client-initiated key updates are not probed: tquic 1.x only initiates them
+//! through its private TLS session (no `Connection` API) and does not report
+//! key-phase changes, so a `key_update_ok` outcome cannot be observed here.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use core::config::{ConnectionConfig, GeneralConfig, IOConfig, SchedulerConfig};
+use core::errors;
+use core::recorder::Recorder;
+use core::resolver::{final_resolver, resolve_targets, BogonSkipped, Resolved};
+use core::retry;
+use core::summary;
+use core::throttle::{sample_delay, HostDeadline, NotReady, RateLimit};
+use core::transport::quic::{run_probe, AppProtocol, CloseLinger};
+use core::triage;
+use core::types::{FailureCategory, ResponseHeaders};
+use log::{debug, error};
+use serde::Serialize;
+use tquic::h3::connection::Http3Connection;
+use tquic::h3::{Header, Http3Event};
+use tquic::Connection;
+
+use crate::h3::{
+    capture_headers, epoch_ms, is_trailer_block, ms_between, parse_status, record_late_event,
+    QpackLimits,
+};
+
+/// Outcome of one request issued during the soak.
+#[derive(Debug, Clone, Serialize)]
+pub struct SoakSample {
+    pub seq: u64,
+    pub stream_id: Option<u64>,
+    /// Offset from handshake completion
+    pub t_offset_ms: f64,
+    pub status: Option<u16>,
+    pub bytes: u64,
+    pub ttfb_ms: Option<f64>,
+    pub duration_ms: Option<f64>,
+    pub finished: bool,
+    /// Active path (local, peer) differed from the one at handshake time
+    pub path_changed: bool,
+    /// Trailer section of the response, if the server sent one
+    pub trailers: Option<ResponseHeaders>,
+    pub error: Option<String>,
+}
+
+#[derive(Default)]
+struct SoakState {
+    trace_id: Option<String>,
+    t_handshake_ok_ms: Option<u128>,
+    samples: Vec<SoakSample>,
+    /// Connection closed before the soak duration elapsed
+    lost_at_ms: Option<f64>,
+    idle_timeout: bool,
+    local_close: Option<String>,
+    peer_close: Option<String>,
+    qpack_error: Option<String>,
+    packets_recv: Option<u64>,
+    late_events: Vec<String>,
+    user_agent: Option<String>,
+}
+
+struct Inflight {
+    idx: usize,
+    t_open: Instant,
+    t_first_byte: Option<Instant>,
+}
+
+struct SoakApp {
+    host: String,
+    user_agent: String,
+    path: String,
+    interval: Duration,
+    duration: Duration,
+    qpack: QpackLimits,
+
+    h3: Option<Http3Connection>,
+    t_start: Option<Instant>,
+    next_at: Option<Instant>,
+    initial_path: Option<(SocketAddr, SocketAddr)>,
+    inflight: HashMap<u64, Inflight>,
+    linger: CloseLinger,
+    shared: Arc<Mutex<SoakState>>,
+}
+
+fn active_path(conn: &Connection) -> Option<(SocketAddr, SocketAddr)> {
+    conn.get_active_path()
+        .ok()
+        .map(|p| (p.local_addr(), p.remote_addr()))
+}
+
+impl SoakApp {
+    fn new(host: &str, cfg: &ConnectionConfig, shared: Arc<Mutex<SoakState>>) -> Self {
+        let user_agent = cfg.pick_user_agent().to_string();
+        shared.lock().unwrap().user_agent = Some(user_agent.clone());
+        Self {
+            host: host.to_string(),
+            user_agent,
+            path: cfg.path.clone(),
+            interval: Duration::from_millis(cfg.soak_interval_ms.max(1)),
+            duration: Duration::from_millis(cfg.soak_duration_ms),
+            qpack: QpackLimits::new(cfg),
+            h3: None,
+            t_start: None,
+            next_at: None,
+            initial_path: None,
+            inflight: HashMap::new(),
+            linger: CloseLinger::new(cfg),
+            shared,
+        }
+    }
+
+    fn soak_over(&self, now: Instant) -> bool {
+        self.t_start
+            .is_some_and(|t| now.duration_since(t) >= self.duration)
+    }
+
+    fn send_request(&mut self, conn: &mut Connection, st: &mut SoakState) {
+        let Some(h3) = self.h3.as_mut() else {
+            return;
+        };
+        let now = Instant::now();
+        let idx = st.samples.len();
+        let mut sample = SoakSample {
+            seq: idx as u64,
+            stream_id: None,
+            t_offset_ms: self.t_start.map_or(0.0, |t| ms_between(t, now)),
+            status: None,
+            bytes: 0,
+            ttfb_ms: None,
+            duration_ms: None,
+            finished: false,
+            path_changed: active_path(conn) != self.initial_path,
+            trailers: None,
+            error: None,
+        };
+
+        let headers = [
+            Header::new(b":method", b"GET"),
+            Header::new(b":scheme", b"https"),
+            Header::new(b":authority", self.host.as_bytes()),
+            Header::new(b":path", self.path.as_bytes()),
+            Header::new(b"user-agent", self.user_agent.as_bytes()),
+            Header::new(b"accept", b"*/*"),
+        ];
+        let sent = h3
+            .stream_new(conn)
+            .and_then(|sid| h3.send_headers(conn, sid, &headers, true).map(|()| sid));
+        match sent {
+            Ok(sid) => {
+                sample.stream_id = Some(sid);
+                self.inflight.insert(
+                    sid,
+                    Inflight {
+                        idx,
+                        t_open: now,
+                        t_first_byte: None,
+                    },
+                );
+            }
+            Err(e) => {
+                debug!("[{}] soak request {idx} failed: {e:?}", self.host);
+                sample.error = Some(format!("{e:?}"));
+            }
+        }
+        st.samples.push(sample);
+    }
+
+    /// Complete a sample and release the stream (a soak must not leak streams).
+    fn finish_stream(&mut self, conn: &mut Connection, sid: u64, st: &mut SoakState) {
+        if let Some(h3) = self.h3.as_mut() {
+            let _ = h3.stream_close(conn, sid);
+        }
+        if let Some(f) = self.inflight.remove(&sid) {
+            let now = Instant::now();
+            let sample = &mut st.samples[f.idx];
+            sample.ttfb_ms = f.t_first_byte.map(|t| ms_between(f.t_open, t));
+            sample.duration_ms = Some(ms_between(f.t_open, now));
+        }
+    }
+}
+
+impl AppProtocol for SoakApp {
+    fn on_connected(&mut self, conn: &mut Connection) {
+        let shared = self.shared.clone();
+        let mut st = shared.lock().unwrap();
+        st.trace_id = Some(conn.trace_id().to_string());
+        st.t_handshake_ok_ms = Some(epoch_ms());
+
+        let h3 = self
+            .qpack
+            .h3_config()
+            .and_then(|cfg| Http3Connection::new_with_quic_conn(conn, &cfg));
+        match h3 {
+            Ok(h3) => self.h3 = Some(h3),
+            Err(e) => {
+                error!("[{}] http3 init error: {:?}", self.host, e);
+                let _ = conn.close(true, 0x1, b"h3init");
+                return;
+            }
+        }
+
+        let now = Instant::now();
+        self.t_start = Some(now);
+        self.next_at = Some(now + self.interval);
+        self.initial_path = active_path(conn);
+        self.send_request(conn, &mut st);
+    }
+
+    fn on_stream_readable(&mut self, conn: &mut Connection, _stream_id: u64) {
+        let shared = self.shared.clone();
+        let mut st = shared.lock().unwrap();
+
+        while let Some(polled) = self.h3.as_mut().map(|h3| h3.poll(conn)) {
+            let (sid, event) = match polled {
+                Ok(ev) => ev,
+                Err(e) => {
+                    if let Some(q) = errors::qpack_h3_error(&e) {
+                        st.qpack_error.get_or_insert(q);
+                    }
+                    break;
+                }
+            };
+            if self.linger.is_done() {
+                record_late_event(&mut st.late_events, sid, &event);
+            }
+            match event {
+                Http3Event::Headers { headers, fin } => {
+                    if let Some(f) = self.inflight.get(&sid) {
+                        let sample = &mut st.samples[f.idx];
+                        if is_trailer_block(sample.status, &headers) {
+                            capture_headers(sample.trailers.get_or_insert_default(), &headers);
+                        } else {
+                            sample.status = parse_status(&headers);
+                        }
+                    }
+                    if fin {
+                        if let Some(f) = self.inflight.get(&sid) {
+                            st.samples[f.idx].finished = true;
+                        }
+                        self.finish_stream(conn, sid, &mut st);
+                    }
+                }
+                Http3Event::Data => {
+                    let mut buf = [0u8; 8192];
+                    while let Some(Ok(n)) =
+                        self.h3.as_mut().map(|h3| h3.recv_body(conn, sid, &mut buf))
+                    {
+                        if n == 0 {
+                            break;
+                        }
+                        if let Some(f) = self.inflight.get_mut(&sid) {
+                            f.t_first_byte.get_or_insert_with(Instant::now);
+                            st.samples[f.idx].bytes += n as u64;
+                        }
+                    }
+                }
+                Http3Event::Finished => {
+                    if let Some(f) = self.inflight.get(&sid) {
+                        st.samples[f.idx].finished = true;
+                    }
+                    self.finish_stream(conn, sid, &mut st);
+                }
+                Http3Event::Reset(code) => {
+                    if let Some(f) = self.inflight.get(&sid) {
+                        st.samples[f.idx].error = Some(format!("reset {code:#x}"));
+                    }
+                    self.finish_stream(conn, sid, &mut st);
+                }
+                _ => {}
+            }
+        }
+
+        if self.inflight.is_empty() && self.soak_over(Instant::now()) {
+            self.linger.close(conn);
+        }
+    }
+
+    fn on_conn_closed(&mut self, conn: &mut Connection) {
+        let mut st = self.shared.lock().unwrap();
+        let now = Instant::now();
+        if self.t_start.is_some() && !self.soak_over(now) {
+            st.lost_at_ms = self.t_start.map(|t| ms_between(t, now));
+        }
+        st.idle_timeout = conn.is_idle_timeout();
+        st.packets_recv = Some(conn.stats().recv_count);
+        st.local_close = conn.local_error().map(|e| format!("{e:?}"));
+        st.peer_close = conn.peer_error().map(|e| format!("{e:?}"));
+        if let Some(q) = errors::qpack_close(conn) {
+            st.qpack_error.get_or_insert(q);
+        }
+        debug!(
+            "[{}] soak ended after {} requests (lost_at_ms={:?})",
+            self.host,
+            st.samples.len(),
+            st.lost_at_ms
+        );
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        let next = self
+            .next_at
+            .map(|t| t.saturating_duration_since(Instant::now()));
+        match (next, self.linger.timeout()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn on_timeout(&mut self, conn: &mut Connection) {
+        self.linger.on_timeout(conn);
+
+        let now = Instant::now();
+        if self.next_at.is_none_or(|t| t > now) {
+            return;
+        }
+        if self.soak_over(now) {
+            // Stop the timer; close once outstanding requests are answered.
+            self.next_at = None;
+            if self.inflight.is_empty() {
+                self.linger.close(conn);
+            }
+            return;
+        }
+        self.next_at = self.next_at.map(|t| t + self.interval);
+        let shared = self.shared.clone();
+        let mut st = shared.lock().unwrap();
+        self.send_request(conn, &mut st);
+    }
+}
+
+/// Per-connection soak time series written via `Recorder`.
+#[derive(Debug, Serialize)]
+pub struct SoakRecord {
+    pub host: String,
+    pub peer_addr: String,
+    pub resolver: &'static str,
+    pub dns_stale: bool,
+    /// Same meaning as in `ProbeRecord`
+    pub skipped_bogon: bool,
+    pub trace_id: Option<String>,
+    pub t_start_ms: u128,
+    pub t_handshake_ok_ms: Option<u128>,
+    pub t_end_ms: u128,
+    /// Same meaning as in `ProbeRecord`
+    pub resolved: bool,
+    pub handshake_ok: bool,
+    pub category: Option<FailureCategory>,
+    pub host_deadline_hit: bool,
+    pub soak_interval_ms: u64,
+    pub soak_duration_ms: u64,
+    pub samples: Vec<SoakSample>,
+    /// Offset from handshake at which the connection closed before the soak ended
+    pub lost_at_ms: Option<f64>,
+    pub idle_timeout: bool,
+    pub local_close: Option<String>,
+    pub peer_close: Option<String>,
+    /// QPACK (header compression) failure, as in `ProbeRecord::qpack_error`
+    pub qpack_error: Option<String>,
+    /// User-Agent sent on the connection (null if it never connected)
+    pub user_agent: Option<String>,
+    /// H3 events received after the last sample completed (`post_finish_ms`)
+    pub late_events: Vec<String>,
+    pub error: Option<String>,
+}
+
+/// Record for an attempt that never connected (resolution failed, or the host
+/// deadline ran out first). `resolved` is the host's latest resolution, if
+/// any: a deadline hit after resolving keeps its resolver details.
+fn unprobed_record(
+    host: &str,
+    att: &ConnectionConfig,
+    resolved: Option<&Resolved>,
+    category: FailureCategory,
+    error: String,
+) -> SoakRecord {
+    let t_ms = epoch_ms();
+    SoakRecord {
+        host: host.to_string(),
+        peer_addr: String::new(),
+        resolver: resolved.map_or_else(|| final_resolver(att), |r| r.resolver),
+        dns_stale: resolved.is_some_and(|r| r.stale),
+        skipped_bogon: resolved.is_some_and(|r| r.skipped_bogon),
+        trace_id: None,
+        t_start_ms: t_ms,
+        t_handshake_ok_ms: None,
+        t_end_ms: t_ms,
+        resolved: resolved.is_some(),
+        handshake_ok: false,
+        category: Some(category),
+        host_deadline_hit: false,
+        soak_interval_ms: att.soak_interval_ms,
+        soak_duration_ms: att.soak_duration_ms,
+        samples: Vec::new(),
+        lost_at_ms: None,
+        idle_timeout: false,
+        local_close: None,
+        peer_close: None,
+        qpack_error: None,
+        user_agent: None,
+        late_events: Vec::new(),
+        error: Some(error),
+    }
+}
+
+fn write_unprobed(recorder: &Recorder, host: &str, record: &SoakRecord) {
+    if let Err(we) = recorder.write_for_key(host, record) {
+        error!("[{}] failed to write soak record: {we}", host);
+    }
+    if let Err(we) = recorder.write_error_for_key(host, record) {
+        error!("[{}] failed to write error record: {we}", host);
+    }
+    retry::record_outcome(host, record.category);
+    summary::record_outcome(record.category);
+    if let Err(e) = triage::record(host, None, None, record.category) {
+        error!("[{}] failed to write triage row: {e:#}", host);
+    }
+}
+
+/// Write the `host_deadline` marker record and return the error to abort with.
+fn host_deadline_hit(
+    host: &str,
+    att: &ConnectionConfig,
+    resolved: Option<&Resolved>,
+    deadline: &HostDeadline,
+    recorder: &Recorder,
+) -> anyhow::Error {
+    let e = deadline.error();
+    let mut record = unprobed_record(
+        host,
+        att,
+        resolved,
+        FailureCategory::HostDeadline,
+        format!("{e:?}"),
+    );
+    record.host_deadline_hit = true;
+    write_unprobed(recorder, host, &record);
+    e
+}
+
+/// Entry point mirroring `h3::probe`: soak the first address that connects.
+pub fn probe(
+    host: &str,
+    scheduler_config: &SchedulerConfig,
+    io_config: &IOConfig,
+    general_config: &GeneralConfig,
+    connection_configs: &[ConnectionConfig],
+    rl: &RateLimit,
+    recorder: &Recorder,
+) -> Result<()> {
+    let deadline = HostDeadline::start(scheduler_config.host_deadline_ms);
+    // Category of the latest failed connection, for `global_retry_rounds`
+    let mut last_failure = None;
+    let mut last_handshake_ms = None;
+    // Latest resolution, for records of attempts that never connected
+    let mut last_resolved: Option<Resolved> = None;
+
+    for (idx, att) in connection_configs.iter().enumerate() {
+        if deadline.expired() {
+            return Err(host_deadline_hit(
+                host,
+                att,
+                last_resolved.as_ref(),
+                &deadline,
+                recorder,
+            ));
+        }
+
+        let resolved = match resolve_targets(host, att) {
+            Ok(r) => r,
+            Err(e) => {
+                let skipped_bogon = e.is::<BogonSkipped>();
+                let category = if skipped_bogon {
+                    FailureCategory::SkippedBogon
+                } else {
+                    FailureCategory::ResolveFailed
+                };
+                let mut record = unprobed_record(host, att, None, category, format!("{e:?}"));
+                record.skipped_bogon = skipped_bogon;
+                write_unprobed(recorder, host, &record);
+                return Err(e);
+            }
+        };
+
+        let mut attempt_succeeded = false;
+
+        for &(_fam_eff, addr) in &resolved.targets {
+            if deadline.expired() {
+                return Err(host_deadline_hit(
+                    host,
+                    att,
+                    Some(&resolved),
+                    &deadline,
+                    recorder,
+                ));
+            }
+            if let Err(e) =
+                rl.until_ready_timeout(deadline.clamp(scheduler_config.throttle_max_wait()))
+            {
+                if deadline.expired() {
+                    return Err(host_deadline_hit(
+                        host,
+                        att,
+                        Some(&resolved),
+                        &deadline,
+                        recorder,
+                    ));
+                }
+                // Interrupted: not probed, so no record or outcome
+                if e == NotReady::Cancelled {
+                    return Err(e.into());
+                }
+                let record = unprobed_record(
+                    host,
+                    att,
+                    Some(&resolved),
+                    FailureCategory::LocalError,
+                    e.to_string(),
+                );
+                write_unprobed(recorder, host, &record);
+                return Err(e.into());
+            }
+
+            let t_start_ms = epoch_ms();
+            let shared = Arc::new(Mutex::new(SoakState::default()));
+            let app = SoakApp::new(host, att, shared.clone());
+
+            let res = run_probe(host, &addr, io_config, general_config, att, recorder, app);
+
+            let mut st = shared.lock().unwrap();
+            let handshake_ok = st.t_handshake_ok_ms.is_some();
+            let record = SoakRecord {
+                host: host.to_string(),
+                peer_addr: addr.to_string(),
+                resolver: resolved.resolver,
+                dns_stale: resolved.stale,
+                skipped_bogon: resolved.skipped_bogon,
+                trace_id: st.trace_id.take(),
+                t_start_ms,
+                t_handshake_ok_ms: st.t_handshake_ok_ms,
+                t_end_ms: epoch_ms(),
+                resolved: true,
+                handshake_ok,
+                category: FailureCategory::classify(handshake_ok, st.packets_recv),
+                host_deadline_hit: false,
+                soak_interval_ms: att.soak_interval_ms,
+                soak_duration_ms: att.soak_duration_ms,
+                samples: std::mem::take(&mut st.samples),
+                lost_at_ms: st.lost_at_ms,
+                idle_timeout: st.idle_timeout,
+                local_close: st.local_close.take(),
+                peer_close: st.peer_close.take(),
+                qpack_error: st.qpack_error.take(),
+                user_agent: st.user_agent.take(),
+                late_events: std::mem::take(&mut st.late_events),
+                error: res.as_ref().err().map(|e| format!("{e:?}")),
+            };
+            if let Err(e) = recorder.write_for_key(host, &record) {
+                error!("[{}] failed to write soak record: {e}", host);
+            }
+            last_handshake_ms = record
+                .t_handshake_ok_ms
+                .map(|t| t.saturating_sub(record.t_start_ms) as u64);
+            let failed = record.error.is_some() || record.category.is_some();
+            last_failure = record.category.or(record
+                .error
+                .is_some()
+                .then_some(FailureCategory::LocalError));
+            if let Err(e) = failed
+                .then(|| recorder.write_error_for_key(host, &record))
+                .transpose()
+            {
+                error!("[{}] failed to write error record: {e}", host);
+            }
+
+            if let Err(e) = res {
+                error!("[{}] soak {} err: {e:?}", host, addr);
+                continue;
+            }
+
+            // One soak per host: stop once a connection was established.
+            if handshake_ok {
+                attempt_succeeded = true;
+                break;
+            }
+        }
+        last_resolved = Some(resolved);
+
+        if attempt_succeeded {
+            last_failure = None;
+            break;
+        } else if idx + 1 < connection_configs.len() && scheduler_config.inter_attempt_delay_ms > 0
+        {
+            std::thread::sleep(deadline.clamp(sample_delay(
+                scheduler_config.delay_distribution,
+                scheduler_config.inter_attempt_delay_ms,
+            )));
+        }
+    }
+    retry::record_outcome(host, last_failure);
+    summary::record_outcome(last_failure);
+    if let Err(e) = triage::record(host, None, last_handshake_ms, last_failure) {
+        error!("[{}] failed to write triage row: {e:#}", host);
+    }
+
+    Ok(())
+}
@@ -17,7 +17,7 @@ use anyhow::Result;
 use core::config::{ConnectionConfig, GeneralConfig, IOConfig, SchedulerConfig};
 use core::recorder::Recorder;
 use core::resolver::resolve_targets;
-use core::throttle::RateLimit;
+use core::throttle::{sample_delay, HostDeadline, RateLimit};
 use core::transport::quic::{run_probe, AppProtocol};
 use log::{debug, error};
 use serde::Serialize;
@@ -134,6 +134,8 @@ impl AppProtocol for TemplateApp {
 pub struct TemplateResult {
     pub host: String,
     pub trace_id: Option<String>,
+    /// Resolver that produced the peer address (see `resolver_fallback`).
+    pub resolver: &'static str,
     pub elapsed_ms: u128,
     pub handshake_ok: bool,
     // Add your own serialised fields here, mirroring `TemplateState`.
@@ -157,13 +159,22 @@ pub fn probe(
     rl: &RateLimit,
     recorder: &Recorder,
 ) -> Result<()> {
+    // Overall per-host budget (`host_deadline_ms`), checked before each step.
+    let deadline = HostDeadline::start(scheduler_config.host_deadline_ms);
+
     for (idx, att) in connection_configs.iter().enumerate() {
+        if deadline.expired() {
+            return Err(deadline.error());
+        }
         // Resolve host -> (family, SocketAddr) tuples for this attempt.
-        let targets = resolve_targets(host, att.port, att.ip_version)?;
+        let resolved = resolve_targets(host, att)?;
 
         let mut attempt_succeeded = false;
 
-        for (_fam_eff, addr) in targets {
+        for &(_fam_eff, addr) in &resolved.targets {
+            if deadline.expired() {
+                return Err(deadline.error());
+            }
             // Global RPS / burst control.
             rl.until_ready();
 
@@ -180,6 +191,7 @@ pub fn probe(
             let record = TemplateResult {
                 host: host.to_string(),
                 trace_id: st.trace_id.clone(),
+                resolver: resolved.resolver,
                 elapsed_ms,
                 handshake_ok: st.handshake_ok,
                 // fill in additional fields here
@@ -208,9 +220,11 @@ pub fn probe(
             break;
         } else if idx + 1 < connection_configs.len() && scheduler_config.inter_attempt_delay_ms > 0
         {
-            std::thread::sleep(std::time::Duration::from_millis(
+            // Per-domain pacing between attempts (`[scheduler]` section).
+            std::thread::sleep(deadline.clamp(sample_delay(
+                scheduler_config.delay_distribution,
                 scheduler_config.inter_attempt_delay_ms,
-            ));
+            )));
         }
     }
 
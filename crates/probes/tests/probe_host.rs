@@ -0,0 +1,19 @@
+//! Embedding example: check one host in-process with `probes::probe_host`.
+
+use core::config::ConnectionConfig;
+use core::types::FailureCategory;
+
+#[test]
+fn unresolvable_host_yields_one_record() {
+    // `.invalid` never resolves (RFC 6761), so this runs offline.
+    let result = probes::probe_host("example.invalid", &ConnectionConfig::default()).unwrap();
+
+    assert!(!result.handshake_ok());
+    assert_eq!(result.status(), None);
+    assert!(result.error.is_some());
+    let [record] = result.records.as_slice() else {
+        panic!("expected one record, got {}", result.records.len());
+    };
+    assert!(!record.resolved);
+    assert_eq!(record.category, Some(FailureCategory::ResolveFailed));
+}
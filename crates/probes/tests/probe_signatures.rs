@@ -0,0 +1,46 @@
+//! The runner dispatches to `h3::probe`, `soak::probe` and `compare::probe`
+//! through one function pointer type; this keeps their signatures (and the
+//! template's) in lockstep.
+
+use core::config::{ConnectionConfig, GeneralConfig, IOConfig, SchedulerConfig};
+use core::recorder::Recorder;
+use core::throttle::RateLimit;
+
+type ProbeFn = fn(
+    &str,
+    &SchedulerConfig,
+    &IOConfig,
+    &GeneralConfig,
+    &[ConnectionConfig],
+    &RateLimit,
+    &Recorder,
+) -> anyhow::Result<()>;
+
+#[test]
+fn probes_share_one_signature() {
+    let probes: [(&str, ProbeFn); 3] = [
+        ("h3", probes::h3::probe),
+        ("soak", probes::soak::probe),
+        ("compare", probes::compare::probe),
+    ];
+
+    let scheduler = SchedulerConfig::default();
+    let io = IOConfig::default();
+    let general = GeneralConfig::default();
+    let recorder = Recorder::new(std::env::temp_dir(), false, false).unwrap();
+    let rl = RateLimit::disabled();
+
+    // No attempts: nothing is resolved or sent, so this runs offline.
+    for (name, probe) in probes {
+        let res = probe(
+            "example.invalid",
+            &scheduler,
+            &io,
+            &general,
+            &[],
+            &rl,
+            &recorder,
+        );
+        assert!(res.is_ok(), "{name}: {res:?}");
+    }
+}
@@ -0,0 +1,81 @@
+//! Ctrl-C / SIGTERM handling: the first signal stops the scan from starting
+//! new hosts and lets the ones in flight finish, so every sink can be flushed
+//! with complete records; a second signal kills the process as usual.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use core::throttle::RateLimit;
+
+/// Exit status of an interrupted scan (128 + SIGINT, as a shell reports it).
+pub const EXIT_CODE: i32 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static SKIPPED: AtomicU64 = AtomicU64::new(0);
+
+extern "C" fn on_signal(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    // Back to the default action for the next signal
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+        libc::signal(libc::SIGTERM, libc::SIG_DFL);
+    }
+}
+
+/// Install the SIGINT and SIGTERM handler.
+pub fn install() -> Result<()> {
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    for sig in [libc::SIGINT, libc::SIGTERM] {
+        if unsafe { libc::signal(sig, handler) } == libc::SIG_ERR {
+            bail!(
+                "installing handler for signal {sig}: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// A signal arrived; no new host should be started.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Count a host not probed because of the signal.
+pub fn record_skipped() {
+    SKIPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn skipped() -> u64 {
+    SKIPPED.load(Ordering::Relaxed)
+}
+
+/// Sleep for `d`, waking early once a signal arrived.
+pub fn sleep(d: Duration) {
+    let until = Instant::now() + d;
+    while !interrupted() {
+        let left = until.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return;
+        }
+        std::thread::sleep(left.min(Duration::from_millis(100)));
+    }
+}
+
+/// Watch for the signal until `done`: once it arrives, cancel `rl` so workers
+/// waiting for a rate-limit token give up instead of connecting.
+pub fn spawn_watcher(done: Arc<AtomicBool>, rl: RateLimit) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        while !done.load(Ordering::Relaxed) {
+            if interrupted() {
+                log::warn!("interrupted: finishing hosts in flight, skipping the rest");
+                rl.cancel();
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    })
+}
@@ -1,8 +1,10 @@
 use anyhow::{anyhow, Result};
-use core::config::{read_config, read_domains_iter};
+use core::config::{read_configs, read_domains_iter};
 use core::qlog;
 use core::recorder::Recorder;
+use core::summary::HostErrors;
 use core::throttle::RateLimit;
+use core::types::{ProbeKind, RetryRoundRecord};
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 use rayon::prelude::*;
 use std::io::{stderr, stdout, IsTerminal};
@@ -11,6 +13,10 @@ use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+mod interrupt;
+mod watchdog;
+use watchdog::Watchdog;
+
 // TTY-recognition
 fn is_tty() -> bool {
     stdout().is_terminal() || stderr().is_terminal()
@@ -29,32 +35,106 @@ fn fmt_hms(mut secs: u64) -> String {
 }
 
 fn main() -> Result<()> {
-    // CLI: runner [config.toml]
-    let cfg_path = std::env::args()
-        .nth(1)
-        .unwrap_or_else(|| "in/config.toml".into());
-    let cfg = read_config(&cfg_path)?;
+    // CLI: runner [--resume] [base.toml [overlay.toml ...]]
+    let mut cfg_paths: Vec<String> = std::env::args().skip(1).collect();
+    let resume = cfg_paths.iter().any(|a| a == "--resume");
+    cfg_paths.retain(|a| a != "--resume");
+    if cfg_paths.is_empty() {
+        cfg_paths.push("in/config.toml".into());
+    }
+    let cfg = read_configs(&cfg_paths)?;
 
     // Logging
     if cfg.general.save_log_files {
-        let _run_log = core::logging::init_file_logger(&cfg.io.out_dir, cfg.general.log_level)?;
+        let _run_log = core::logging::init_file_logger(
+            &cfg.io.out_dir,
+            cfg.general.log_level,
+            cfg.general.time_rotation,
+            cfg.general.compress_rotated,
+            cfg.general.max_rotated_files,
+        )?;
     }
+    if cfg.duplicate_attempts > 0 {
+        log::warn!(
+            "collapsed {} duplicate [[connection_config]] attempt(s), {} left",
+            cfg.duplicate_attempts,
+            cfg.connection_config.len()
+        );
+    }
+
+    // Ctrl-C: stop starting hosts, flush everything, exit with its own status
+    interrupt::install()?;
 
     // Keylog
-    core::keylog::init(&cfg.io.out_dir, cfg.general.save_keylog_files)?;
+    core::keylog::init(
+        &cfg.io.out_dir,
+        cfg.general.save_keylog_files,
+        cfg.general.compress_rotated,
+        cfg.general.max_rotated_files,
+    )?;
+
+    // QLOG sink (flat folder + rotation, optionally one file per connection)
+    qlog::init(&cfg.io.out_dir, &cfg.general)?;
+
+    // By-IP aggregate (written at exit)
+    core::by_ip::init(&cfg.io.out_dir, cfg.general.save_by_ip);
+    core::summary::init(
+        &cfg.io.out_dir,
+        cfg.general.save_summary,
+        cfg.general.summary_strip_server_versions,
+    );
+
+    // Host -> output file index (appended as hosts complete)
+    core::index::init(&cfg.io.out_dir, cfg.general.save_index)?;
+
+    // Per-host triage rows (appended as hosts complete)
+    core::triage::init(&cfg.io.out_dir, cfg.general.save_triage_csv)?;
 
-    // QLOG sink (flat folder + rotation)
-    qlog::init(&cfg.io.out_dir, cfg.general.save_qlog_files)?;
+    // Hosts whose compare arms disagree (appended as hosts complete)
+    core::diffs::init(
+        &cfg.io.out_dir,
+        matches!(cfg.general.probe, ProbeKind::Compare) && !cfg.general.counts_only,
+        cfg.compare.handshake_ms_threshold,
+    )?;
+
+    // Output file listing (written last at exit)
+    core::manifest::init(&cfg.io.out_dir, cfg.general.save_manifest);
+
+    // Hosts with transient failures, re-run after the full list
+    core::retry::init(cfg.scheduler.global_retry_rounds > 0);
+
+    // Cap on connection attempts across the whole run
+    core::budget::init(cfg.scheduler.max_total_connections);
 
     // Load domains
     let domains_path = PathBuf::from(&cfg.io.in_dir).join(&cfg.io.domains_file_name);
-    let domains: Vec<String> = read_domains_iter(&domains_path)?.collect();
+    let mut domains: Vec<String> = read_domains_iter(&domains_path)?.collect();
     if domains.is_empty() {
         return Err(anyhow!("no domains found in {}", domains_path.display()));
     }
 
-    // Recorder (one file per trace_id)
-    let recorder = Recorder::new(&cfg.io.out_dir, cfg.general.save_recorder_files)?;
+    // --resume: skip hosts an earlier run into the same out_dir completed
+    if resume {
+        let done = core::recorder::completed_hosts(&cfg.io.out_dir, cfg.general.fast_resume)?;
+        let before = domains.len();
+        domains.retain(|d| !done.contains(d));
+        log::info!(
+            "resuming: skipping {} completed host(s), {} left",
+            before - domains.len(),
+            domains.len()
+        );
+    }
+
+    // Recorder (one file per trace_id), marking completed hosts for --resume
+    let recorder = Recorder::new(
+        &cfg.io.out_dir,
+        cfg.general.save_recorder_files,
+        cfg.general.save_error_records,
+    )?
+    .with_progress(&cfg.io.out_dir, resume)?;
+
+    // Run metadata: scheduling parameters (rate, delay distribution) of this run
+    recorder.write_for_key("run", &cfg.scheduler)?;
 
     // Thread pool sizing
     let threads = if cfg.scheduler.concurrency == 0 {
@@ -73,11 +153,11 @@ fn main() -> Result<()> {
     // Global rate limiter
     let rl = RateLimit::per_second(cfg.scheduler.requests_per_second, cfg.scheduler.burst);
 
-    // Progress bar
-    let total = domains.len() as u64;
+    // Progress bar (retry rounds add to the total)
+    let total = Arc::new(AtomicU64::new(domains.len() as u64));
     let start = Instant::now();
     let processed = Arc::new(AtomicU64::new(0));
-    let err_cnt = Arc::new(AtomicU64::new(0));
+    let host_errors = Arc::new(HostErrors::default());
 
     let use_tty = is_tty();
 
@@ -85,13 +165,15 @@ fn main() -> Result<()> {
     let done_flag = Arc::new(AtomicBool::new(false));
     let reporter = if !use_tty {
         let processed_c = processed.clone();
-        let err_c = err_cnt.clone();
+        let errors_c = host_errors.clone();
         let done_c = done_flag.clone();
+        let total_c = total.clone();
         Some(std::thread::spawn(move || {
             // Every 10 seconds
             while !done_c.load(Ordering::Relaxed) {
+                let total = total_c.load(Ordering::Relaxed);
                 let p = processed_c.load(Ordering::Relaxed);
-                let e = err_c.load(Ordering::Relaxed);
+                let e = errors_c.count();
                 let pct = if total > 0 {
                     (p as f64 / total as f64) * 100.0
                 } else {
@@ -122,8 +204,9 @@ fn main() -> Result<()> {
                 std::thread::sleep(Duration::from_secs(10));
             }
             // Finish message
+            let total = total_c.load(Ordering::Relaxed);
             let p = processed_c.load(Ordering::Relaxed);
-            let e = err_c.load(Ordering::Relaxed);
+            let e = errors_c.count();
             let pct = if total > 0 {
                 (p as f64 / total as f64) * 100.0
             } else {
@@ -142,9 +225,24 @@ fn main() -> Result<()> {
         None
     };
 
+    // Stalled-worker watchdog, fed by per-host progress marks
+    core::progress::init(cfg.general.worker_stall_secs > 0);
+    let watchdog =
+        (cfg.general.worker_stall_secs > 0).then(|| Watchdog::new(cfg.general.worker_stall_secs));
+    let watchdog_thread = watchdog.as_ref().map(|wd| {
+        wd.spawn(
+            done_flag.clone(),
+            cfg.general.abort_on_stall,
+            recorder.clone(),
+            rl.clone(),
+        )
+    });
+
+    let interrupt_thread = interrupt::spawn_watcher(done_flag.clone(), rl.clone());
+
     // TTY-Progressbar setup
     let pb = if use_tty {
-        let pb = ProgressBar::new(total);
+        let pb = ProgressBar::new(total.load(Ordering::Relaxed));
         pb.set_draw_target(ProgressDrawTarget::stderr());
         pb.set_style(ProgressStyle::with_template(
             "{spinner:.green} {pos}/{len} [{bar:40.cyan/blue}] \
@@ -156,42 +254,185 @@ fn main() -> Result<()> {
         None
     };
 
-    domains.par_iter().for_each(|host| {
-        if let Err(e) = probes::h3::probe(
+    // Attempts per host: the two arms when comparing
+    let compare_arms = cfg.compare.arms();
+    let attempts = match cfg.general.probe {
+        ProbeKind::Compare => &compare_arms,
+        _ => &cfg.connection_config,
+    };
+
+    // A skipped host is counted by `record_skipped` and as processed, so the
+    // progress still adds up to the total; it is not marked done for --resume
+    let skip = |record_skipped: fn()| -> bool {
+        record_skipped();
+        processed.fetch_add(1, Ordering::Relaxed);
+        if let Some(pb) = &pb {
+            pb.inc(1);
+        }
+        false
+    };
+
+    // Probe one host (`retrying` in a retry round); `false` if it was skipped
+    // because the connection budget is used up or the scan was interrupted
+    let run_host = |host: &String, retrying: bool| -> bool {
+        if interrupt::interrupted() {
+            return skip(interrupt::record_skipped);
+        }
+        if core::budget::exhausted() {
+            return skip(if retrying {
+                core::budget::record_retry_skipped
+            } else {
+                core::budget::record_skipped
+            });
+        }
+        if let Some(wd) = &watchdog {
+            wd.begin(host);
+        }
+        let probe = match cfg.general.probe {
+            ProbeKind::H3 => probes::h3::probe,
+            ProbeKind::Soak => probes::soak::probe,
+            ProbeKind::Compare => probes::compare::probe,
+        };
+        let res = probe(
             host,
             &cfg.scheduler,
             &cfg.io,
             &cfg.general,
-            &cfg.connection_config,
+            attempts,
             &rl,
             &recorder,
-        ) {
-            err_cnt.fetch_add(1, Ordering::Relaxed);
-            log::error!("[{}] ERROR: {e:#}", host);
-            if let Some(pb) = &pb {
-                let errs = err_cnt.load(Ordering::Relaxed);
-                pb.set_message(format!("errors: {errs}"));
+        );
+        // Interrupted while waiting for a rate-limit token: not probed either
+        if res.as_ref().is_err_and(core::throttle::is_cancelled) {
+            if let Some(wd) = &watchdog {
+                wd.end();
             }
+            return skip(interrupt::record_skipped);
+        }
+        if let Err(e) = &res {
+            log::error!("[{}] ERROR: {e:#}", host);
+        }
+        if let (true, Some(pb)) = (host_errors.record(host, res.is_err()), &pb) {
+            pb.set_message(format!("errors: {}", host_errors.count()));
+        }
+        if let Some(wd) = &watchdog {
+            wd.end();
+        }
+        if let Err(e) = core::index::record(host, &recorder) {
+            log::error!("[{}] index write failed: {e:#}", host);
+        }
+        if let Err(e) = recorder.mark_done(host) {
+            log::error!("[{}] progress write failed: {e:#}", host);
         }
         processed.fetch_add(1, Ordering::Relaxed);
         if let Some(pb) = &pb {
             pb.inc(1);
         }
+        // Mandatory cooldown before this worker takes its next host
+        if cfg.scheduler.post_host_sleep_ms > 0 {
+            interrupt::sleep(Duration::from_millis(cfg.scheduler.post_host_sleep_ms));
+        }
+        true
+    };
+    domains.par_iter().for_each(|host| {
+        run_host(host, false);
     });
 
+    // Retry rounds: re-run hosts whose last failure was transient, backing
+    // off (doubling) between rounds
+    for round in 1..=cfg.scheduler.global_retry_rounds {
+        let hosts = core::retry::take_pending();
+        if hosts.is_empty() || interrupt::interrupted() {
+            break;
+        }
+        if core::budget::exhausted() {
+            // The round's hosts keep their outcome from the earlier pass
+            for _ in &hosts {
+                core::budget::record_retry_skipped();
+            }
+            break;
+        }
+        let backoff = Duration::from_millis(
+            cfg.scheduler
+                .global_retry_backoff_ms
+                .saturating_mul(1 << (round - 1).min(16)),
+        );
+        log::info!(
+            "retry round {round}: {} host(s) after {}s",
+            hosts.len(),
+            backoff.as_secs()
+        );
+        interrupt::sleep(backoff);
+
+        total.fetch_add(hosts.len() as u64, Ordering::Relaxed);
+        if let Some(pb) = &pb {
+            pb.inc_length(hosts.len() as u64);
+        }
+        hosts.par_iter().for_each(|(host, failure)| {
+            if !run_host(host, true) {
+                return;
+            }
+            core::summary::retract_failure(*failure);
+            let record = RetryRoundRecord {
+                host: host.clone(),
+                round,
+                succeeded: !core::retry::is_pending(host),
+            };
+            if let Err(e) = recorder.write_for_key(host, &record) {
+                log::error!("[{}] failed to write retry record: {e}", host);
+            }
+        });
+    }
+
     if let Some(pb) = &pb {
         pb.finish_with_message(format!(
             "done in {:.2}s, errors: {}",
             start.elapsed().as_secs_f32(),
-            err_cnt.load(Ordering::Relaxed)
+            host_errors.count()
         ));
     }
 
-    // Cancel Reporter-Thread, if non-TTY
+    let skipped = core::budget::skipped();
+    let retry_skipped = core::budget::retry_skipped();
+    if skipped + retry_skipped > 0 {
+        log::warn!(
+            "connection budget (max_total_connections = {}) used up: {skipped} host(s) \
+             skipped, {retry_skipped} retry re-run(s) not started",
+            cfg.scheduler.max_total_connections
+        );
+    }
+
+    if interrupt::interrupted() {
+        log::warn!(
+            "scan interrupted: {} host(s) not probed, exiting with status {}",
+            interrupt::skipped(),
+            interrupt::EXIT_CODE
+        );
+    }
+
+    // Cancel Reporter-Thread, if non-TTY, and the watchdog
+    done_flag.store(true, Ordering::Relaxed);
     if reporter.is_some() {
-        done_flag.store(true, Ordering::Relaxed);
         let _ = reporter.unwrap().join();
     }
+    if let Some(t) = watchdog_thread {
+        let _ = t.join();
+    }
+    let _ = interrupt_thread.join();
 
+    core::summary::record_run(
+        domains.len() as u64,
+        processed.load(Ordering::Relaxed),
+        host_errors.count(),
+        start.elapsed(),
+    );
+    core::summary::record_throttle(rl.stats());
+
+    // Flush keylog, qlog, recorder and logger; the global sinks are never dropped.
+    core::shutdown_all(&recorder);
+
+    if interrupt::interrupted() {
+        std::process::exit(interrupt::EXIT_CODE);
+    }
     Ok(())
 }
@@ -0,0 +1,107 @@
+//! Watchdog for worker threads that stop making progress.
+//!
+//! Each worker registers the host it is probing; a background thread reports
+//! every worker whose host made no progress (see `core::progress`) for longer
+//! than `worker_stall_secs`, so a hang shows up in the log and summary instead
+//! of a silently frozen scan.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use core::recorder::Recorder;
+use core::throttle::RateLimit;
+
+struct Busy {
+    host: String,
+    since: Instant,
+    reported: bool,
+}
+
+pub struct Watchdog {
+    stall: Duration,
+    /// Keyed by rayon worker index
+    busy: Mutex<HashMap<usize, Busy>>,
+}
+
+impl Watchdog {
+    pub fn new(stall_secs: u64) -> Arc<Self> {
+        Arc::new(Self {
+            stall: Duration::from_secs(stall_secs),
+            busy: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn worker() -> usize {
+        rayon::current_thread_index().unwrap_or(usize::MAX)
+    }
+
+    /// The calling worker started on `host`.
+    pub fn begin(&self, host: &str) {
+        core::progress::begin(host);
+        self.busy.lock().unwrap().insert(
+            Self::worker(),
+            Busy {
+                host: host.to_string(),
+                since: Instant::now(),
+                reported: false,
+            },
+        );
+    }
+
+    /// The calling worker finished its host.
+    pub fn end(&self) {
+        if let Some(b) = self.busy.lock().unwrap().remove(&Self::worker()) {
+            core::progress::end(&b.host);
+        }
+    }
+
+    /// Report newly stalled workers; returns how many there were.
+    fn check(&self) -> usize {
+        let mut stalled = 0;
+        for (worker, b) in self.busy.lock().unwrap().iter_mut() {
+            let idle = core::progress::last(&b.host)
+                .map_or(b.since, |t| t.max(b.since))
+                .elapsed();
+            if b.reported || idle < self.stall {
+                continue;
+            }
+            b.reported = true;
+            stalled += 1;
+            log::warn!(
+                "worker {worker} stalled: no progress on {} for {}s",
+                b.host,
+                idle.as_secs()
+            );
+            core::summary::record_stalled_worker();
+        }
+        stalled
+    }
+
+    /// Check once a second until `done`. With `abort`, the first stall
+    /// cancels `rl` (so throttled workers stop instead of starting new
+    /// connections), flushes all sinks and exits the process (status 2).
+    pub fn spawn(
+        self: &Arc<Self>,
+        done: Arc<AtomicBool>,
+        abort: bool,
+        recorder: Recorder,
+        rl: RateLimit,
+    ) -> JoinHandle<()> {
+        let wd = self.clone();
+        std::thread::spawn(move || {
+            while !done.load(Ordering::Relaxed) {
+                std::thread::sleep(Duration::from_secs(1));
+                if wd.check() > 0 && abort {
+                    log::error!("aborting scan on stalled worker (abort_on_stall)");
+                    rl.cancel();
+                    core::summary::record_throttle(rl.stats());
+                    core::shutdown_all(&recorder);
+                    std::process::exit(2);
+                }
+            }
+        })
+    }
+}